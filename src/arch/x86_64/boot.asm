@@ -1,13 +1,19 @@
 %define VGA_BUF 0xb8000
+; Must match linker.ld. Everything but this file's code is linked at
+; KERNEL_OFFSET, so before paging it's reached at `symbol - KERNEL_OFFSET`.
+%define KERNEL_OFFSET 0xffffffff80000000
 
 global start
+global p4_table
 extern long_mode_start
 
 section .bss
 align 4096
 p4_table:
     resb 4096
-p3_table:
+p3_table:           ; identity map, removed by long_mode_start
+    resb 4096
+p3_high_table:      ; the kernel's mapping at KERNEL_OFFSET
     resb 4096
 p2_table:
     resb 4096
@@ -26,12 +32,15 @@ gdt64:
 .pointer:
     dw $ - gdt64 - 1
     dq gdt64
+.boot_pointer:      ; loaded in protected mode, which only uses the low 32 bits
+    dw .pointer - gdt64 - 1
+    dq gdt64 - KERNEL_OFFSET
 
 
-section .text
+section .boot.text progbits alloc exec nowrite align=16
 bits 32
 start:
-    mov esp, stack_top
+    mov esp, stack_top - KERNEL_OFFSET
     mov edi, ebx
 
     call test_multiboot
@@ -41,7 +50,7 @@ start:
     call setup_page_tables
     call enable_paging
 
-    lgdt [gdt64.pointer] 
+    lgdt [gdt64.boot_pointer - KERNEL_OFFSET]
 
     ; update selectors
     mov ax, gdt64.data
@@ -49,35 +58,42 @@ start:
     mov ds, ax  ; data selector
     mov es, ax  ; extra selector]
 
-    jmp gdt64.code:long_mode_start
+    jmp gdt64.code:higher_half_jump
 
     mov dword [VGA_BUF], 0x2f4b2f4f
     hlt
 
 
 setup_page_tables:
-    mov eax, p3_table
+    mov eax, p3_table - KERNEL_OFFSET
     or  eax, 0b11 ; present, writable
-    mov [p4_table], eax
+    mov [p4_table - KERNEL_OFFSET], eax
+
+    ; KERNEL_OFFSET is P4 entry 511, P3 entry 510
+    mov eax, p3_high_table - KERNEL_OFFSET
+    or  eax, 0b11
+    mov [p4_table - KERNEL_OFFSET + 511*8], eax
 
-    mov eax, p2_table
+    ; both map the first GiB through the same P2
+    mov eax, p2_table - KERNEL_OFFSET
     or  eax, 0b11
-    mov [p3_table], eax
+    mov [p3_table - KERNEL_OFFSET], eax
+    mov [p3_high_table - KERNEL_OFFSET + 510*8], eax
 
     mov ecx, 0
 .map_p2_tables:
     mov eax, 0x200000
     mul ecx
     or  eax, 0b10000011 ; huge, present, writable
-    mov [p2_table + ecx*8], eax
+    mov [p2_table - KERNEL_OFFSET + ecx*8], eax
 
     inc ecx
     cmp ecx, 512
     jne .map_p2_tables
-; recursively map p4 table
-    mov eax, p4_table
+; recursively map p4 table, entry 511 is taken by the kernel
+    mov eax, p4_table - KERNEL_OFFSET
     or  eax, 0b11 ; present, writable
-    mov [p4_table + 511*8], eax
+    mov [p4_table - KERNEL_OFFSET + 510*8], eax
 
     ret
 
@@ -85,7 +101,7 @@ setup_page_tables:
 
 enable_paging:
     ; load P4 to cr3 register (cpu uses this to access the P4 table)
-    mov eax, p4_table
+    mov eax, p4_table - KERNEL_OFFSET
     mov cr3, eax
 
     ; enable PAE-flag in cr4 (Physical Address Extension)
@@ -107,6 +123,18 @@ enable_paging:
 
     ret
 
+bits 64
+; Still running from the identity map. Moves the stack and the GDT to
+; their higher half addresses before continuing in the kernel.
+higher_half_jump:
+    mov rsp, stack_top
+    lgdt [gdt64.pointer]
+
+    mov rax, long_mode_start
+    jmp rax
+
+bits 32
+
 
 
 
@@ -4,8 +4,9 @@ section .text
 bits 64
 
 ; params:
-;    rdi - cpuid call 
-;    rsi - pointer to a continous 16 bytes of memory
+;    rdi - cpuid call
+;    rsi - cpuid subleaf (loaded into ecx, ignored by most calls)
+;    rdx - pointer to a continous 16 bytes of memory
 ;          which will be filled
 internal_cpuid:
 	; save frame pointer and callee-save ebx
@@ -13,16 +14,20 @@ internal_cpuid:
 	mov rbp, rsp
 	push rbx
 
+	; cpuid clobbers edx, keep the result pointer around
+	mov r8, rdx
+
 	; cpuid call - 0 = vendor id
 	mov eax, edi
+	mov ecx, esi
 	cpuid
 
-	mov dword [rsi],    eax
-	mov dword [rsi+4],  ebx
-	mov dword [rsi+8],  ecx
-	mov dword [rsi+12], edx
+	mov dword [r8],    eax
+	mov dword [r8+4],  ebx
+	mov dword [r8+8],  ecx
+	mov dword [r8+12], edx
 
 	pop rbx
 	pop rbp
 
-	ret
\ No newline at end of file
+	ret
@@ -1,20 +1,32 @@
+; The VGA text buffer through the kernel's mapping of low memory
+%define VGA_BUF 0xffffffff800b8000
+
 global long_mode_start
+extern p4_table
 
 section .text
 bits 64
 long_mode_start:
+	; now that we run in the higher half, drop the identity map
+	mov qword [p4_table], 0
+	mov rax, cr3
+	mov cr3, rax
+
 	call setup_SSE
 
+	; terminate the frame pointer chain for backtraces
+	xor rbp, rbp
+
 	extern rust_main
 	call rust_main
 .os_returned:
     ; rust main returned, print `OS returned!`
     mov rax, 0x4f724f204f534f4f
-    mov [0xb8000], rax
+    mov [VGA_BUF], rax
     mov rax, 0x4f724f754f744f65
-    mov [0xb8008], rax
+    mov [VGA_BUF + 8], rax
     mov rax, 0x4f214f644f654f6e
-    mov [0xb8010], rax
+    mov [VGA_BUF + 16], rax
     hlt
 
 ; Check for SSE and enable it. If it's not supported throw error "a".
@@ -44,9 +56,9 @@ setup_SSE:
 ; parameter: error code (in ascii) in al
 error:
     mov rbx, 0x4f4f4f524f524f45
-    mov [0xb8000], rbx
+    mov [VGA_BUF], rbx
     mov rbx, 0x4f204f204f3a4f52
-    mov [0xb8008], rbx
-    mov byte [0xb800e], al
+    mov [VGA_BUF + 8], rbx
+    mov byte [VGA_BUF + 14], al
     hlt
     jmp error
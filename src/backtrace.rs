@@ -0,0 +1,290 @@
+use core::fmt;
+use core::slice;
+use core::str;
+use multiboot2::ElfSectionsTag;
+use memory;
+
+const MAX_DEPTH: usize = 64;
+
+// ELF section types
+const SHT_SYMTAB: u32 = 2;
+// ELF symbol types
+const STT_FUNC: u8 = 2;
+
+// Layout of the elf sections multiboot tag: type, size, number of
+// sections, entry size, string table index, followed by the headers
+const TAG_NUM_SECTIONS_OFFSET: usize = 8;
+const TAG_ENTRY_SIZE_OFFSET: usize = 12;
+const TAG_HEADERS_OFFSET: usize = 20;
+
+#[repr(C)]
+struct ElfSectionHeader {
+    name: u32,
+    typ: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    addralign: u64,
+    entry_size: u64,
+}
+
+#[repr(C)]
+struct ElfSymbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section_index: u16,
+    value: u64,
+    size: u64,
+}
+
+struct SymbolTable {
+    symbols: &'static [ElfSymbol],
+    strings: &'static [u8],
+}
+
+// Written once during boot, read from panic and exception handlers which
+// can't risk waiting on a lock
+static mut SYMBOL_TABLE: Option<SymbolTable> = None;
+
+pub fn init(elf_sections_tag: &ElfSectionsTag) {
+    let log = log!("Loading kernel symbols");
+
+    let tag = elf_sections_tag as *const _ as usize;
+    let (count, entry_size) = unsafe {
+        (*((tag + TAG_NUM_SECTIONS_OFFSET) as *const u32) as usize,
+         *((tag + TAG_ENTRY_SIZE_OFFSET) as *const u32) as usize)
+    };
+    let header = |idx: usize| unsafe {
+        &*((tag + TAG_HEADERS_OFFSET + idx * entry_size) as *const ElfSectionHeader)
+    };
+
+    let symtab = match (0..count).map(&header).find(|h| h.typ == SHT_SYMTAB) {
+        Some(symtab) => symtab,
+        None => {
+            log.fail();
+            return;
+        }
+    };
+    let strtab = header(symtab.link as usize);
+    // The loader puts sections that aren't part of the loaded image into
+    // physical memory and records their physical address
+
+    unsafe {
+        SYMBOL_TABLE = Some(SymbolTable {
+            symbols: slice::from_raw_parts(memory::phys_to_virt(symtab.addr as usize) as *const ElfSymbol,
+                                           symtab.size as usize / symtab.entry_size as usize),
+            strings: slice::from_raw_parts(memory::phys_to_virt(strtab.addr as usize) as *const u8,
+                                           strtab.size as usize),
+        });
+    }
+
+    log.ok();
+}
+
+impl SymbolTable {
+    // Finds the function containing `addr`, returns its name and the offset
+    fn lookup(&self, addr: u64) -> Option<(&'static [u8], u64)> {
+        let mut best: Option<&ElfSymbol> = None;
+
+        for symbol in self.symbols.iter() {
+            if symbol.info & 0xf != STT_FUNC || symbol.value > addr {
+                continue;
+            }
+
+            if symbol.size > 0 && addr < symbol.value + symbol.size {
+                best = Some(symbol);
+                break;
+            }
+
+            // Fall back to the closest preceding symbol of unknown size
+            if symbol.size == 0 && best.map_or(true, |b| b.value < symbol.value) {
+                best = Some(symbol);
+            }
+        }
+
+        best.map(|symbol| (self.name(symbol), addr - symbol.value))
+    }
+
+    fn name(&self, symbol: &ElfSymbol) -> &'static [u8] {
+        let start = symbol.name as usize;
+        if start >= self.strings.len() {
+            return b"";
+        }
+
+        let len = self.strings[start..].iter().position(|&b| b == 0).unwrap_or(0);
+        &self.strings[start..start + len]
+    }
+}
+
+
+
+// Formats as `symbol+offset`, or `???` if the address is unknown
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_symbol(f, self.0, 0)
+    }
+}
+
+// Like Symbolized, but for return addresses. These point behind the call,
+// which may already be the first byte of the next function.
+pub struct ReturnAddress(pub u64);
+
+impl fmt::Display for ReturnAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_symbol(f, self.0 - 1, 1)
+    }
+}
+
+fn write_symbol(f: &mut fmt::Formatter, addr: u64, offset_adjust: u64) -> fmt::Result {
+    let table = unsafe { &SYMBOL_TABLE };
+
+    match table.as_ref().and_then(|table| table.lookup(addr)) {
+        Some((name, offset)) => {
+            write_demangled(f, name)?;
+            write!(f, "+{:#x}", offset + offset_adjust)
+        }
+        None => write!(f, "???"),
+    }
+}
+
+// Demangles legacy Rust symbols (_ZN<len><name>...17h<hash>E) into
+// `path::to::function`, dropping the hash
+fn write_demangled(f: &mut fmt::Formatter, name: &[u8]) -> fmt::Result {
+    if !name.starts_with(b"_ZN") || !name.ends_with(b"E") {
+        return f.write_str(str::from_utf8(name).unwrap_or("<invalid>"));
+    }
+
+    let mut rest = &name[3..name.len() - 1];
+    let mut first = true;
+
+    while !rest.is_empty() {
+        let digits = rest.iter().take_while(|&&b| b >= b'0' && b <= b'9').count();
+        let len = str::from_utf8(&rest[..digits]).ok().and_then(|s| s.parse::<usize>().ok());
+        let len = match len {
+            Some(len) if digits + len <= rest.len() => len,
+            _ => return f.write_str(str::from_utf8(name).unwrap_or("<invalid>")),
+        };
+
+        let component = &rest[digits..digits + len];
+        rest = &rest[digits + len..];
+
+        let is_hash = component.len() == 17 && component[0] == b'h';
+        if rest.is_empty() && is_hash {
+            break;
+        }
+
+        if !first {
+            f.write_str("::")?;
+        }
+        first = false;
+
+        write_component(f, component)?;
+    }
+
+    Ok(())
+}
+
+const ESCAPES: [(&'static [u8], &'static str); 15] = [
+    (b"$LT$", "<"), (b"$GT$", ">"), (b"$RF$", "&"), (b"$BP$", "*"), (b"$C$", ","),
+    (b"$SP$", "@"), (b"$u20$", " "), (b"$u27$", "'"), (b"$u5b$", "["), (b"$u5d$", "]"),
+    (b"$u7b$", "{"), (b"$u7d$", "}"), (b"$u7e$", "~"), (b"$u3b$", ";"), (b"..", "::"),
+];
+
+fn write_component(f: &mut fmt::Formatter, mut component: &[u8]) -> fmt::Result {
+    // Components starting with an escape are prefixed by an underscore
+    if component.starts_with(b"_$") {
+        component = &component[1..];
+    }
+
+    'outer: while !component.is_empty() {
+        for &(escape, replacement) in ESCAPES.iter() {
+            if component.starts_with(escape) {
+                f.write_str(replacement)?;
+                component = &component[escape.len()..];
+                continue 'outer;
+            }
+        }
+
+        f.write_str(str::from_utf8(&component[..1]).unwrap_or("?"))?;
+        component = &component[1..];
+    }
+
+    Ok(())
+}
+
+
+
+fn is_readable(addr: u64) -> bool {
+    addr % 8 == 0 && ::memory::paging::is_mapped(addr as usize)
+}
+
+#[inline(always)]
+fn current_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov $0, rbp" : "=r"(rbp) ::: "intel", "volatile");
+    }
+
+    rbp
+}
+
+// Frame pointer of the code an exception handler interrupted. The entry
+// wrappers leave rbp alone, so the handler's prologue saved it in its frame.
+// Must be inlined into the handler itself.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    let rbp = current_frame_pointer();
+    if !is_readable(rbp) {
+        return 0;
+    }
+
+    unsafe { *(rbp as *const u64) }
+}
+
+// Prints the call stack of the caller
+pub fn print() {
+    print_from(current_frame_pointer());
+}
+
+// Prints the call stack of an interrupted context: the instruction at `rip`
+// first, then the frames above it starting at its frame pointer `rbp`
+pub fn print_fault(rip: u64, rbp: u64) {
+    println!("{}Backtrace:", WHITE);
+    println!("{}#-  {}{:#018x} {}{}", LIGHT_GRAY, CYAN, rip, LIGHT_GRAY, Symbolized(rip));
+    walk(rbp);
+}
+
+pub fn print_from(rbp: u64) {
+    println!("{}Backtrace:", WHITE);
+    walk(rbp);
+}
+
+// Walks the frame pointer chain starting at `rbp`. Each frame holds the
+// caller's frame pointer followed by the return address.
+fn walk(mut rbp: u64) {
+    for depth in 0..MAX_DEPTH {
+        if rbp == 0 || !is_readable(rbp) || !is_readable(rbp + 8) {
+            break;
+        }
+
+        let (next, return_addr) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_addr == 0 {
+            break;
+        }
+
+        println!("{}#{:<2} {}{:#018x} {}{}",
+            LIGHT_GRAY, depth, CYAN, return_addr, LIGHT_GRAY, ReturnAddress(return_addr));
+
+        // The stack grows down, older frames are always above
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
@@ -0,0 +1,168 @@
+use cpuid;
+use memory::paging;
+use control_regs::cr4::{self, CR4};
+use control_regs::efer::{self, Efer};
+
+// A hardening or performance feature that is enabled at boot if the CPU
+// supports it. Passing `no<param>` on the kernel command line keeps it off.
+struct CpuFeature {
+    name: &'static str,
+    param: &'static str,
+    supported: fn() -> bool,
+    enable: fn(),
+}
+
+static CPU_FEATURES: [CpuFeature; 8] = [
+    CpuFeature {
+        name: "No-execute pages",
+        param: "nx",
+        supported: nx_supported,
+        enable: enable_nx,
+    },
+    CpuFeature {
+        name: "Global pages",
+        param: "global",
+        supported: global_pages_supported,
+        enable: enable_global_pages,
+    },
+    CpuFeature {
+        name: "Supervisor mode execution prevention",
+        param: "smep",
+        supported: smep_supported,
+        enable: enable_smep,
+    },
+    CpuFeature {
+        name: "Supervisor mode access prevention",
+        param: "smap",
+        supported: smap_supported,
+        enable: enable_smap,
+    },
+    CpuFeature {
+        name: "FSGSBASE instructions",
+        param: "fsgsbase",
+        supported: fsgsbase_supported,
+        enable: enable_fsgsbase,
+    },
+    CpuFeature {
+        name: "Process context identifiers",
+        param: "pcid",
+        supported: pcid_supported,
+        enable: enable_pcid,
+    },
+    CpuFeature {
+        name: "XSAVE instructions",
+        param: "xsave",
+        supported: xsave_supported,
+        enable: enable_xsave,
+    },
+    CpuFeature {
+        name: "Page attribute table",
+        param: "pat",
+        supported: pat_supported,
+        enable: paging::init_pat,
+    },
+];
+
+pub fn enable_supported(command_line: &str) {
+    let log = log!("Enabling CPU features");
+
+    for feature in CPU_FEATURES.iter() {
+        if !(feature.supported)() {
+            println!("{}  {} {}(not supported)", LIGHT_GRAY, feature.name, DARK_GRAY);
+        } else if is_disabled(command_line, feature.param) {
+            println!("{}  {} {}(disabled by no{})",
+                LIGHT_GRAY, feature.name, DARK_GRAY, feature.param);
+        } else {
+            let mut line = log!("  ");
+            line.write(feature.name);
+            (feature.enable)();
+            line.ok();
+        }
+    }
+
+    log.ok();
+}
+
+fn is_disabled(command_line: &str, param: &str) -> bool {
+    command_line.split_whitespace()
+        .any(|arg| arg.starts_with("no") && &arg[2..] == param)
+}
+
+fn set_cr4(flags: CR4) {
+    (CR4::load() | flags).store();
+}
+
+
+
+fn nx_supported() -> bool {
+    cpuid::get_extended_processor_features().contains(cpuid::EXECUTE_DISABLE)
+}
+
+fn enable_nx() {
+    (Efer::load() | efer::EXECUTE_DISABLE_BIT_ENABLED).store();
+}
+
+fn global_pages_supported() -> bool {
+    cpuid::get_features().contains(cpuid::PAGE_GLOBAL_ENABLE)
+}
+
+fn enable_global_pages() {
+    set_cr4(cr4::GLOBAL_PAGES);
+}
+
+fn smep_supported() -> bool {
+    cpuid::get_extended_features().contains(cpuid::SUPERVISOR_EXEC_PREVENTION)
+}
+
+fn enable_smep() {
+    set_cr4(cr4::SUPERVISOR_EXECUTION_PREVENTION);
+}
+
+fn smap_supported() -> bool {
+    cpuid::get_extended_features().contains(cpuid::SUPERVISOR_ACCESS_PREVENTION)
+}
+
+fn enable_smap() {
+    set_cr4(cr4::SUPERVISOR_ACCESS_PREVENTION);
+}
+
+fn fsgsbase_supported() -> bool {
+    cpuid::get_extended_features().contains(cpuid::FSGSBASE)
+}
+
+fn enable_fsgsbase() {
+    set_cr4(cr4::FSGSBASE);
+}
+
+fn pcid_supported() -> bool {
+    cpuid::get_features().contains(cpuid::PROCESS_CONTEXT_IDS)
+}
+
+fn enable_pcid() {
+    // Only allowed while the current PCID (low bits of CR3) is 0, which
+    // is always the case during boot
+    set_cr4(cr4::PCID);
+}
+
+fn xsave_supported() -> bool {
+    cpuid::get_features().contains(cpuid::XSAVE_INSTRUCTIONS)
+}
+
+fn enable_xsave() {
+    set_cr4(cr4::XSAVE);
+}
+
+fn pat_supported() -> bool {
+    cpuid::get_features().contains(cpuid::PAGE_ATTRIBUTE_TABLE)
+}
+
+
+
+#[test]
+fn test_is_disabled() {
+    assert!(is_disabled("nosmap", "smap"));
+    assert!(is_disabled("quiet nosmep  nopcid", "pcid"));
+    assert!(!is_disabled("nosmep", "smap"));
+    assert!(!is_disabled("smap", "smap"));
+    assert!(!is_disabled("", "nx"));
+}
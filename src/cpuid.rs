@@ -1,4 +1,7 @@
+use core::fmt;
+
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
 struct CpuIdResult {
     eax: u32,
     ebx: u32,
@@ -7,6 +10,10 @@ struct CpuIdResult {
 }
 
 fn cpuid(id: u32) -> CpuIdResult {
+    cpuid_subleaf(id, 0)
+}
+
+fn cpuid_subleaf(id: u32, subleaf: u32) -> CpuIdResult {
     let mut result = CpuIdResult {
         eax: 0,
         ebx: 0,
@@ -15,15 +22,37 @@ fn cpuid(id: u32) -> CpuIdResult {
     };
 
     unsafe {
-        internal_cpuid(id, &mut result);
+        internal_cpuid(id, subleaf, &mut result);
     }
 
     result
 }
 
+#[cfg(not(test))]
 #[link(name = "cpuid")]
 extern "C" {
-    fn internal_cpuid(code: u32, ptr: *mut CpuIdResult);
+    fn internal_cpuid(code: u32, subleaf: u32, ptr: *mut CpuIdResult);
+}
+
+// Host tests aren't linked with cpuid.asm and query the host CPU instead
+#[cfg(test)]
+unsafe fn internal_cpuid(code: u32, subleaf: u32, ptr: *mut CpuIdResult) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    asm!("cpuid"
+        : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+        : "{eax}"(code), "{ecx}"(subleaf)
+        :: "intel");
+    *ptr = CpuIdResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx };
+}
+
+// Highest supported standard leaf
+fn max_leaf() -> u32 {
+    cpuid(0).eax
+}
+
+// Highest supported extended (0x8000_0000+) leaf
+fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000).eax
 }
 
 
@@ -34,7 +63,7 @@ extern "C" {
 
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum Vendor {
     OLD_AMD, // "AMDisbetter!"
@@ -49,6 +78,7 @@ pub enum Vendor {
     SIS, // "SiS SiS SiS "
     NSC, // "Geode by NSC"
     RISE, // "RiseRiseRise"
+    Unknown([u8; 12]),
 }
 
 impl Vendor {
@@ -68,7 +98,7 @@ impl Vendor {
             b"SiS SiS SiS " => SIS,
             b"Geode by NSC" => NSC,
             b"RiseRiseRise" => RISE,
-            vendor => panic!("Unknown CPU Vendor: {:?}", vendor),
+            vendor => Unknown(*vendor),
         }
     }
 }
@@ -82,20 +112,116 @@ pub fn get_vendor() -> Vendor {
         edx
     } = cpuid(0);
 
-    let buf = [ebx as u8,
-               (ebx >> 8) as u8,
-               (ebx >> 16) as u8,
-               (ebx >> 24) as u8,
-               edx as u8,
-               (edx >> 8) as u8,
-               (edx >> 16) as u8,
-               (edx >> 24) as u8,
-               ecx as u8,
-               (ecx >> 8) as u8,
-               (ecx >> 16) as u8,
-               (ecx >> 24) as u8];
+    Vendor::for_name(&registers_to_name(ebx, edx, ecx))
+}
 
-    Vendor::for_name(&buf)
+// Concatenates three registers into a 12 byte identification string
+fn registers_to_name(first: u32, second: u32, third: u32) -> [u8; 12] {
+    [first as u8,
+     (first >> 8) as u8,
+     (first >> 16) as u8,
+     (first >> 24) as u8,
+     second as u8,
+     (second >> 8) as u8,
+     (second >> 16) as u8,
+     (second >> 24) as u8,
+     third as u8,
+     (third >> 8) as u8,
+     (third >> 16) as u8,
+     (third >> 24) as u8]
+}
+
+
+
+
+
+
+
+
+
+// Hypervisors report themselves in this range if IS_HYPERVISOR is set
+const HYPERVISOR_LEAF_BASE: u32 = 0x4000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum Hypervisor {
+    KVM, // "KVMKVMKVM\0\0\0"
+    TCG, // "TCGTCGTCGTCG"
+    HYPER_V, // "Microsoft Hv"
+    VMWARE, // "VMwareVMware"
+    XEN, // "XenVMMXenVMM"
+    Unknown([u8; 12]),
+}
+
+impl Hypervisor {
+    fn for_name(name: &[u8; 12]) -> Hypervisor {
+        use self::Hypervisor::*;
+
+        match name {
+            b"KVMKVMKVM\0\0\0" => KVM,
+            b"TCGTCGTCGTCG" => TCG,
+            b"Microsoft Hv" => HYPER_V,
+            b"VMwareVMware" => VMWARE,
+            b"XenVMMXenVMM" => XEN,
+            hypervisor => Unknown(*hypervisor),
+        }
+    }
+}
+
+fn hypervisor_at(base: u32) -> Hypervisor {
+    let CpuIdResult {
+        eax: _,
+        ebx,
+        ecx,
+        edx
+    } = cpuid(base);
+
+    Hypervisor::for_name(&registers_to_name(ebx, ecx, edx))
+}
+
+pub fn get_hypervisor() -> Option<Hypervisor> {
+    if !get_features().contains(IS_HYPERVISOR) {
+        return None;
+    }
+
+    Some(hypervisor_at(HYPERVISOR_LEAF_BASE))
+}
+
+// Base leaf of the KVM interface. Hypervisors offering several interfaces
+// (e.g. KVM with Hyper-V enlightenments) move KVM to 0x4000_0100.
+fn kvm_leaf_base() -> Option<u32> {
+    if !get_features().contains(IS_HYPERVISOR) {
+        return None;
+    }
+
+    for &base in [HYPERVISOR_LEAF_BASE, HYPERVISOR_LEAF_BASE + 0x100].iter() {
+        if hypervisor_at(base) == Hypervisor::KVM {
+            return Some(base);
+        }
+    }
+
+    None
+}
+
+bitflags! {
+    // Paravirtual features offered by KVM (leaf base + 1, eax)
+    flags KvmFeatures: u32 {
+        const KVM_CLOCKSOURCE           = 1 <<  0,
+        const KVM_NOP_IO_DELAY          = 1 <<  1,
+        const KVM_MMU_OP                = 1 <<  2,
+        const KVM_CLOCKSOURCE2          = 1 <<  3,
+        const KVM_ASYNC_PAGE_FAULT      = 1 <<  4,
+        const KVM_STEAL_TIME            = 1 <<  5,
+        const KVM_PV_END_OF_INTERRUPT   = 1 <<  6,
+        const KVM_PV_UNHALT             = 1 <<  7,
+        const KVM_PV_TLB_FLUSH          = 1 <<  9,
+        const KVM_PV_SEND_IPI           = 1 << 11,
+        const KVM_CLOCKSOURCE_STABLE    = 1 << 24
+    }
+}
+
+pub fn get_kvm_features() -> Option<KvmFeatures> {
+    kvm_leaf_base().map(|base| KvmFeatures::from_bits_truncate(cpuid(base + 1).eax))
 }
 
 
@@ -183,3 +309,528 @@ pub fn get_features() -> Features {
 
     Features::from_bits((ecx as u64) << 32 | (edx as u64)).unwrap()
 }
+
+
+
+
+
+
+
+
+
+bitflags! {
+    // Structured extended features (leaf 7, subleaf 0)
+    flags ExtendedFeatures: u64 {
+        const FSGSBASE                  = 1 <<  0,
+        const TSC_ADJUST_MSR            = 1 <<  1,
+        const SOFTWARE_GUARD_EXTENSIONS = 1 <<  2,
+        const BIT_MANIPULATION_1        = 1 <<  3,
+        const HARDWARE_LOCK_ELISION     = 1 <<  4,
+        const AVX2                      = 1 <<  5,
+        const FDP_EXCEPTION_ONLY        = 1 <<  6,
+        const SUPERVISOR_EXEC_PREVENTION= 1 <<  7,
+        const BIT_MANIPULATION_2        = 1 <<  8,
+        const ENHANCED_REP_MOVSB        = 1 <<  9,
+        const INVPCID                   = 1 << 10,
+        const RESTRICTED_TRANSACTIONS   = 1 << 11,
+        const RESOURCE_DIRECTOR_MONITOR = 1 << 12,
+        const FPU_CS_DS_DEPRECATED      = 1 << 13,
+        const MEMORY_PROTECTION_EXT     = 1 << 14,
+        const RESOURCE_DIRECTOR_ALLOC   = 1 << 15,
+        const AVX512_FOUNDATION         = 1 << 16,
+        const AVX512_DWORD_QWORD        = 1 << 17,
+        const RDSEED                    = 1 << 18,
+        const ADX                       = 1 << 19,
+        const SUPERVISOR_ACCESS_PREVENTION = 1 << 20,
+        const AVX512_INTEGER_FMA        = 1 << 21,
+        const CLFLUSHOPT                = 1 << 23,
+        const CLWB                      = 1 << 24,
+        const PROCESSOR_TRACE           = 1 << 25,
+        const AVX512_PREFETCH           = 1 << 26,
+        const AVX512_EXPONENTIAL        = 1 << 27,
+        const AVX512_CONFLICT_DETECTION = 1 << 28,
+        const SHA_INSTRUCTIONS          = 1 << 29,
+        const AVX512_BYTE_WORD          = 1 << 30,
+        const AVX512_VECTOR_LENGTH      = 1 << 31,
+        // Ecx flags begin here
+        const PREFETCHWT1               = 1 << 32,
+        const AVX512_VECTOR_BYTE_MANIP  = 1 << 33,
+        const USER_MODE_INSTR_PREVENTION= 1 << 34,
+        const PROTECTION_KEYS_USER      = 1 << 35,
+        const OS_PROTECTION_KEYS_ENABLED= 1 << 36,
+        const WAITPKG                   = 1 << 37,
+        const AVX512_VECTOR_BYTE_MANIP_2= 1 << 38,
+        const CET_SHADOW_STACK          = 1 << 39,
+        const GALOIS_FIELD_INSTRUCTIONS = 1 << 40,
+        const VECTOR_AES                = 1 << 41,
+        const VECTOR_PCLMULQDQ          = 1 << 42,
+        const AVX512_NEURAL_NETWORK     = 1 << 43,
+        const AVX512_BIT_ALGORITHMS     = 1 << 44,
+        const AVX512_POPCNT_DQ          = 1 << 46,
+        const FIVE_LEVEL_PAGING         = 1 << 48,
+        const RDPID                     = 1 << 54,
+        const SGX_LAUNCH_CONFIG         = 1 << 62
+    }
+}
+
+pub fn get_extended_features() -> ExtendedFeatures {
+    if max_leaf() < 7 {
+        return ExtendedFeatures::empty();
+    }
+
+    let CpuIdResult {
+        eax: _,
+        ebx,
+        ecx,
+        edx: _
+    } = cpuid_subleaf(7, 0);
+
+    ExtendedFeatures::from_bits_truncate((ecx as u64) << 32 | (ebx as u64))
+}
+
+
+
+
+
+
+
+
+
+bitflags! {
+    // Extended processor info and feature bits (leaf 0x8000_0001)
+    flags ExtendedProcessorFeatures: u64 {
+        const SYSCALL                   = 1 << 11,
+        const MULTIPROCESSOR_CAPABLE    = 1 << 19,
+        const EXECUTE_DISABLE           = 1 << 20,
+        const MMX_EXTENSIONS            = 1 << 22,
+        const FXSAVE_OPTIMIZATIONS      = 1 << 25,
+        const PAGES_1GIB                = 1 << 26,
+        const RDTSCP                    = 1 << 27,
+        const LONG_MODE                 = 1 << 29,
+        const EXTENDED_3DNOW            = 1 << 30,
+        const INSTRUCTIONS_3DNOW        = 1 << 31,
+        // Ecx flags begin here
+        const LAHF_IN_LONG_MODE         = 1 << 32,
+        const CMP_LEGACY                = 1 << 33,
+        const SECURE_VIRTUAL_MACHINE    = 1 << 34,
+        const EXTENDED_APIC_SPACE       = 1 << 35,
+        const CR8_IN_LEGACY_MODE        = 1 << 36,
+        const ADVANCED_BIT_MANIPULATION = 1 << 37,
+        const SSE4A                     = 1 << 38,
+        const MISALIGNED_SSE            = 1 << 39,
+        const PREFETCHW                 = 1 << 40,
+        const OS_VISIBLE_WORKAROUND     = 1 << 41,
+        const INSTRUCTION_BASED_SAMPLING= 1 << 42,
+        const EXTENDED_OPERATIONS       = 1 << 43,
+        const SKINIT                    = 1 << 44,
+        const WATCHDOG_TIMER            = 1 << 45,
+        const LIGHTWEIGHT_PROFILING     = 1 << 47,
+        const FUSED_MULTIPLY_ADD_4      = 1 << 48,
+        const TRANSLATION_CACHE_EXT     = 1 << 49,
+        const TRAILING_BIT_MANIPULATION = 1 << 53,
+        const TOPOLOGY_EXTENSIONS       = 1 << 54
+    }
+}
+
+pub fn get_extended_processor_features() -> ExtendedProcessorFeatures {
+    if max_extended_leaf() < 0x8000_0001 {
+        return ExtendedProcessorFeatures::empty();
+    }
+
+    let CpuIdResult {
+        eax: _,
+        ebx: _,
+        ecx,
+        edx
+    } = cpuid(0x8000_0001);
+
+    ExtendedProcessorFeatures::from_bits_truncate((ecx as u64) << 32 | (edx as u64))
+}
+
+
+
+
+
+
+
+
+
+const BRAND_STRING_LENGTH: usize = 48;
+
+pub struct BrandString {
+    bytes: [u8; BRAND_STRING_LENGTH],
+}
+
+impl BrandString {
+    fn from_registers(regs: &[CpuIdResult; 3]) -> BrandString {
+        let mut bytes = [0; BRAND_STRING_LENGTH];
+
+        for (i, result) in regs.iter().enumerate() {
+            let words = [result.eax, result.ebx, result.ecx, result.edx];
+            for (j, word) in words.iter().enumerate() {
+                for k in 0..4 {
+                    bytes[i * 16 + j * 4 + k] = (*word >> (k * 8)) as u8;
+                }
+            }
+        }
+
+        BrandString { bytes: bytes }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // The string is null terminated and, on some Intel CPUs, right-aligned
+        let end = self.bytes.iter().position(|&b| b == 0).unwrap_or(BRAND_STRING_LENGTH);
+        let start = self.bytes[..end].iter().position(|&b| b != b' ').unwrap_or(end);
+
+        ::core::str::from_utf8(&self.bytes[start..end]).unwrap_or("<invalid brand string>")
+    }
+}
+
+impl fmt::Debug for BrandString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for BrandString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+pub fn get_brand_string() -> Option<BrandString> {
+    if max_extended_leaf() < 0x8000_0004 {
+        return None;
+    }
+
+    let regs = [cpuid(0x8000_0002), cpuid(0x8000_0003), cpuid(0x8000_0004)];
+    Some(BrandString::from_registers(&regs))
+}
+
+
+
+
+
+
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheInfo {
+    pub level: u8,
+    pub cache_type: CacheType,
+    pub line_size: u32,
+    pub partitions: u32,
+    pub ways: u32,
+    pub sets: u32,
+    pub fully_associative: bool,
+    pub inclusive: bool,
+    // Maximum number of logical processors sharing this cache
+    pub shared_by: u32,
+}
+
+impl CacheInfo {
+    // Decodes the deterministic cache parameters reported by leaf 4 (Intel)
+    // and leaf 0x8000_001D (AMD). Both use the same register layout.
+    fn from_registers(regs: &CpuIdResult) -> Option<CacheInfo> {
+        let cache_type = match regs.eax & 0b1_1111 {
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            _ => return None, // No more caches
+        };
+
+        Some(CacheInfo {
+            level: ((regs.eax >> 5) & 0b111) as u8,
+            cache_type: cache_type,
+            line_size: (regs.ebx & 0xfff) + 1,
+            partitions: ((regs.ebx >> 12) & 0x3ff) + 1,
+            ways: ((regs.ebx >> 22) & 0x3ff) + 1,
+            sets: regs.ecx + 1,
+            fully_associative: regs.eax & (1 << 9) != 0,
+            inclusive: regs.edx & (1 << 1) != 0,
+            shared_by: ((regs.eax >> 14) & 0xfff) + 1,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.line_size as usize * self.partitions as usize * self.ways as usize *
+        self.sets as usize
+    }
+}
+
+pub struct CacheInfoIter {
+    leaf: Option<u32>,
+    index: u32,
+}
+
+impl Iterator for CacheInfoIter {
+    type Item = CacheInfo;
+
+    fn next(&mut self) -> Option<CacheInfo> {
+        let leaf = match self.leaf {
+            Some(leaf) => leaf,
+            None => return None,
+        };
+
+        let info = CacheInfo::from_registers(&cpuid_subleaf(leaf, self.index));
+        if info.is_none() {
+            self.leaf = None;
+        }
+
+        self.index += 1;
+        info
+    }
+}
+
+pub fn get_cache_info() -> CacheInfoIter {
+    let leaf = match get_vendor() {
+        Vendor::INTEL if max_leaf() >= 4 => Some(4),
+        Vendor::AMD if max_extended_leaf() >= 0x8000_001d &&
+                       get_extended_processor_features().contains(TOPOLOGY_EXTENSIONS) => {
+            Some(0x8000_001d)
+        }
+        _ => None,
+    };
+
+    CacheInfoIter {
+        leaf: leaf,
+        index: 0,
+    }
+}
+
+
+
+
+
+
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopologyLevelType {
+    Thread,
+    Core,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TopologyLevel {
+    pub level_type: TopologyLevelType,
+    // Logical processors at this level (e.g. threads per core)
+    pub logical_processors: u32,
+    // Shift of the x2APIC id to get the id of the next level
+    pub id_shift: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Topology {
+    pub x2apic_id: u32,
+    pub threads_per_core: u32,
+    pub logical_processors_per_package: u32,
+}
+
+pub struct TopologyLevelIter {
+    index: u32,
+    done: bool,
+}
+
+impl Iterator for TopologyLevelIter {
+    type Item = TopologyLevel;
+
+    fn next(&mut self) -> Option<TopologyLevel> {
+        if self.done {
+            return None;
+        }
+
+        let regs = cpuid_subleaf(0xb, self.index);
+        let level_type = match (regs.ecx >> 8) & 0xff {
+            0 => {
+                self.done = true;
+                return None;
+            }
+            1 => TopologyLevelType::Thread,
+            2 => TopologyLevelType::Core,
+            other => TopologyLevelType::Other(other as u8),
+        };
+
+        self.index += 1;
+        Some(TopologyLevel {
+            level_type: level_type,
+            logical_processors: regs.ebx & 0xffff,
+            id_shift: (regs.eax & 0b1_1111) as u8,
+        })
+    }
+}
+
+pub fn get_topology_levels() -> TopologyLevelIter {
+    TopologyLevelIter {
+        index: 0,
+        done: max_leaf() < 0xb || cpuid_subleaf(0xb, 0).ebx == 0,
+    }
+}
+
+pub fn get_topology() -> Option<Topology> {
+    if max_leaf() < 0xb || cpuid_subleaf(0xb, 0).ebx == 0 {
+        return None;
+    }
+
+    let mut topology = Topology {
+        x2apic_id: cpuid_subleaf(0xb, 0).edx,
+        threads_per_core: 1,
+        logical_processors_per_package: 1,
+    };
+
+    for level in get_topology_levels() {
+        match level.level_type {
+            TopologyLevelType::Thread => topology.threads_per_core = level.logical_processors,
+            TopologyLevelType::Core => {
+                topology.logical_processors_per_package = level.logical_processors
+            }
+            TopologyLevelType::Other(_) => {}
+        }
+    }
+
+    Some(topology)
+}
+
+
+
+
+
+
+
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct XsaveInfo {
+    // State components that may be enabled in XCR0
+    pub supported_components: u64,
+    // Size of the save area for the components currently enabled in XCR0
+    pub enabled_size: u32,
+    // Size of the save area if all supported components are enabled
+    pub max_size: u32,
+}
+
+pub fn get_xsave_info() -> Option<XsaveInfo> {
+    if !get_features().contains(XSAVE_INSTRUCTIONS) || max_leaf() < 0xd {
+        return None;
+    }
+
+    let CpuIdResult {
+        eax,
+        ebx,
+        ecx,
+        edx
+    } = cpuid_subleaf(0xd, 0);
+
+    Some(XsaveInfo {
+        supported_components: (edx as u64) << 32 | (eax as u64),
+        enabled_size: ebx,
+        max_size: ecx,
+    })
+}
+
+
+
+
+
+
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+}
+
+impl Signature {
+    // The extended family only counts for family 0xf, the extended model
+    // for families 6 and 0xf
+    fn from_eax(eax: u32) -> Signature {
+        let family = (eax >> 8) & 0xf;
+        let model = (eax >> 4) & 0xf;
+
+        Signature {
+            family: if family == 0xf { family + (eax >> 20 & 0xff) } else { family },
+            model: if family == 0x6 || family == 0xf { (eax >> 16 & 0xf) << 4 | model } else { model },
+            stepping: eax & 0xf,
+        }
+    }
+}
+
+pub fn get_signature() -> Signature {
+    Signature::from_eax(cpuid(1).eax)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AddressWidths {
+    pub physical: u8,
+    pub linear: u8,
+}
+
+pub fn get_address_widths() -> AddressWidths {
+    if max_extended_leaf() < 0x8000_0008 {
+        // Without the leaf, PAE implies 36 bit physical addresses
+        let physical = if get_features().contains(PHYSICAL_ADDR_EXTENSIONS) { 36 } else { 32 };
+        return AddressWidths {
+            physical: physical,
+            linear: 48,
+        };
+    }
+
+    let eax = cpuid(0x8000_0008).eax;
+    AddressWidths {
+        physical: eax as u8,
+        linear: (eax >> 8) as u8,
+    }
+}
+
+
+
+#[test]
+fn test_brand_string() {
+    // "  Intel(R) Cpu" padded with spaces in front and nulls at the end
+    let regs = [CpuIdResult { eax: 0x6e492020, ebx: 0x286c6574, ecx: 0x43202952, edx: 0x00007570 },
+                CpuIdResult { eax: 0, ebx: 0, ecx: 0, edx: 0 },
+                CpuIdResult { eax: 0, ebx: 0, ecx: 0, edx: 0 }];
+
+    assert_eq!(BrandString::from_registers(&regs).as_str(), "Intel(R) Cpu");
+}
+
+#[test]
+fn test_signature() {
+    let signature = |family, model, stepping| Signature { family: family, model: model, stepping: stepping };
+
+    // Skylake, Pentium 4 and Zen
+    assert_eq!(Signature::from_eax(0x506e3), signature(6, 0x5e, 3));
+    assert_eq!(Signature::from_eax(0xf29), signature(0xf, 2, 9));
+    assert_eq!(Signature::from_eax(0x800f12), signature(0x17, 1, 2));
+}
+
+#[test]
+fn test_cache_info() {
+    // 32 KiB L1 data cache, 8 ways, 64 sets, 64 byte lines, shared by 2 threads
+    let regs = CpuIdResult {
+        eax: 1 | 1 << 5 | 1 << 14,
+        ebx: 63 | 0 << 12 | 7 << 22,
+        ecx: 63,
+        edx: 0,
+    };
+    let info = CacheInfo::from_registers(&regs).unwrap();
+
+    assert_eq!(info.level, 1);
+    assert_eq!(info.cache_type, CacheType::Data);
+    assert_eq!(info.size(), 32 * 1024);
+    assert_eq!(info.shared_by, 2);
+    assert!(CacheInfo::from_registers(&CpuIdResult { eax: 0, ebx: 0, ecx: 0, edx: 0 }).is_none());
+}
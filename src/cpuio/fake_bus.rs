@@ -0,0 +1,98 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use cpuio::port::{CpuIoValue, PortBus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read(u16, u32),
+    Write(u16, u32),
+}
+
+// Records every access in order. Reads return the values scripted for
+// the port, then its default value, then 0xff like a port nothing
+// answers on.
+pub struct FakeBus {
+    accesses: RefCell<Vec<Access>>,
+    scripted: RefCell<HashMap<u16, VecDeque<u32>>>,
+    defaults: RefCell<HashMap<u16, u32>>,
+}
+
+impl FakeBus {
+    pub fn new() -> FakeBus {
+        FakeBus {
+            accesses: RefCell::new(Vec::new()),
+            scripted: RefCell::new(HashMap::new()),
+            defaults: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Queues values for the next reads of `port`
+    pub fn script(&self, port: u16, values: &[u32]) {
+        self.scripted
+            .borrow_mut()
+            .entry(port)
+            .or_insert_with(VecDeque::new)
+            .extend(values.iter().cloned());
+    }
+
+    // Value read from `port` once its scripted values ran out
+    pub fn set_default(&self, port: u16, value: u32) {
+        self.defaults.borrow_mut().insert(port, value);
+    }
+
+    pub fn accesses(&self) -> Vec<Access> {
+        self.accesses.borrow().clone()
+    }
+
+    pub fn writes(&self) -> Vec<(u16, u32)> {
+        self.accesses
+            .borrow()
+            .iter()
+            .filter_map(|access| match *access {
+                Access::Write(port, value) => Some((port, value)),
+                Access::Read(..) => None,
+            })
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.accesses.borrow_mut().clear();
+    }
+}
+
+impl PortBus for FakeBus {
+    unsafe fn read<T: CpuIoValue>(&self, port: u16) -> T {
+        let scripted = self.scripted.borrow_mut().get_mut(&port).and_then(|values| values.pop_front());
+        let value = scripted.or_else(|| self.defaults.borrow().get(&port).cloned())
+                            .unwrap_or(0xffff_ffff);
+        let value = T::from_u32(value);
+
+        self.accesses.borrow_mut().push(Access::Read(port, value.to_u32()));
+        value
+    }
+
+    unsafe fn write<T: CpuIoValue>(&self, port: u16, value: T) {
+        self.accesses.borrow_mut().push(Access::Write(port, value.to_u32()));
+    }
+}
+
+#[test]
+fn test_fake_bus() {
+    use cpuio::Port;
+
+    let bus = FakeBus::new();
+    let port = unsafe { Port::<u8, _>::on_bus(0x60, &bus) };
+    bus.script(0x60, &[0x1e, 0x9e]);
+    bus.set_default(0x60, 0x00);
+
+    assert_eq!(port.read(), 0x1e);
+    port.write(0xf4);
+    assert_eq!(port.read(), 0x9e);
+    assert_eq!(port.read(), 0x00);
+    assert_eq!(bus.accesses(), vec![Access::Read(0x60, 0x1e), Access::Write(0x60, 0xf4),
+                                    Access::Read(0x60, 0x9e), Access::Read(0x60, 0x00)]);
+
+    // Unscripted reads float high, truncated to the port width
+    let wide = unsafe { Port::<u16, _>::on_bus(0x1f0, &bus) };
+    assert_eq!(wide.read(), 0xffff);
+}
@@ -10,6 +10,34 @@ pub struct ExceptionStackFrame {
     pub stack_segment: u64
 }
 
+// General purpose registers as pushed by save_all_registers!
+#[repr(C,packed)]
+#[derive(Copy,Clone,Debug)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+#[repr(C,packed)]
+#[derive(Copy,Clone,Debug)]
+pub struct InterruptContext {
+    pub registers: Registers,
+    pub stack_frame: ExceptionStackFrame,
+}
+
 #[repr(C,packed)]
 #[derive(Copy,Clone,Debug)]
 pub struct ErrorCode(u64);
@@ -56,8 +84,15 @@ bitflags! (
     }
 );
 
+// Starts the backtrace at the faulting instruction, then walks up from the
+// interrupted frame pointer (read from the handler's frame unless given)
 macro_rules! fail {
-    () => {
+    ($stack_frame:expr) => {
+        fail!($stack_frame, ::backtrace::interrupted_frame_pointer());
+    };
+    ($stack_frame:expr, $rbp:expr) => {
+        ::backtrace::print_fault($stack_frame.instruction_pointer, $rbp);
+        ::testing::handle_fault();
         println!("\n\\{},{};Can't recover\\{},{};", 
             BLACK as u8, RED as u8, RED as u8, BLACK as u8);
         unsafe { asm!("hlt") }
@@ -67,7 +102,7 @@ pub extern "C" fn divide_by_zero_handler(stack_frame: &ExceptionStackFrame) {
     println!("{}\nERROR: {}division by zero", RED, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
     
-    fail!();
+    fail!(stack_frame);
 }
 
 pub extern "C" fn invalid_opcode_handler(stack_frame: &ExceptionStackFrame) {
@@ -75,121 +110,204 @@ pub extern "C" fn invalid_opcode_handler(stack_frame: &ExceptionStackFrame) {
         RED, WHITE, stack_frame.instruction_pointer);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
-pub extern "C" fn page_fault_handler(stack_frame: &ExceptionStackFrame, errno: u64) {
+pub extern "C" fn page_fault_handler(stack_frame: &mut ExceptionStackFrame, errno: u64) {
     use ::x86::shared::control_regs;
 
+    if ::fixup::handle_fault(stack_frame) {
+        return;
+    }
+
     println!("{}\nERROR: {}page fault trying to access 0x{:x} ({:?})", 
         RED, WHITE,
         unsafe { control_regs::cr2() },
         PageFaultErrorCode::from_bits(errno).unwrap());
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
-pub extern "C" fn breakpoint_handler(stack_frame: &ExceptionStackFrame) {
-    println!("{}\nBREAKPOINT: {}At instruction {:#x}", 
-        RED, WHITE, stack_frame.instruction_pointer);
-    println!("{}{:#?}", LIGHT_GRAY, stack_frame);
+pub extern "C" fn breakpoint_handler(context: &mut InterruptContext) {
+    if ::gdb::is_enabled() {
+        ::gdb::enter(context, ::gdb::SIGTRAP);
+        return;
+    }
 
-    println!("Press any key to continue");
-    ::keyboard::next_key();
+    ::monitor::enter(context, "BREAKPOINT");
 }
 
-pub extern "C" fn debug_exception_handler(stack_frame: &ExceptionStackFrame) {
-    let dr6: u64;
-    unsafe { asm!("mov $0, dr6" : "=r"(dr6) ::: "intel", "volatile"); }
+pub extern "C" fn debug_exception_handler(context: &mut InterruptContext) {
+    use ::debug_regs::{self, BreakCondition};
+    use ::debug_regs::dr6::Dr6;
+    use ::control_regs::rflags;
 
-    println!("{}\nDEBUG: {}Triggered (DR6: {})", RED, WHITE, dr6);
-    println!("{}{:#?}", LIGHT_GRAY, stack_frame);
+    let dr6 = Dr6::load();
+    Dr6::clear();
+
+    let mut handled = false;
+    if dr6.contains(::debug_regs::dr6::SINGLE_STEP) && ::trace::is_active() {
+        ::trace::record(context);
+        handled = true;
+    }
+
+    // Steps and breakpoints requested by gdb
+    let triggered = (0..debug_regs::SLOT_COUNT).any(|slot| dr6.triggered(slot));
+    if !handled && ::gdb::is_enabled() &&
+       (dr6.contains(::debug_regs::dr6::SINGLE_STEP) || triggered) {
+        ::gdb::enter(context, ::gdb::SIGTRAP);
+        return;
+    }
+
+    if !handled && dr6.contains(::debug_regs::dr6::SINGLE_STEP) && ::monitor::is_stepping() {
+        ::monitor::enter(context, "STEP");
+        return;
+    }
+
+    for slot in 0..debug_regs::SLOT_COUNT {
+        if !dr6.triggered(slot) {
+            continue;
+        }
+
+        if let Some(bp) = debug_regs::get(slot) {
+            if bp.name == ::monitor::BREAKPOINT_NAME {
+                ::monitor::enter(context, "WATCHPOINT");
+                return;
+            }
+
+            println!("{}\nWATCHPOINT: {}{} {}(#{}, {:?} {:#x}, {} bytes) hit at {:#x}",
+                YELLOW, WHITE, bp.name, LIGHT_GRAY,
+                slot, bp.condition, bp.address, bp.size.bytes(),
+                context.stack_frame.instruction_pointer);
+
+            // Instruction breakpoints are faults, resume without
+            // triggering the same breakpoint again
+            if bp.condition == BreakCondition::Execute {
+                context.stack_frame.flags |= rflags::INSTRUCTION_BREAKPOINTS_DISABLED.bits();
+            }
+            handled = true;
+        }
+    }
+
+    if handled {
+        return;
+    }
+
+    println!("{}\nDEBUG: {}Triggered (DR6: {:?})", RED, WHITE, dr6);
+    println!("{}{:#?}", LIGHT_GRAY, context.stack_frame);
     
-    fail!();
+    fail!(context.stack_frame, context.registers.rbp);
 }
 
 pub extern "C" fn overflow_handler(stack_frame: &ExceptionStackFrame) {
     println!("{}\nERROR: {}Overflow", RED, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
 pub extern "C" fn out_of_bounds_handler(stack_frame: &ExceptionStackFrame) {
     println!("{}\nERROR: {}Out of bounds", RED, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
 pub extern "C" fn device_not_available_handler(stack_frame: &ExceptionStackFrame) {
+    // Lazy FPU switching: load the state of the running context
+    if ::fpu::handle_device_not_available() {
+        return;
+    }
+
     println!("{}\nERROR: {}Couldn't execute FP instruction at {}{}{} (Device not available)", RED, WHITE, LIGHT_GRAY, stack_frame.instruction_pointer, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
-pub extern "C" fn double_fault_handler(stack_frame: &ExceptionStackFrame, _: u64) {
+pub extern "C" fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, _: u64) {
     println!("{}\nFATAL: {}Double fault", RED, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
-pub extern "C" fn missing_segment_handler(stack_frame: &ExceptionStackFrame, error: ErrorCode) {
+pub extern "C" fn missing_segment_handler(stack_frame: &mut ExceptionStackFrame, error: ErrorCode) {
     println!("{}\nERROR: {}Missing segment (Details: {}{}{})", RED, WHITE, LIGHT_GRAY, error, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
-pub extern "C" fn stack_fault_handler(stack_frame: &ExceptionStackFrame, error: ErrorCode) {
+pub extern "C" fn stack_fault_handler(stack_frame: &mut ExceptionStackFrame, error: ErrorCode) {
     println!("{}\nERROR: {}Stack fault (Details: {}{}{})", RED, WHITE, LIGHT_GRAY, error, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
-pub extern "C" fn general_protection_fault_handler(stack_frame: &ExceptionStackFrame, error: ErrorCode) {
+pub extern "C" fn general_protection_fault_handler(stack_frame: &mut ExceptionStackFrame, error: ErrorCode) {
+    // Non-canonical addresses raise #GP instead of a page fault
+    if ::fixup::handle_fault(stack_frame) {
+        return;
+    }
+
     println!("{}\nERROR: {}General Protection Exception (Error: {}{}{})", RED, WHITE, LIGHT_GRAY, error, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
 pub extern "C" fn floating_point_error_handler(stack_frame: &ExceptionStackFrame) {
     println!("{}\nERROR: {}Error executing floating-point instruction", RED, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
-pub extern "C" fn alignment_check_handler(stack_frame: &ExceptionStackFrame, error: ErrorCode) {
+pub extern "C" fn alignment_check_handler(stack_frame: &mut ExceptionStackFrame, error: ErrorCode) {
     println!("{}\nERROR: {}Alignment checking requested, operand at {}{:#x}{} is not aligned", RED, WHITE, LIGHT_GRAY, stack_frame.instruction_pointer, WHITE);
     println!("Externally triggered: {}", error.external());
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
 pub extern "C" fn machine_check_handler(stack_frame: &ExceptionStackFrame) {
-    println!("{}\nFATAL: {}Machine Check or Bus Error", RED, WHITE);
+    println!("{}\nMACHINE CHECK: {}Machine Check or Bus Error", RED, WHITE);
+    if ::mca::handle_machine_check() {
+        println!("{}Recovered, resuming at {:#x}", LIGHT_GRAY, stack_frame.instruction_pointer);
+        return;
+    }
+
+    println!("{}\nFATAL: {}Unrecoverable machine check", RED, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
 pub extern "C" fn simd_handler(stack_frame: &ExceptionStackFrame) {
     println!("{}\nERROR: {}Error executing SIMD instruction", RED, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
+    fail!(stack_frame);
 }
 
 pub extern "C" fn virtualization_error_handler(stack_frame: &ExceptionStackFrame) {
     println!("{}\nERROR: {}Virtualization error", RED, WHITE);
     println!("{}{:#?}", LIGHT_GRAY, stack_frame);
 
-    fail!();
-}
\ No newline at end of file
+    fail!(stack_frame);
+}
+
+// Raised by the master PIC for IRQs that went away and by the APIC,
+// neither expects an end of interrupt
+pub extern "C" fn spurious_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
+}
+
+// A spurious IRQ 15 from the slave PIC. The master did deliver the cascade
+// IRQ 2 and keeps lower priority IRQs blocked until it gets its EOI.
+pub extern "C" fn spurious_slave_interrupt_handler(_stack_frame: &ExceptionStackFrame) {
+    ::cpuio::legacy_irq_done(2);
+}
@@ -1,5 +1,8 @@
 mod exceptions;
 use self::exceptions::*;
+pub use self::exceptions::{ExceptionStackFrame, InterruptContext, Registers};
+use super::{PIC1_VECTOR_BASE, PIC2_VECTOR_BASE};
+use serial::{irq3_handler as serial_irq3_handler, irq4_handler as serial_irq4_handler};
 
 pub type HandlerFunc = extern "C" fn() -> !;
 const NUM_ENTRIES: usize = 256;
@@ -141,6 +144,49 @@ macro_rules! restore_scratch_registers {
     }
 }
 
+// Saves every general purpose register, in the order of `Registers`
+macro_rules! save_all_registers {
+    () => {
+        asm!("push rax
+              push rbx
+              push rcx
+              push rdx
+              push rsi
+              push rdi
+              push rbp
+              push r8
+              push r9
+              push r10
+              push r11
+              push r12
+              push r13
+              push r14
+              push r15
+        " :::: "intel", "volatile");
+    }
+}
+
+macro_rules! restore_all_registers {
+    () => {
+        asm!("pop r15
+              pop r14
+              pop r13
+              pop r12
+              pop r11
+              pop r10
+              pop r9
+              pop r8
+              pop rbp
+              pop rdi
+              pop rsi
+              pop rdx
+              pop rcx
+              pop rbx
+              pop rax"
+              :::: "intel", "volatile");
+    }
+}
+
 macro_rules! handler {
     ($name:ident) => ({
         #[naked]
@@ -177,7 +223,7 @@ macro_rules! handler_with_raw_error {
                       sub rsp, 8 // align stack: 9 regs pushed + 6 error qwords
                       call $0
                       add rsp, 8"
-                      :: "i"($name as extern "C" fn(&ExceptionStackFrame, u64))
+                      :: "i"($name as extern "C" fn(&mut ExceptionStackFrame, u64))
                       : "rdi", "rsi" : "intel");
 
                 restore_scratch_registers!();
@@ -205,7 +251,7 @@ macro_rules! handler_with_error {
                       sub rsp, 8 // align stack: 9 regs pushed + 6 error qwords
                       call $0
                       add rsp, 8"
-                      :: "i"($name as extern "C" fn(&ExceptionStackFrame, ErrorCode))
+                      :: "i"($name as extern "C" fn(&mut ExceptionStackFrame, ErrorCode))
                       : "rdi", "rsi" : "intel");
 
                 restore_scratch_registers!();
@@ -220,6 +266,31 @@ macro_rules! handler_with_error {
     })
 }
 
+// Passes the full register state, which the handler may modify before
+// it is restored by iretq
+macro_rules! handler_with_context {
+    ($name:ident) => ({
+        #[naked]
+        extern "C" fn wrapper() -> ! {
+            unsafe {
+                save_all_registers!();
+
+                // 15 regs pushed + 5 frame qwords keep the stack aligned
+                asm!("mov rdi, rsp
+                      call $0"
+                      :: "i"($name as extern "C" fn(&mut InterruptContext))
+                      : "rdi" : "intel", "volatile");
+
+                restore_all_registers!();
+                asm!("iretq" :::: "intel", "volatile");
+
+                ::core::intrinsics::unreachable();
+            }
+        }
+        wrapper
+    })
+}
+
 #[repr(C,packed)]
 struct IdtRef {
     limit: u16,
@@ -266,10 +337,10 @@ lazy_static! {
         idt.set_handler(0, handler!(divide_by_zero_handler));
 
         println!("    Debug");
-        idt.set_handler(1, handler!(debug_exception_handler));
+        idt.set_handler(1, handler_with_context!(debug_exception_handler));
 
         println!("    Breakpoint");
-        idt.set_handler(3, handler!(breakpoint_handler));
+        idt.set_handler(3, handler_with_context!(breakpoint_handler));
 
         println!("    Overflow");
         idt.set_handler(4, handler!(overflow_handler));
@@ -313,6 +384,15 @@ lazy_static! {
         println!("    Virtualization");
         idt.set_handler(20, handler!(virtualization_error_handler));
 
+        println!("    Serial ports");
+        idt.set_handler(PIC1_VECTOR_BASE + 3, handler!(serial_irq3_handler));
+        idt.set_handler(PIC1_VECTOR_BASE + 4, handler!(serial_irq4_handler));
+
+        println!("    Spurious interrupts");
+        idt.set_handler(PIC1_VECTOR_BASE + 7, handler!(spurious_interrupt_handler));
+        idt.set_handler(PIC2_VECTOR_BASE + 7, handler!(spurious_slave_interrupt_handler));
+        idt.set_handler(0xff, handler!(spurious_interrupt_handler));
+
         log.ok();
 
         idt
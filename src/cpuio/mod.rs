@@ -1,46 +1,93 @@
 mod port;
+mod pic;
+mod port_registry;
 mod interrupts;
+#[cfg(test)]
+pub mod fake_bus;
 
-use core::ptr::Unique;
+use core::ops::Deref;
 use spin::Mutex;
-pub use self::port::{Port, UnsafePort};
+pub use self::port::{Port, UnsafePort, PortBus, CpuBus};
+pub use self::pic::Pics;
+pub use self::port_registry::{PortRange, PortClaim, ClaimError};
+pub use self::port_registry::{claim as claim_ports, release as release_ports,
+                              print_claims as print_port_claims};
+pub use self::interrupts::{ExceptionStackFrame, InterruptContext, Registers};
 use memory::FrameAllocator;
 use memory::paging;
+use mmio::{Field, ReadOnly, ReadWrite, WriteOnly};
 use cpuid;
 use cpuid::get_features;
+use control_regs::rflags::{self, RFlags};
+
+// Vectors the 8259 PICs deliver IRQ 0-7 and 8-15 to
+pub const PIC1_VECTOR_BASE: u8 = 0xe0;
+pub const PIC2_VECTOR_BASE: u8 = 0xe8;
 
 pub const APIC_ADDRESS_BASE: usize = 0xfee00000;
-pub const APIC: Mutex<Apic> = Mutex::new(Apic {
-    registers: unsafe { Unique::new(APIC_ADDRESS_BASE as *mut _) },
-});
+static APIC: Mutex<Option<Apic>> = Mutex::new(None);
+
+// The PICs are reprogrammed from IRQ handlers, so their ports stay claimed
+struct ClaimedPics {
+    pics: Pics,
+    _ports: (PortClaim, PortClaim),
+}
 
-const LOCAL_APIC_ID: usize = 0x02;
-const END_OF_INTERRUPT: usize = 0x0b;
-const SPURIOUS_INTERRUPT_VECTOR_REGISTER: usize = 0x0f;
+static PICS: Mutex<Option<ClaimedPics>> = Mutex::new(None);
 
-#[repr(C,packed)]
-struct Register {
-    _unused: [u32; 3],
-    value: u32,
+// Local APIC registers are 32 bit wide and 16 byte aligned
+#[repr(C)]
+struct ApicRegister<R> {
+    register: R,
+    _reserved: [u32; 3],
 }
 
+impl<R> Deref for ApicRegister<R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        &self.register
+    }
+}
+
+#[repr(C)]
+struct ApicRegisters {
+    _reserved0: [u32; 8],                                     // 0x000
+    id: ApicRegister<ReadWrite<u32>>,                         // 0x020
+    version: ApicRegister<ReadOnly<u32>>,                     // 0x030
+    _reserved1: [u32; 16],                                    // 0x040
+    task_priority: ApicRegister<ReadWrite<u32>>,              // 0x080
+    arbitration_priority: ApicRegister<ReadOnly<u32>>,        // 0x090
+    processor_priority: ApicRegister<ReadOnly<u32>>,          // 0x0a0
+    end_of_interrupt: ApicRegister<WriteOnly<u32>>,           // 0x0b0
+    remote_read: ApicRegister<ReadOnly<u32>>,                 // 0x0c0
+    logical_destination: ApicRegister<ReadWrite<u32>>,        // 0x0d0
+    destination_format: ApicRegister<ReadWrite<u32>>,         // 0x0e0
+    spurious_interrupt_vector: ApicRegister<ReadWrite<u32>>,  // 0x0f0
+}
+
+const APIC_ID: Field = Field::new(24, 8);
+const SPURIOUS_VECTOR: Field = Field::new(0, 8);
+const APIC_SOFTWARE_ENABLE: Field = Field::bit(8);
+
 pub struct Apic {
-    registers: Unique<[Register; 16]>,
+    registers: &'static ApicRegisters,
 }
 
 impl Apic {
     fn get_local_apic_id(&self) -> u32 {
-        unsafe { self.registers.get()[LOCAL_APIC_ID].value }
+        self.registers.id.get(APIC_ID) as u32
     }
 
-    fn get_spurious_interrupt_vector(&self) -> u32 {
-        unsafe { self.registers.get()[SPURIOUS_INTERRUPT_VECTOR_REGISTER].value }
+    fn enable(&self, spurious_vector: u8) {
+        self.registers.spurious_interrupt_vector.modify(|value| {
+            let value = SPURIOUS_VECTOR.set(value, spurious_vector as u64);
+            APIC_SOFTWARE_ENABLE.set(value, 1)
+        });
     }
 
-    fn set_spurious_interrupt_vector(&mut self, value: u32) {
-        unsafe {
-            self.registers.get_mut()[SPURIOUS_INTERRUPT_VECTOR_REGISTER].value = value;
-        }
+    fn end_of_interrupt(&self) {
+        self.registers.end_of_interrupt.write(0);
     }
 }
 
@@ -67,32 +114,21 @@ pub fn setup_apic<A>(alloc: &mut A)
 fn disable_8259_pic() {
     let log = log!("  Disabling 8259 PIC");
 
-    unsafe {
-        let pic1_cmd = UnsafePort::<u8>::new(0x20);
-        let pic1_data = UnsafePort::<u8>::new(0x21);
-        let pic2_cmd = UnsafePort::<u8>::new(0xa0);
-        let pic2_data = UnsafePort::<u8>::new(0xa1);
-
-        // ICW1
-        pic1_cmd.write(0x11);
-        pic2_cmd.write(0x11);
-
-        // IRQ base offset
-        pic1_data.write(0xe0);
-        pic2_data.write(0xe8);
-
-        // ICW3
-        pic1_data.write(0x04);
-        pic2_data.write(0x02);
-
-        // ICW4
-        pic1_data.write(0x01);
-        pic2_data.write(0x01);
+    let pic1 = match claim_ports("pic1", PortRange::new(0x20, 2)) {
+        Ok(claim) => claim,
+        Err(_) => return log.fail(),
+    };
+    let pic2 = match claim_ports("pic2", PortRange::new(0xa0, 2)) {
+        Ok(claim) => claim,
+        Err(_) => {
+            release_ports(pic1);
+            return log.fail();
+        }
+    };
 
-        // Interrupt masks
-        pic1_data.write(0xff);
-        pic2_data.write(0xff);
-    }
+    let pics = Pics::from_claims(&pic1, &pic2);
+    pics.remap_and_mask();
+    *PICS.lock() = Some(ClaimedPics { pics: pics, _ports: (pic1, pic2) });
 
     log.ok();
 }
@@ -100,9 +136,12 @@ fn disable_8259_pic() {
 fn map_apic_registers<A>(alloc: &mut A)
     where A: FrameAllocator
 {
-    let log = log!("  Identity-mapping APIC registers");
+    let log = log!("  Mapping APIC registers");
 
-    paging::simple_id_map(paging::Frame::for_address(APIC_ADDRESS_BASE), alloc);
+    let registers = unsafe {
+        paging::map_registers(APIC_ADDRESS_BASE, paging::CacheMode::Uncached, alloc)
+    };
+    *APIC.lock() = Some(Apic { registers: registers });
 
     log.ok();
 }
@@ -110,11 +149,71 @@ fn map_apic_registers<A>(alloc: &mut A)
 fn enable_interrupts() {
     let log = log!("  Enabling interrupts");
 
-    let _apic = APIC;
-    let mut apic = _apic.lock();
-    let siv = apic.get_spurious_interrupt_vector();
-    // Interrupt enable bit, spurious interrupt number
-    apic.set_spurious_interrupt_vector(siv | 1 << 8 | 0xff);
+    APIC.lock().as_ref().expect("APIC registers not mapped").enable(0xff);
 
     log.ok();
-}
\ No newline at end of file
+}
+
+// Legacy IRQs still reach the CPU through the 8259 while LINT0 is in
+// ExtINT mode, so drivers for ISA devices unmask their line here
+pub fn unmask_legacy_irq(irq: u8) {
+    without_interrupts(|| {
+        if let Some(ref claimed) = *PICS.lock() {
+            claimed.pics.unmask(irq);
+        }
+    });
+}
+
+// Signals the end of a legacy IRQ's handler
+pub fn legacy_irq_done(irq: u8) {
+    if let Some(ref claimed) = *PICS.lock() {
+        claimed.pics.end_of_interrupt(irq);
+    }
+}
+
+
+
+pub fn interrupts_enabled() -> bool {
+    RFlags::load().contains(rflags::HARDWARE_INTERRUPTS)
+}
+
+pub fn enable_hardware_interrupts() {
+    unsafe { asm!("sti" :::: "volatile") };
+}
+
+pub fn disable_hardware_interrupts() {
+    unsafe { asm!("cli" :::: "volatile") };
+}
+
+// Runs `f` with interrupts disabled, for code sharing state with an
+// interrupt handler
+pub fn without_interrupts<F, R>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    let enabled = interrupts_enabled();
+    if enabled {
+        disable_hardware_interrupts();
+    }
+
+    let result = f();
+
+    if enabled {
+        enable_hardware_interrupts();
+    }
+    result
+}
+
+
+#[test]
+fn test_apic_register_offsets() {
+    let memory = [0u32; 64];
+    let base = memory.as_ptr() as usize;
+    let registers = unsafe { &*(memory.as_ptr() as *const ApicRegisters) };
+    let offset = |register: &ApicRegister<ReadWrite<u32>>| register as *const _ as usize - base;
+
+    assert_eq!(offset(&registers.id), 0x20);
+    assert_eq!(offset(&registers.task_priority), 0x80);
+    assert_eq!(offset(&registers.spurious_interrupt_vector), 0xf0);
+    assert_eq!(&registers.end_of_interrupt as *const _ as usize - base, 0xb0);
+    assert_eq!(::core::mem::size_of::<ApicRegisters>(), 0x100);
+}
@@ -0,0 +1,153 @@
+use cpuio::port::{CpuBus, PortBus, UnsafePort};
+use cpuio::port_registry::PortClaim;
+use cpuio::{PIC1_VECTOR_BASE, PIC2_VECTOR_BASE};
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+const END_OF_INTERRUPT: u8 = 0x20;
+// IRQs on the second PIC are cascaded through IRQ 2
+const CASCADE_IRQ: u8 = 2;
+
+// The two cascaded 8259 PICs
+pub struct Pics<B: PortBus = CpuBus> {
+    pic1_command: UnsafePort<u8, B>,
+    pic1_data: UnsafePort<u8, B>,
+    pic2_command: UnsafePort<u8, B>,
+    pic2_data: UnsafePort<u8, B>,
+}
+
+impl Pics<CpuBus> {
+    // `pic1` and `pic2` must be the claims of 0x20-0x21 and 0xa0-0xa1
+    pub fn from_claims(pic1: &PortClaim, pic2: &PortClaim) -> Pics<CpuBus> {
+        Pics {
+            pic1_command: pic1.unsafe_port(0),
+            pic1_data: pic1.unsafe_port(1),
+            pic2_command: pic2.unsafe_port(0),
+            pic2_data: pic2.unsafe_port(1),
+        }
+    }
+}
+
+impl<B: PortBus + Clone> Pics<B> {
+    pub fn on_bus(bus: B) -> Pics<B> {
+        unsafe {
+            Pics {
+                pic1_command: UnsafePort::on_bus(PIC1_COMMAND, bus.clone()),
+                pic1_data: UnsafePort::on_bus(PIC1_DATA, bus.clone()),
+                pic2_command: UnsafePort::on_bus(PIC2_COMMAND, bus.clone()),
+                pic2_data: UnsafePort::on_bus(PIC2_DATA, bus),
+            }
+        }
+    }
+}
+
+impl<B: PortBus> Pics<B> {
+    // Moves IRQ 0-15 to PIC1_VECTOR_BASE.. so they don't collide with
+    // exceptions, and masks all of them
+    pub fn remap_and_mask(&self) {
+        let pic1_cmd = &self.pic1_command;
+        let pic1_data = &self.pic1_data;
+        let pic2_cmd = &self.pic2_command;
+        let pic2_data = &self.pic2_data;
+
+        unsafe {
+            // ICW1
+            pic1_cmd.write(0x11);
+            pic2_cmd.write(0x11);
+
+            // IRQ base offset
+            pic1_data.write(PIC1_VECTOR_BASE);
+            pic2_data.write(PIC2_VECTOR_BASE);
+
+            // ICW3
+            pic1_data.write(0x04);
+            pic2_data.write(0x02);
+
+            // ICW4
+            pic1_data.write(0x01);
+            pic2_data.write(0x01);
+
+            // Interrupt masks
+            pic1_data.write(0xff);
+            pic2_data.write(0xff);
+        }
+    }
+
+    pub fn unmask(&self, irq: u8) {
+        let (port, bit) = if irq < 8 {
+            (&self.pic1_data, irq)
+        } else {
+            (&self.pic2_data, irq - 8)
+        };
+
+        unsafe {
+            port.write(port.read() & !(1 << bit));
+
+            if irq >= 8 {
+                let pic1_data = &self.pic1_data;
+                pic1_data.write(pic1_data.read() & !(1 << CASCADE_IRQ));
+            }
+        }
+    }
+
+    pub fn end_of_interrupt(&self, irq: u8) {
+        unsafe {
+            if irq >= 8 {
+                self.pic2_command.write(END_OF_INTERRUPT);
+            }
+            self.pic1_command.write(END_OF_INTERRUPT);
+        }
+    }
+}
+
+
+#[cfg(test)]
+use cpuio::fake_bus::{Access, FakeBus};
+
+#[test]
+fn test_remap_and_mask() {
+    let bus = FakeBus::new();
+    Pics::on_bus(&bus).remap_and_mask();
+
+    assert_eq!(bus.writes(), vec![(0x20, 0x11), (0xa0, 0x11),
+                                  (0x21, 0xe0), (0xa1, 0xe8),
+                                  (0x21, 0x04), (0xa1, 0x02),
+                                  (0x21, 0x01), (0xa1, 0x01),
+                                  (0x21, 0xff), (0xa1, 0xff)]);
+}
+
+#[test]
+fn test_unmask() {
+    let bus = FakeBus::new();
+    bus.script(0x21, &[0xff]);
+    Pics::on_bus(&bus).unmask(4);
+
+    assert_eq!(bus.accesses(), vec![Access::Read(0x21, 0xff), Access::Write(0x21, 0xef)]);
+}
+
+#[test]
+fn test_unmask_cascaded() {
+    let bus = FakeBus::new();
+    bus.script(0xa1, &[0xff]);
+    bus.script(0x21, &[0xef]);
+    Pics::on_bus(&bus).unmask(12);
+
+    assert_eq!(bus.accesses(), vec![Access::Read(0xa1, 0xff), Access::Write(0xa1, 0xef),
+                                    Access::Read(0x21, 0xef), Access::Write(0x21, 0xeb)]);
+}
+
+#[test]
+fn test_end_of_interrupt() {
+    let bus = FakeBus::new();
+    let pics = Pics::on_bus(&bus);
+
+    pics.end_of_interrupt(4);
+    assert_eq!(bus.writes(), vec![(0x20, 0x20)]);
+
+    bus.clear();
+    pics.end_of_interrupt(12);
+    assert_eq!(bus.writes(), vec![(0xa0, 0x20), (0x20, 0x20)]);
+}
@@ -1,11 +1,23 @@
 use core::marker::PhantomData;
 
-pub trait CpuIoValue {
+pub trait CpuIoValue: Copy {
     unsafe fn write(port: u16, value: Self);
     unsafe fn read(port: u16) -> Self;
+
+    // Conversions for buses that don't access the hardware
+    fn to_u32(self) -> u32;
+    fn from_u32(value: u32) -> Self;
 }
 
 impl CpuIoValue for u8 {
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    fn from_u32(value: u32) -> u8 {
+        value as u8
+    }
+
     unsafe fn write(port: u16, value: u8) {
         asm!("outb %al, %dx"
             :
@@ -28,6 +40,14 @@ impl CpuIoValue for u8 {
 }
 
 impl CpuIoValue for u16 {
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    fn from_u32(value: u32) -> u16 {
+        value as u16
+    }
+
     unsafe fn write(port: u16, value: u16) {
         asm!("outw %ax, %dx"
             :
@@ -50,6 +70,14 @@ impl CpuIoValue for u16 {
 }
 
 impl CpuIoValue for u32 {
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    fn from_u32(value: u32) -> u32 {
+        value as u32
+    }
+
     unsafe fn write(port: u16, value: u32) {
         asm!("outl %eax, %dx"
             : 
@@ -71,8 +99,41 @@ impl CpuIoValue for u32 {
     }
 }
 
-pub struct Port<T: CpuIoValue> {
+// Carries out port reads and writes. Drivers are generic over the bus so
+// their register accesses can be checked against a fake one in tests.
+pub trait PortBus {
+    unsafe fn read<T: CpuIoValue>(&self, port: u16) -> T;
+    unsafe fn write<T: CpuIoValue>(&self, port: u16, value: T);
+}
+
+// The real bus, accessed with `in` and `out`
+#[derive(Debug, Clone, Copy)]
+pub struct CpuBus;
+
+impl PortBus for CpuBus {
+    unsafe fn read<T: CpuIoValue>(&self, port: u16) -> T {
+        T::read(port)
+    }
+
+    unsafe fn write<T: CpuIoValue>(&self, port: u16, value: T) {
+        T::write(port, value)
+    }
+}
+
+impl<'a, B: PortBus> PortBus for &'a B {
+    unsafe fn read<T: CpuIoValue>(&self, port: u16) -> T {
+        (**self).read(port)
+    }
+
+    unsafe fn write<T: CpuIoValue>(&self, port: u16, value: T) {
+        (**self).write(port, value)
+    }
+}
+
+
+pub struct Port<T: CpuIoValue, B: PortBus = CpuBus> {
     port: u16,
+    bus: B,
     _phantomdata: PhantomData<T>,
 }
 
@@ -80,22 +141,34 @@ impl<T: CpuIoValue> Port<T> {
     pub const unsafe fn new(port: u16) -> Port<T> {
         Port {
             port: port,
+            bus: CpuBus,
+            _phantomdata: PhantomData,
+        }
+    }
+}
+
+impl<T: CpuIoValue, B: PortBus> Port<T, B> {
+    pub unsafe fn on_bus(port: u16, bus: B) -> Port<T, B> {
+        Port {
+            port: port,
+            bus: bus,
             _phantomdata: PhantomData,
         }
     }
 
     pub fn read(&self) -> T {
-        unsafe { T::read(self.port) }
+        unsafe { self.bus.read(self.port) }
     }
 
     pub fn write(&self, value: T) {
-        unsafe { T::write(self.port, value) }
+        unsafe { self.bus.write(self.port, value) }
     }
 }
 
 
-pub struct UnsafePort<T: CpuIoValue> {
+pub struct UnsafePort<T: CpuIoValue, B: PortBus = CpuBus> {
     port: u16,
+    bus: B,
     _phantomdata: PhantomData<T>,
 }
 
@@ -103,15 +176,26 @@ impl<T: CpuIoValue> UnsafePort<T> {
     pub const unsafe fn new(port: u16) -> UnsafePort<T> {
         UnsafePort {
             port: port,
+            bus: CpuBus,
+            _phantomdata: PhantomData,
+        }
+    }
+}
+
+impl<T: CpuIoValue, B: PortBus> UnsafePort<T, B> {
+    pub unsafe fn on_bus(port: u16, bus: B) -> UnsafePort<T, B> {
+        UnsafePort {
+            port: port,
+            bus: bus,
             _phantomdata: PhantomData,
         }
     }
 
     pub unsafe fn read(&self) -> T {
-        T::read(self.port)
+        self.bus.read(self.port)
     }
 
     pub unsafe fn write(&self, value: T) {
-        T::write(self.port, value)
+        self.bus.write(self.port, value)
     }
 }
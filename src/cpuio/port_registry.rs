@@ -0,0 +1,204 @@
+use core::mem;
+use spin::Mutex;
+use cpuio::port::{CpuIoValue, Port, UnsafePort};
+
+const MAX_CLAIMS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub base: u16,
+    pub len: u16,
+}
+
+impl PortRange {
+    pub const fn new(base: u16, len: u16) -> PortRange {
+        PortRange { base: base, len: len }
+    }
+
+    pub fn last(&self) -> u16 {
+        self.base + (self.len - 1)
+    }
+
+    // True if `size` bytes at `offset` lie inside the range
+    fn contains(&self, offset: u16, size: u16) -> bool {
+        offset < self.len && size <= self.len - offset
+    }
+
+    fn overlaps(&self, other: &PortRange) -> bool {
+        self.base <= other.last() && other.base <= self.last()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimError {
+    EmptyRange,
+    // Name and range of the claim in the way
+    Conflict(&'static str, PortRange),
+    TooManyClaims,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Claim {
+    pub name: &'static str,
+    pub range: PortRange,
+}
+
+// Ownership of a claimed range, the only safe way to get ports in it.
+// Dropping it keeps the range claimed, hand it to release() instead.
+#[derive(Debug)]
+pub struct PortClaim {
+    claim: Claim,
+}
+
+impl PortClaim {
+    pub fn name(&self) -> &'static str {
+        self.claim.name
+    }
+
+    pub fn range(&self) -> PortRange {
+        self.claim.range
+    }
+
+    // The port at `offset` into the range, which must fit a `T`
+    pub fn port<T: CpuIoValue>(&self, offset: u16) -> Port<T> {
+        unsafe { Port::new(self.checked_port::<T>(offset)) }
+    }
+
+    // Like port(), for ports whose accesses have side effects
+    pub fn unsafe_port<T: CpuIoValue>(&self, offset: u16) -> UnsafePort<T> {
+        unsafe { UnsafePort::new(self.checked_port::<T>(offset)) }
+    }
+
+    fn checked_port<T: CpuIoValue>(&self, offset: u16) -> u16 {
+        let range = self.claim.range;
+        assert!(range.contains(offset, mem::size_of::<T>() as u16),
+                "Port offset {:#x} outside of {} ({:#x}-{:#x})",
+                offset, self.claim.name, range.base, range.last());
+
+        range.base + offset
+    }
+}
+
+pub struct Registry {
+    claims: [Option<Claim>; MAX_CLAIMS],
+}
+
+impl Registry {
+    pub const fn new() -> Registry {
+        Registry { claims: [None; MAX_CLAIMS] }
+    }
+
+    pub fn claim(&mut self, name: &'static str, range: PortRange) -> Result<PortClaim, ClaimError> {
+        if range.len == 0 || range.base.checked_add(range.len - 1).is_none() {
+            return Err(ClaimError::EmptyRange);
+        }
+
+        if let Some(other) = self.claims.iter().filter_map(|c| *c).find(|c| c.range.overlaps(&range)) {
+            return Err(ClaimError::Conflict(other.name, other.range));
+        }
+
+        let claim = Claim { name: name, range: range };
+        match self.claims.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(claim),
+            None => return Err(ClaimError::TooManyClaims),
+        }
+
+        Ok(PortClaim { claim: claim })
+    }
+
+    pub fn release(&mut self, claim: PortClaim) {
+        for slot in self.claims.iter_mut() {
+            if *slot == Some(claim.claim) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn claims(&self) -> [Option<Claim>; MAX_CLAIMS] {
+        self.claims
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+pub fn claim(name: &'static str, range: PortRange) -> Result<PortClaim, ClaimError> {
+    REGISTRY.lock().claim(name, range)
+}
+
+pub fn release(claim: PortClaim) {
+    REGISTRY.lock().release(claim)
+}
+
+pub fn print_claims() {
+    let claims = REGISTRY.lock().claims();
+
+    for claim in claims.iter().filter_map(|c| *c) {
+        println!("{}{:#06x}-{:#06x}  {}{}",
+            LIGHT_GRAY, claim.range.base, claim.range.last(), CYAN, claim.name);
+    }
+}
+
+
+#[test]
+fn test_claim_conflicts() {
+    let mut registry = Registry::new();
+    registry.claim("com1", PortRange::new(0x3f8, 8)).unwrap();
+
+    assert_eq!(registry.claim("other", PortRange::new(0x3ff, 1)).unwrap_err(),
+               ClaimError::Conflict("com1", PortRange::new(0x3f8, 8)));
+    assert_eq!(registry.claim("other", PortRange::new(0x3f0, 9)).unwrap_err(),
+               ClaimError::Conflict("com1", PortRange::new(0x3f8, 8)));
+    assert!(registry.claim("before", PortRange::new(0x3f0, 8)).is_ok());
+    assert!(registry.claim("after", PortRange::new(0x400, 1)).is_ok());
+}
+
+#[test]
+fn test_claim_invalid() {
+    let mut registry = Registry::new();
+
+    assert_eq!(registry.claim("empty", PortRange::new(0x60, 0)).unwrap_err(),
+               ClaimError::EmptyRange);
+    assert_eq!(registry.claim("wraps", PortRange::new(0xfffe, 3)).unwrap_err(),
+               ClaimError::EmptyRange);
+    assert!(registry.claim("end", PortRange::new(0xfffe, 2)).is_ok());
+}
+
+#[test]
+fn test_release() {
+    let mut registry = Registry::new();
+    let claim = registry.claim("keyboard", PortRange::new(0x60, 1)).unwrap();
+    assert_eq!(registry.claims().iter().filter(|c| c.is_some()).count(), 1);
+
+    registry.release(claim);
+    assert!(registry.claims().iter().all(|c| c.is_none()));
+    assert!(registry.claim("keyboard", PortRange::new(0x60, 1)).is_ok());
+}
+
+#[test]
+fn test_too_many_claims() {
+    let mut registry = Registry::new();
+    for port in 0..MAX_CLAIMS as u16 {
+        registry.claim("port", PortRange::new(port, 1)).unwrap();
+    }
+
+    assert_eq!(registry.claim("port", PortRange::new(0x100, 1)).unwrap_err(),
+               ClaimError::TooManyClaims);
+}
+
+#[test]
+fn test_port_offsets() {
+    let mut registry = Registry::new();
+    let claim = registry.claim("exit", PortRange::new(0xf4, 4)).unwrap();
+
+    assert_eq!(claim.checked_port::<u8>(3), 0xf7);
+    assert_eq!(claim.checked_port::<u32>(0), 0xf4);
+}
+
+#[test]
+#[should_panic]
+fn test_port_outside_claim() {
+    let mut registry = Registry::new();
+    let claim = registry.claim("exit", PortRange::new(0xf4, 4)).unwrap();
+
+    claim.checked_port::<u16>(3);
+}
@@ -0,0 +1,267 @@
+use spin::Mutex;
+
+fn set_dr0(value: u64) {
+    unsafe {
+        asm!("mov dr0, $0" :: "r"(value) :: "intel", "volatile");
+    }
+}
+
+fn set_dr1(value: u64) {
+    unsafe {
+        asm!("mov dr1, $0" :: "r"(value) :: "intel", "volatile");
+    }
+}
+
+fn set_dr2(value: u64) {
+    unsafe {
+        asm!("mov dr2, $0" :: "r"(value) :: "intel", "volatile");
+    }
+}
+
+fn set_dr3(value: u64) {
+    unsafe {
+        asm!("mov dr3, $0" :: "r"(value) :: "intel", "volatile");
+    }
+}
+
+fn get_dr6() -> u64 {
+    let reg: u64;
+    unsafe {
+        asm!("mov $0, dr6" : "=r"(reg) ::: "intel", "volatile");
+    }
+
+    reg
+}
+
+fn set_dr6(value: u64) {
+    unsafe {
+        asm!("mov dr6, $0" :: "r"(value) :: "intel", "volatile");
+    }
+}
+
+fn get_dr7() -> u64 {
+    let reg: u64;
+    unsafe {
+        asm!("mov $0, dr7" : "=r"(reg) ::: "intel", "volatile");
+    }
+
+    reg
+}
+
+fn set_dr7(value: u64) {
+    unsafe {
+        asm!("mov dr7, $0" :: "r"(value) :: "intel", "volatile");
+    }
+}
+
+fn set_address(slot: usize, address: u64) {
+    match slot {
+        0 => set_dr0(address),
+        1 => set_dr1(address),
+        2 => set_dr2(address),
+        3 => set_dr3(address),
+        _ => panic!("Invalid debug register slot {}", slot),
+    }
+}
+
+pub const SLOT_COUNT: usize = 4;
+
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; SLOT_COUNT]> = Mutex::new([None; SLOT_COUNT]);
+
+
+
+pub mod dr6 {
+    bitflags! {
+        // Debug status register
+        flags Dr6: u64 {
+            // Breakpoint conditions met, set even if the slot is disabled
+            const BREAKPOINT_0 = 1 << 0,
+            const BREAKPOINT_1 = 1 << 1,
+            const BREAKPOINT_2 = 1 << 2,
+            const BREAKPOINT_3 = 1 << 3,
+            // The next instruction accesses a debug register
+            const DEBUG_REGISTER_ACCESS = 1 << 13,
+            // Raised by RFlags::SINGLE_STEP
+            const SINGLE_STEP = 1 << 14,
+            // Raised by a task switch
+            const TASK_SWITCH = 1 << 15,
+        }
+    }
+
+    impl Dr6 {
+        pub fn load() -> Dr6 {
+            Dr6::from_bits_truncate(super::get_dr6())
+        }
+
+        // The processor never clears DR6 itself
+        pub fn clear() {
+            super::set_dr6(0);
+        }
+
+        pub fn triggered(&self, slot: usize) -> bool {
+            self.bits & (1 << slot) != 0
+        }
+    }
+}
+
+pub mod dr7 {
+    bitflags! {
+        // Debug control register
+        flags Dr7: u64 {
+            const LOCAL_0 = 1 << 0,
+            const GLOBAL_0 = 1 << 1,
+            const LOCAL_1 = 1 << 2,
+            const GLOBAL_1 = 1 << 3,
+            const LOCAL_2 = 1 << 4,
+            const GLOBAL_2 = 1 << 5,
+            const LOCAL_3 = 1 << 6,
+            const GLOBAL_3 = 1 << 7,
+            // Report the exact instruction causing a data breakpoint
+            const LOCAL_EXACT = 1 << 8,
+            const GLOBAL_EXACT = 1 << 9,
+            // Always set
+            const RESERVED_1 = 1 << 10,
+            // Raise #DB before any access to the debug registers
+            const GENERAL_DETECT = 1 << 13,
+            // Condition and length fields of each slot
+            const CONDITIONS = 0xffff << 16,
+        }
+    }
+
+    impl Dr7 {
+        pub fn load() -> Dr7 {
+            Dr7::from_bits_truncate(super::get_dr7())
+        }
+
+        pub fn store(&self) {
+            super::set_dr7(self.bits);
+        }
+    }
+}
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakCondition {
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+impl BreakCondition {
+    fn bits(&self) -> u64 {
+        match *self {
+            BreakCondition::Execute => 0b00,
+            BreakCondition::Write => 0b01,
+            BreakCondition::ReadWrite => 0b11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakSize {
+    Byte,
+    Word,
+    DoubleWord,
+    QuadWord,
+}
+
+impl BreakSize {
+    pub fn bytes(&self) -> u64 {
+        match *self {
+            BreakSize::Byte => 1,
+            BreakSize::Word => 2,
+            BreakSize::DoubleWord => 4,
+            BreakSize::QuadWord => 8,
+        }
+    }
+
+    fn bits(&self) -> u64 {
+        match *self {
+            BreakSize::Byte => 0b00,
+            BreakSize::Word => 0b01,
+            BreakSize::DoubleWord => 0b11,
+            BreakSize::QuadWord => 0b10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Breakpoint {
+    pub name: &'static str,
+    pub address: u64,
+    pub condition: BreakCondition,
+    pub size: BreakSize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointError {
+    NoFreeSlot,
+    // Watched addresses must be aligned to their size
+    Misaligned,
+    // Instruction breakpoints must have a size of one byte
+    InvalidSize,
+    NotInstalled,
+}
+
+// Installs a hardware breakpoint in the first free slot and returns the slot
+pub fn install(breakpoint: Breakpoint) -> Result<usize, BreakpointError> {
+    if breakpoint.condition == BreakCondition::Execute && breakpoint.size != BreakSize::Byte {
+        return Err(BreakpointError::InvalidSize);
+    }
+    if breakpoint.address % breakpoint.size.bytes() != 0 {
+        return Err(BreakpointError::Misaligned);
+    }
+
+    let mut breakpoints = BREAKPOINTS.lock();
+    let slot = match breakpoints.iter().position(|bp| bp.is_none()) {
+        Some(slot) => slot,
+        None => return Err(BreakpointError::NoFreeSlot),
+    };
+
+    set_address(slot, breakpoint.address);
+
+    let mut dr7 = dr7::Dr7::load();
+    let shift = 16 + 4 * slot;
+    let bits = dr7.bits() & !(0b1111 << shift) |
+               (breakpoint.condition.bits() | breakpoint.size.bits() << 2) << shift |
+               1 << (2 * slot + 1);
+    dr7 = dr7::Dr7::from_bits_truncate(bits);
+    dr7.insert(dr7::GLOBAL_EXACT | dr7::RESERVED_1);
+    dr7.store();
+
+    breakpoints[slot] = Some(breakpoint);
+    Ok(slot)
+}
+
+pub fn remove(slot: usize) -> Result<Breakpoint, BreakpointError> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    let breakpoint = match breakpoints.get(slot).and_then(|bp| *bp) {
+        Some(breakpoint) => breakpoint,
+        None => return Err(BreakpointError::NotInstalled),
+    };
+
+    let shift = 16 + 4 * slot;
+    let bits = dr7::Dr7::load().bits() & !(0b1111 << shift) & !(0b11 << (2 * slot));
+    dr7::Dr7::from_bits_truncate(bits).store();
+    set_address(slot, 0);
+
+    breakpoints[slot] = None;
+    Ok(breakpoint)
+}
+
+pub fn get(slot: usize) -> Option<Breakpoint> {
+    BREAKPOINTS.lock().get(slot).and_then(|bp| *bp)
+}
+
+// Finds the slot of an installed breakpoint by address and condition
+pub fn find(address: u64, condition: BreakCondition) -> Option<usize> {
+    BREAKPOINTS.lock().iter().position(|bp| match *bp {
+        Some(bp) => bp.address == address && bp.condition == condition,
+        None => false,
+    })
+}
+
+pub fn list() -> [Option<Breakpoint>; SLOT_COUNT] {
+    *BREAKPOINTS.lock()
+}
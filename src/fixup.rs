@@ -0,0 +1,155 @@
+use core::mem;
+use core::slice;
+use cpuio::ExceptionStackFrame;
+use control_regs::cr4::{self, CR4};
+
+// User memory is the lower canonical half
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+// Each entry maps the address of an instruction that may fault to the
+// address execution continues at instead. The linker script collects
+// the entries emitted by the asm blocks below into the `__ex_table` section.
+#[repr(C)]
+struct FixupEntry {
+    fault: u64,
+    recovery: u64,
+}
+
+extern "C" {
+    static __ex_table_start: FixupEntry;
+    static __ex_table_end: FixupEntry;
+}
+
+// The access faulted after `copied` bytes were transferred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub copied: usize,
+}
+
+fn table() -> &'static [FixupEntry] {
+    unsafe {
+        let start = &__ex_table_start as *const FixupEntry;
+        let end = &__ex_table_end as *const FixupEntry;
+        slice::from_raw_parts(start, (end as usize - start as usize) / mem::size_of::<FixupEntry>())
+    }
+}
+
+pub fn search(instruction_pointer: u64) -> Option<u64> {
+    table().iter()
+           .find(|entry| entry.fault == instruction_pointer)
+           .map(|entry| entry.recovery)
+}
+
+// Called from the #PF and #GP handlers. Returns true if the faulting
+// instruction has a recovery address, which the frame now points to.
+pub fn handle_fault(stack_frame: &mut ExceptionStackFrame) -> bool {
+    match search(stack_frame.instruction_pointer) {
+        Some(recovery) => {
+            stack_frame.instruction_pointer = recovery;
+            true
+        }
+        None => false,
+    }
+}
+
+
+
+// Copies `len` bytes, stopping at the first fault on either side. The
+// remaining count is left in rcx by `rep movsb`, so the recovery address
+// is simply the next instruction.
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Fault> {
+    let remaining: usize;
+    let (_dst, _src): (usize, usize);
+
+    asm!("1: rep movsb
+          2:
+          .pushsection __ex_table, \"a\"
+          .balign 8
+          .quad 1b, 2b
+          .popsection"
+          : "={rcx}"(remaining), "={rdi}"(_dst), "={rsi}"(_src)
+          : "{rcx}"(len), "{rdi}"(dst), "{rsi}"(src)
+          : "memory"
+          : "volatile");
+
+    if remaining == 0 {
+        Ok(())
+    } else {
+        Err(Fault { copied: len - remaining })
+    }
+}
+
+// Types that are valid for any bit pattern, so they can be read from
+// arbitrary memory
+pub unsafe trait Plain: Copy {}
+
+unsafe impl Plain for u8 {}
+unsafe impl Plain for u16 {}
+unsafe impl Plain for u32 {}
+unsafe impl Plain for u64 {}
+unsafe impl Plain for usize {}
+unsafe impl Plain for i8 {}
+unsafe impl Plain for i16 {}
+unsafe impl Plain for i32 {}
+unsafe impl Plain for i64 {}
+unsafe impl Plain for isize {}
+
+// Reads a `T` from an address that may not be mapped
+pub fn try_read<T: Plain>(addr: u64) -> Result<T, Fault> {
+    unsafe {
+        let mut value: T = mem::uninitialized();
+        copy(&mut value as *mut T as *mut u8, addr as *const u8, mem::size_of::<T>())
+            .map(|_| value)
+    }
+}
+
+// Writes a `T` to an address that may not be mapped. Unsafe because the
+// target may be any memory that is mapped.
+pub unsafe fn try_write<T: Copy>(addr: u64, value: T) -> Result<(), Fault> {
+    copy(addr as *mut u8, &value as *const T as *const u8, mem::size_of::<T>())
+}
+
+fn is_user_range(addr: u64, len: usize) -> bool {
+    addr.checked_add(len as u64).map_or(false, |end| end <= USER_END)
+}
+
+// Like copy(), with supervisor mode access prevention lifted if it's on
+unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Fault> {
+    let smap = CR4::load().contains(cr4::SUPERVISOR_ACCESS_PREVENTION);
+    if smap {
+        asm!("stac" :::: "volatile");
+    }
+    let result = copy(dst, src, len);
+    if smap {
+        asm!("clac" :::: "volatile");
+    }
+    result
+}
+
+// Fills `dst` from the untrusted address `src`. Ranges reaching outside of
+// user memory fail without copying anything.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Fault> {
+    if !is_user_range(src, dst.len()) {
+        return Err(Fault { copied: 0 });
+    }
+    unsafe { copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) }
+}
+
+// Copies `src` to the untrusted address `dst`, which must lie in user
+// memory like for copy_from_user()
+pub unsafe fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Fault> {
+    if !is_user_range(dst, src.len()) {
+        return Err(Fault { copied: 0 });
+    }
+    copy_user(dst as *mut u8, src.as_ptr(), src.len())
+}
+
+
+#[test]
+fn test_is_user_range() {
+    assert!(is_user_range(0x1000, 0x1000));
+    assert!(is_user_range(USER_END - 8, 8));
+    assert!(!is_user_range(USER_END - 8, 9));
+    assert!(!is_user_range(0xffff_ffff_8000_0000, 1));
+    assert!(!is_user_range(!0, 2));
+}
@@ -0,0 +1,207 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use cpuid;
+use control_regs::cr0::{self, CR0};
+use control_regs::cr4::{self, CR4};
+use memory::{FrameAllocator, GlobalFrameAllocator, PAGE_SIZE};
+use memory::paging;
+
+// XCR0 state components
+const X87_STATE: u64 = 1 << 0;
+const SSE_STATE: u64 = 1 << 1;
+const AVX_STATE: u64 = 1 << 2;
+
+// Offsets into the legacy (FXSAVE) region of a save area
+const FPU_CONTROL_WORD_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+// Power-on defaults: all exceptions masked, round to nearest
+const DEFAULT_FPU_CONTROL_WORD: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+// Save area addresses of the context that should run (CURRENT) and of
+// the context whose state is loaded into the FPU (OWNER). 0 means none.
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+
+
+// FPU/SSE/AVX register state of one execution context. The state is only
+// saved and restored when another context actually uses the FPU.
+pub struct FpuContext {
+    // Page aligned, which satisfies the 64 byte alignment XSAVE needs
+    state: &'static mut [u8; PAGE_SIZE],
+}
+
+impl FpuContext {
+    pub fn new<A>(alloc: &mut A) -> FpuContext
+        where A: FrameAllocator
+    {
+        let state = paging::alloc_any(alloc);
+        for byte in state.iter_mut() {
+            *byte = 0;
+        }
+
+        // A zeroed XSAVE header marks every component as being in its
+        // initial state, only the legacy control registers need values
+        unsafe {
+            *(&mut state[FPU_CONTROL_WORD_OFFSET] as *mut _ as *mut u16) = DEFAULT_FPU_CONTROL_WORD;
+            *(&mut state[MXCSR_OFFSET] as *mut _ as *mut u32) = DEFAULT_MXCSR;
+        }
+
+        FpuContext { state: state }
+    }
+
+    fn address(&self) -> usize {
+        self.state.as_ptr() as usize
+    }
+
+    // Makes this the running context. The state is swapped in by the #NM
+    // handler the next time an FPU instruction is executed.
+    pub fn activate(&self) {
+        CURRENT.store(self.address(), Ordering::SeqCst);
+
+        if OWNER.load(Ordering::SeqCst) != self.address() {
+            set_task_switched();
+        } else {
+            clear_task_switched();
+        }
+    }
+}
+
+impl Drop for FpuContext {
+    fn drop(&mut self) {
+        let address = self.address();
+
+        OWNER.compare_and_swap(address, 0, Ordering::SeqCst);
+        if CURRENT.compare_and_swap(address, 0, Ordering::SeqCst) == address {
+            set_task_switched();
+        }
+
+        unsafe { paging::free_any(self.state, &mut GlobalFrameAllocator) };
+    }
+}
+
+// Leaves the current context, any further FPU use raises #NM
+pub fn deactivate() {
+    CURRENT.store(0, Ordering::SeqCst);
+    set_task_switched();
+}
+
+
+
+pub fn init() {
+    let features = cpuid::get_features();
+    if !features.contains(cpuid::FPU_PRESENT) || !features.contains(cpuid::FXSAVE_INSTRUCTIONS) {
+        panic!("No FPU with FXSAVE support found");
+    }
+
+    let log = log!("Setting up FPU state switching");
+
+    let mut cr0 = CR0::load();
+    cr0.remove(cr0::FPU_MISSING);
+    cr0.insert(cr0::MONITOR_COPROCESSOR | cr0::NATIVE_FPU_ERRORS);
+    cr0.store();
+
+    (CR4::load() | cr4::SSE_ENABLED | cr4::UNMASKED_SIMD_EXCEPTIONS).store();
+
+    // XSAVE needs to be turned on in CR4 by the feature enablement stage
+    if CR4::load().contains(cr4::XSAVE) {
+        init_xsave();
+    }
+
+    unsafe {
+        asm!("fninit" :::: "intel", "volatile");
+    }
+    deactivate();
+
+    log.ok();
+}
+
+fn init_xsave() {
+    let info = cpuid::get_xsave_info().expect("XSAVE enabled but not supported");
+
+    let mut components = X87_STATE | SSE_STATE;
+    if cpuid::get_features().contains(cpuid::ADV_VECTOR_EXTENSIONS) {
+        components |= AVX_STATE;
+    }
+    components &= info.supported_components;
+
+    set_xcr0(components);
+
+    let size = cpuid::get_xsave_info().unwrap().enabled_size as usize;
+    if size > PAGE_SIZE {
+        println!("{}  XSAVE area of {} bytes too large, falling back to FXSAVE",
+            LIGHT_GRAY, size);
+        set_xcr0(X87_STATE | SSE_STATE);
+        return;
+    }
+
+    USE_XSAVE.store(true, Ordering::SeqCst);
+    println!("{}  Using XSAVE for components {:#x} ({} bytes)", LIGHT_GRAY, components, size);
+}
+
+
+
+// Called from the #NM handler. Returns false if no context is active, i.e.
+// the kernel itself used the FPU.
+pub fn handle_device_not_available() -> bool {
+    let current = CURRENT.load(Ordering::SeqCst);
+    if current == 0 {
+        return false;
+    }
+
+    clear_task_switched();
+
+    let owner = OWNER.load(Ordering::SeqCst);
+    if owner != current {
+        unsafe {
+            if owner != 0 {
+                save_state(owner);
+            }
+            restore_state(current);
+        }
+        OWNER.store(current, Ordering::SeqCst);
+    }
+
+    true
+}
+
+unsafe fn save_state(area: usize) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        // Save every component enabled in XCR0
+        asm!("xsave64 [$0]"
+            :: "r"(area), "{eax}"(0xffff_ffffu32), "{edx}"(0xffff_ffffu32)
+            : "memory" : "intel", "volatile");
+    } else {
+        asm!("fxsave64 [$0]" :: "r"(area) : "memory" : "intel", "volatile");
+    }
+}
+
+unsafe fn restore_state(area: usize) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        asm!("xrstor64 [$0]"
+            :: "r"(area), "{eax}"(0xffff_ffffu32), "{edx}"(0xffff_ffffu32)
+            : "memory" : "intel", "volatile");
+    } else {
+        asm!("fxrstor64 [$0]" :: "r"(area) : "memory" : "intel", "volatile");
+    }
+}
+
+fn set_task_switched() {
+    (CR0::load() | cr0::FPU_STATE_SWITCHED).store();
+}
+
+fn clear_task_switched() {
+    unsafe {
+        asm!("clts" :::: "intel", "volatile");
+    }
+}
+
+fn set_xcr0(value: u64) {
+    unsafe {
+        asm!("xsetbv"
+            :: "{ecx}"(0), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+            :: "intel", "volatile");
+    }
+}
@@ -0,0 +1,505 @@
+mod packet;
+
+use core::cmp;
+use spin::Mutex;
+use cpuio::{InterruptContext, ExceptionStackFrame, Registers, PortClaim};
+use control_regs::rflags;
+use debug_regs::{self, Breakpoint, BreakCondition, BreakSize};
+use fixup;
+use serial::{self, SerialPort};
+use self::packet::*;
+
+const BAUD_RATE: u32 = 115200;
+
+// Signal numbers reported to gdb
+pub const SIGTRAP: u8 = 5;
+pub const SIGABRT: u8 = 6;
+
+// Errno values for error replies
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+const ENOSPC: u8 = 28;
+
+const MAX_SOFTWARE_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+
+// Number of registers in gdb's x86_64 `g` packet we know about: 16 general
+// purpose registers, rip, eflags and the 6 segment registers
+const REGISTER_COUNT: usize = 24;
+
+#[derive(Clone, Copy)]
+struct SoftwareBreakpoint {
+    address: u64,
+    original: u8,
+}
+
+struct GdbStub {
+    connection: Connection,
+    // Owned for as long as the stub runs
+    _ports: PortClaim,
+    // Set once gdb talked to us, until it detaches
+    attached: bool,
+    breakpoints: [Option<SoftwareBreakpoint>; MAX_SOFTWARE_BREAKPOINTS],
+}
+
+static STUB: Mutex<Option<GdbStub>> = Mutex::new(None);
+
+enum Action {
+    Stay,
+    Continue,
+    Step,
+}
+
+pub fn init(port_base: u16) {
+    let log = log!("Starting gdb stub");
+
+    // The stub polls the port itself, take it away from the console
+    if serial::CONSOLE.lock().port() == Some(port_base) {
+        serial::CONSOLE.lock().detach();
+    }
+    serial::close(port_base);
+
+    let ports = match serial::claim_ports("gdb", port_base) {
+        Ok(ports) => ports,
+        Err(err) => {
+            log.fail();
+            println!("{}  Serial port {:#x} not available: {:?}", RED, port_base, err);
+            return;
+        }
+    };
+    let port = SerialPort::from_claim(&ports);
+    port.init(BAUD_RATE);
+
+    *STUB.lock() = Some(GdbStub {
+        connection: Connection::new(port),
+        _ports: ports,
+        attached: false,
+        breakpoints: [None; MAX_SOFTWARE_BREAKPOINTS],
+    });
+
+    log.ok();
+    println!("{}  Waiting for gdb on serial port {:#x} at the next breakpoint",
+        LIGHT_GRAY, port_base);
+}
+
+// Starts the stub if the command line contains `gdb` (COM2) or
+// `gdb=com1`...`gdb=com4`
+pub fn init_from_command_line(command_line: &str) {
+    for arg in command_line.split_whitespace() {
+        let port = match arg {
+            "gdb" | "gdb=com2" => serial::COM2,
+            "gdb=com1" => serial::COM1,
+            "gdb=com3" => serial::COM3,
+            "gdb=com4" => serial::COM4,
+            _ => continue,
+        };
+
+        init(port);
+        return;
+    }
+}
+
+pub fn is_enabled() -> bool {
+    STUB.lock().is_some()
+}
+
+// Hands control to gdb until it continues or single steps. Called from the
+// #BP and #DB handlers, changes to the context are applied on return.
+pub fn enter(context: &mut InterruptContext, signal: u8) {
+    // Taken out of the lock for the session, so a panic in the meantime
+    // doesn't deadlock in enter_on_panic
+    let mut stub = match STUB.lock().take() {
+        Some(stub) => stub,
+        None => return,
+    };
+
+    context.stack_frame.flags &= !rflags::SINGLE_STEP.bits();
+
+    // int3 traps behind the instruction, report the breakpoint address
+    let rip = context.stack_frame.instruction_pointer;
+    if stub.breakpoints.iter().any(|bp| bp.map_or(false, |bp| bp.address == rip.wrapping_sub(1))) {
+        context.stack_frame.instruction_pointer = rip - 1;
+    }
+
+    let mut input = [0; BUFFER_SIZE];
+    let mut response = Response::new();
+
+    // A freshly connected gdb asks for the stop reason itself
+    if stub.attached {
+        response.push(b"S");
+        response.push_hex_byte(signal);
+        stub.connection.send(response.as_bytes());
+    }
+
+    loop {
+        let len = stub.connection.receive(&mut input);
+        stub.attached = true;
+        response.clear();
+
+        match stub.handle(&input[..len], context, signal, &mut response) {
+            Action::Stay => stub.connection.send(response.as_bytes()),
+            Action::Continue => break,
+            Action::Step => {
+                context.stack_frame.flags |= rflags::SINGLE_STEP.bits();
+                break;
+            }
+        }
+    }
+
+    // Don't hit an instruction breakpoint at the resume address right away
+    context.stack_frame.flags |= rflags::INSTRUCTION_BREAKPOINTS_DISABLED.bits();
+    *STUB.lock() = Some(stub);
+}
+
+// Lets gdb inspect the kernel after a panic. Execution can't be resumed.
+pub fn enter_on_panic() {
+    if !is_enabled() {
+        return;
+    }
+
+    let (rip, rsp, rbp): (u64, u64, u64);
+    unsafe {
+        asm!("lea $0, [rip]
+              mov $1, rsp
+              mov $2, rbp"
+              : "=r"(rip), "=r"(rsp), "=r"(rbp)
+              ::: "intel", "volatile");
+    }
+
+    let mut context = InterruptContext {
+        registers: Registers {
+            r15: 0, r14: 0, r13: 0, r12: 0, r11: 0, r10: 0, r9: 0, r8: 0,
+            rbp: rbp, rdi: 0, rsi: 0, rdx: 0, rcx: 0, rbx: 0, rax: 0,
+        },
+        stack_frame: ExceptionStackFrame {
+            instruction_pointer: rip,
+            code_segment: 0x08,
+            flags: rflags::RFlags::load().bits(),
+            stack_pointer: rsp,
+            stack_segment: 0x10,
+        },
+    };
+
+    enter(&mut context, SIGABRT);
+}
+
+
+
+impl GdbStub {
+    fn handle(&mut self, packet: &[u8], context: &mut InterruptContext, signal: u8,
+              response: &mut Response) -> Action {
+        if packet.is_empty() {
+            return Action::Stay;
+        }
+
+        let args = &packet[1..];
+        match packet[0] {
+            b'?' => {
+                response.push(b"S");
+                response.push_hex_byte(signal);
+            }
+            b'g' => {
+                for reg in 0..REGISTER_COUNT {
+                    let (value, size) = read_register(context, reg);
+                    response.push_hex_le(value, size);
+                }
+            }
+            b'G' => {
+                let mut offset = 0;
+                for reg in 0..REGISTER_COUNT {
+                    let size = read_register(context, reg).1;
+                    match parse_hex_le(&args[cmp::min(offset, args.len())..], size) {
+                        Some(value) => write_register(context, reg, value),
+                        None => break,
+                    }
+                    offset += 2 * size;
+                }
+                response.push(b"OK");
+            }
+            b'p' => {
+                match parse_hex(args).map(|reg| reg as usize) {
+                    Some(reg) if reg < REGISTER_COUNT => {
+                        let (value, size) = read_register(context, reg);
+                        response.push_hex_le(value, size);
+                    }
+                    // Registers we don't track, e.g. the FPU state
+                    _ => response.push(b"xxxxxxxx"),
+                }
+            }
+            b'P' => {
+                let mut parts = args.splitn(2, |&b| b == b'=');
+                let reg = parts.next().and_then(parse_hex).map(|reg| reg as usize);
+                match (reg, parts.next()) {
+                    (Some(reg), Some(value)) if reg < REGISTER_COUNT => {
+                        let size = read_register(context, reg).1;
+                        match parse_hex_le(value, size) {
+                            Some(value) => {
+                                write_register(context, reg, value);
+                                response.push(b"OK");
+                            }
+                            None => response.push_error(EINVAL),
+                        }
+                    }
+                    _ => response.push_error(EINVAL),
+                }
+            }
+            b'm' => {
+                match parse_address_length(args) {
+                    Some((addr, len))
+                        if (len as usize).checked_mul(2).map_or(false, |hex_len| hex_len <= BUFFER_SIZE) => {
+                        // Partial reads are fine, stop at the first fault
+                        for i in 0..len {
+                            match fixup::try_read::<u8>(addr.wrapping_add(i)) {
+                                Ok(byte) => response.push_hex_byte(byte),
+                                Err(_) if i > 0 => break,
+                                Err(_) => {
+                                    response.push_error(EFAULT);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    _ => response.push_error(EINVAL),
+                }
+            }
+            b'M' => {
+                let mut parts = args.splitn(2, |&b| b == b':');
+                let target = parts.next().and_then(parse_address_length);
+                match (target, parts.next()) {
+                    (Some((addr, len)), Some(data))
+                        if len.checked_mul(2).map_or(false, |hex_len| data.len() as u64 >= hex_len) => {
+                        let mut result = Ok(());
+                        for i in 0..len {
+                            let byte = parse_hex_le(&data[2 * i as usize..], 1).unwrap_or(0);
+                            result = unsafe { fixup::try_write(addr.wrapping_add(i), byte as u8) };
+                            if result.is_err() {
+                                break;
+                            }
+                        }
+
+                        match result {
+                            Ok(()) => response.push(b"OK"),
+                            Err(_) => response.push_error(EFAULT),
+                        }
+                    }
+                    _ => response.push_error(EINVAL),
+                }
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    context.stack_frame.instruction_pointer = addr;
+                }
+
+                return if packet[0] == b'c' { Action::Continue } else { Action::Step };
+            }
+            b'Z' | b'z' => {
+                let insert = packet[0] == b'Z';
+                match self.set_breakpoint(args, insert) {
+                    Ok(()) => response.push(b"OK"),
+                    Err(Some(errno)) => response.push_error(errno),
+                    // Unsupported breakpoint type
+                    Err(None) => {}
+                }
+            }
+            b'D' | b'k' => {
+                self.remove_all_breakpoints();
+                self.attached = false;
+                if packet[0] == b'D' {
+                    self.connection.send(b"OK");
+                }
+                return Action::Continue;
+            }
+            b'H' => response.push(b"OK"),
+            b'q' => {
+                if args.starts_with(b"Supported") {
+                    response.push(b"PacketSize=800");
+                } else if args == b"Attached" {
+                    response.push(b"1");
+                } else if args == b"C" {
+                    response.push(b"QC1");
+                } else if args == b"fThreadInfo" {
+                    response.push(b"m1");
+                } else if args == b"sThreadInfo" {
+                    response.push(b"l");
+                }
+            }
+            // Empty response: not supported
+            _ => {}
+        }
+
+        Action::Stay
+    }
+
+    // Handles Z/z packets: type,address,kind
+    fn set_breakpoint(&mut self, args: &[u8], insert: bool) -> Result<(), Option<u8>> {
+        let mut parts = args.split(|&b| b == b',');
+        let typ = parts.next().and_then(parse_hex);
+        let addr = parts.next().and_then(parse_hex);
+        let kind = parts.next().and_then(parse_hex);
+
+        let (typ, addr, kind) = match (typ, addr, kind) {
+            (Some(typ), Some(addr), Some(kind)) => (typ, addr, kind),
+            _ => return Err(Some(EINVAL)),
+        };
+
+        let (condition, size) = match typ {
+            0 => {
+                return if insert {
+                    self.insert_software_breakpoint(addr)
+                } else {
+                    self.remove_software_breakpoint(addr)
+                };
+            }
+            1 => (BreakCondition::Execute, BreakSize::Byte),
+            2 | 4 => {
+                let condition = if typ == 2 { BreakCondition::Write } else { BreakCondition::ReadWrite };
+                match kind {
+                    1 => (condition, BreakSize::Byte),
+                    2 => (condition, BreakSize::Word),
+                    4 => (condition, BreakSize::DoubleWord),
+                    8 => (condition, BreakSize::QuadWord),
+                    _ => return Err(Some(EINVAL)),
+                }
+            }
+            // Read-only watchpoints aren't supported by the hardware
+            _ => return Err(None),
+        };
+
+        if insert {
+            let breakpoint = Breakpoint {
+                name: "gdb",
+                address: addr,
+                condition: condition,
+                size: size,
+            };
+            debug_regs::install(breakpoint).map(|_| ()).map_err(|_| Some(ENOSPC))
+        } else {
+            match debug_regs::find(addr, condition) {
+                Some(slot) => debug_regs::remove(slot).map(|_| ()).map_err(|_| Some(EINVAL)),
+                None => Err(Some(EINVAL)),
+            }
+        }
+    }
+
+    fn insert_software_breakpoint(&mut self, addr: u64) -> Result<(), Option<u8>> {
+        if self.breakpoints.iter().any(|bp| bp.map_or(false, |bp| bp.address == addr)) {
+            return Ok(());
+        }
+
+        let slot = match self.breakpoints.iter().position(|bp| bp.is_none()) {
+            Some(slot) => slot,
+            None => return Err(Some(ENOSPC)),
+        };
+
+        let original = match fixup::try_read::<u8>(addr) {
+            Ok(original) => original,
+            Err(_) => return Err(Some(EFAULT)),
+        };
+        if unsafe { fixup::try_write(addr, INT3) }.is_err() {
+            return Err(Some(EFAULT));
+        }
+
+        self.breakpoints[slot] = Some(SoftwareBreakpoint {
+            address: addr,
+            original: original,
+        });
+        Ok(())
+    }
+
+    fn remove_software_breakpoint(&mut self, addr: u64) -> Result<(), Option<u8>> {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(bp) = *slot {
+                if bp.address == addr {
+                    let _ = unsafe { fixup::try_write(addr, bp.original) };
+                    *slot = None;
+                    return Ok(());
+                }
+            }
+        }
+
+        Err(Some(EINVAL))
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(bp) = slot.take() {
+                let _ = unsafe { fixup::try_write(bp.address, bp.original) };
+            }
+        }
+
+        for slot in 0..debug_regs::SLOT_COUNT {
+            if debug_regs::get(slot).map_or(false, |bp| bp.name == "gdb") {
+                debug_regs::remove(slot).unwrap();
+            }
+        }
+    }
+}
+
+
+
+// Value and size in bytes of register `reg` in gdb's numbering
+fn read_register(context: &InterruptContext, reg: usize) -> (u64, usize) {
+    let r = &context.registers;
+    let frame = &context.stack_frame;
+
+    match reg {
+        0 => (r.rax, 8),
+        1 => (r.rbx, 8),
+        2 => (r.rcx, 8),
+        3 => (r.rdx, 8),
+        4 => (r.rsi, 8),
+        5 => (r.rdi, 8),
+        6 => (r.rbp, 8),
+        7 => (frame.stack_pointer, 8),
+        8 => (r.r8, 8),
+        9 => (r.r9, 8),
+        10 => (r.r10, 8),
+        11 => (r.r11, 8),
+        12 => (r.r12, 8),
+        13 => (r.r13, 8),
+        14 => (r.r14, 8),
+        15 => (r.r15, 8),
+        16 => (frame.instruction_pointer, 8),
+        17 => (frame.flags, 4),
+        18 => (frame.code_segment, 4),
+        19 => (frame.stack_segment, 4),
+        // ds, es, fs, gs are all the flat data segment
+        _ => (0x10, 4),
+    }
+}
+
+fn write_register(context: &mut InterruptContext, reg: usize, value: u64) {
+    let r = &mut context.registers;
+    let frame = &mut context.stack_frame;
+
+    match reg {
+        0 => r.rax = value,
+        1 => r.rbx = value,
+        2 => r.rcx = value,
+        3 => r.rdx = value,
+        4 => r.rsi = value,
+        5 => r.rdi = value,
+        6 => r.rbp = value,
+        7 => frame.stack_pointer = value,
+        8 => r.r8 = value,
+        9 => r.r9 = value,
+        10 => r.r10 = value,
+        11 => r.r11 = value,
+        12 => r.r12 = value,
+        13 => r.r13 = value,
+        14 => r.r14 = value,
+        15 => r.r15 = value,
+        16 => frame.instruction_pointer = value,
+        17 => frame.flags = value,
+        // Segment registers can't be changed
+        _ => {}
+    }
+}
+
+fn parse_address_length(args: &[u8]) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+        (Some(addr), Some(len)) => Some((addr, len)),
+        _ => None,
+    }
+}
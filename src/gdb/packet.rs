@@ -0,0 +1,187 @@
+use serial::SerialPort;
+
+pub const BUFFER_SIZE: usize = 4096;
+
+// Remote serial protocol framing: $<data>#<checksum>, acknowledged by +
+// or rejected by - (which asks for a retransmit)
+pub struct Connection {
+    port: SerialPort,
+}
+
+impl Connection {
+    pub fn new(port: SerialPort) -> Connection {
+        Connection { port: port }
+    }
+
+    // Waits for the next valid packet and returns its length
+    pub fn receive(&self, buf: &mut [u8; BUFFER_SIZE]) -> usize {
+        loop {
+            // Skip acks and interrupt requests (0x03) between packets
+            while self.port.read_byte() != b'$' {}
+
+            let mut len = 0;
+            let mut sum: u8 = 0;
+            let mut overflow = false;
+            loop {
+                let byte = self.port.read_byte();
+                if byte == b'#' {
+                    break;
+                }
+
+                sum = sum.wrapping_add(byte);
+                if len < BUFFER_SIZE {
+                    buf[len] = byte;
+                    len += 1;
+                } else {
+                    overflow = true;
+                }
+            }
+
+            let checksum = (hex_value(self.port.read_byte()), hex_value(self.port.read_byte()));
+            match checksum {
+                (Some(high), Some(low)) if high << 4 | low == sum && !overflow => {
+                    self.port.write_byte(b'+');
+                    return len;
+                }
+                _ => self.port.write_byte(b'-'),
+            }
+        }
+    }
+
+    pub fn send(&self, data: &[u8]) {
+        loop {
+            let mut sum: u8 = 0;
+
+            self.port.write_byte(b'$');
+            for &byte in data {
+                // Bytes with a meaning in the framing need to be escaped
+                if byte == b'$' || byte == b'#' || byte == b'}' || byte == b'*' {
+                    self.port.write_byte(b'}');
+                    self.port.write_byte(byte ^ 0x20);
+                    sum = sum.wrapping_add(b'}').wrapping_add(byte ^ 0x20);
+                } else {
+                    self.port.write_byte(byte);
+                    sum = sum.wrapping_add(byte);
+                }
+            }
+            self.port.write_byte(b'#');
+            self.port.write_byte(HEX_DIGITS[(sum >> 4) as usize]);
+            self.port.write_byte(HEX_DIGITS[(sum & 0xf) as usize]);
+
+            loop {
+                match self.port.read_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+
+
+const HEX_DIGITS: &'static [u8; 16] = b"0123456789abcdef";
+
+pub fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'...b'9' => Some(digit - b'0'),
+        b'a'...b'f' => Some(digit - b'a' + 10),
+        b'A'...b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Parses a big endian hex number, as used for addresses and lengths
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+
+    let mut value = 0;
+    for &digit in digits {
+        match hex_value(digit) {
+            Some(nibble) => value = value << 4 | nibble as u64,
+            None => return None,
+        }
+    }
+
+    Some(value)
+}
+
+// Parses `size` bytes of little endian hex, as used for register values
+pub fn parse_hex_le(digits: &[u8], size: usize) -> Option<u64> {
+    if digits.len() < size * 2 {
+        return None;
+    }
+
+    let mut value = 0;
+    for i in 0..size {
+        match (hex_value(digits[2 * i]), hex_value(digits[2 * i + 1])) {
+            (Some(high), Some(low)) => value |= ((high << 4 | low) as u64) << (8 * i),
+            _ => return None,
+        }
+    }
+
+    Some(value)
+}
+
+// Packet payload under construction
+pub struct Response {
+    buf: [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl Response {
+    pub fn new() -> Response {
+        Response {
+            buf: [0; BUFFER_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        for &byte in data {
+            if self.len < BUFFER_SIZE {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            }
+        }
+    }
+
+    pub fn push_hex_byte(&mut self, byte: u8) {
+        self.push(&[HEX_DIGITS[(byte >> 4) as usize], HEX_DIGITS[(byte & 0xf) as usize]]);
+    }
+
+    // Little endian, as expected for register values
+    pub fn push_hex_le(&mut self, value: u64, size: usize) {
+        for i in 0..size {
+            self.push_hex_byte((value >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn push_error(&mut self, errno: u8) {
+        self.push(b"E");
+        self.push_hex_byte(errno);
+    }
+}
+
+
+
+#[test]
+fn test_parse_hex() {
+    assert_eq!(parse_hex(b"ffff8000"), Some(0xffff8000));
+    assert_eq!(parse_hex(b"0"), Some(0));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"12g"), None);
+    assert_eq!(parse_hex_le(b"efbeadde", 4), Some(0xdeadbeef));
+    assert_eq!(parse_hex_le(b"ef", 2), None);
+}
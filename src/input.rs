@@ -0,0 +1,33 @@
+use keyboard::{self, Key, MetaKey};
+use serial;
+
+// Returns the next key pressed on the PS/2 keyboard or received on the
+// serial console, so the shell can also be driven by `-serial stdio`
+pub fn try_next_key() -> Option<Key> {
+    if let Some(key) = keyboard::try_next_key() {
+        return Some(key);
+    }
+
+    let port = serial::CONSOLE.lock().port();
+    port.and_then(serial::read_byte).and_then(byte_to_key)
+}
+
+pub fn next_key() -> Key {
+    loop {
+        if let Some(key) = try_next_key() {
+            return key;
+        }
+    }
+}
+
+// Terminals send \r for Enter and DEL for Backspace
+fn byte_to_key(byte: u8) -> Option<Key> {
+    match byte {
+        b'\r' | b'\n' => Some(Key::Meta(MetaKey::Enter)),
+        0x08 | 0x7f => Some(Key::Meta(MetaKey::Backspace)),
+        b'\t' => Some(Key::Meta(MetaKey::Tab)),
+        0x1b => Some(Key::Meta(MetaKey::Esc)),
+        0x20...0x7e => Some(Key::Char(byte as char)),
+        _ => None,
+    }
+}
@@ -1,7 +1,8 @@
 use spin::Mutex;
-use cpuio::Port;
+use cpuio::{self, Port, PortBus, CpuBus, PortClaim, PortRange};
 
-pub static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
+// Set up by init() once the ports are claimed
+static KEYBOARD: Mutex<Option<ClaimedKeyboard>> = Mutex::new(None);
 static LOOPUP_TABLE: [Input; 0] = [];
 static EXTENDED_LOOKUP_TABLE_1: [Input; 0] = [];
 
@@ -45,20 +46,52 @@ pub enum MetaKey {
     CapsLock,
 }
 
-pub struct Keyboard {
-    port: Port<u8>,
+// Status register bit set while a scancode is waiting in the data port
+const OUTPUT_BUFFER_FULL: u8 = 1 << 0;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+pub struct Keyboard<B: PortBus = CpuBus> {
+    port: Port<u8, B>,
+    status: Port<u8, B>,
+}
+
+impl Keyboard<CpuBus> {
+    pub fn from_claims(data: &PortClaim, status: &PortClaim) -> Keyboard<CpuBus> {
+        Keyboard {
+            port: data.port(0),
+            status: status.port(0),
+        }
+    }
+}
+
+struct ClaimedKeyboard {
+    keyboard: Keyboard,
+    _ports: (PortClaim, PortClaim),
 }
 
-impl Keyboard {
-    pub const fn new() -> Keyboard {
-        Keyboard { port: unsafe { Port::new(0x60) } }
+impl<B: PortBus + Clone> Keyboard<B> {
+    pub fn on_bus(bus: B) -> Keyboard<B> {
+        Keyboard {
+            port: unsafe { Port::on_bus(DATA_PORT, bus.clone()) },
+            status: unsafe { Port::on_bus(STATUS_PORT, bus) },
+        }
     }
+}
+
+impl<B: PortBus> Keyboard<B> {
 
     pub fn poll(&self) -> Option<Input> {
         use self::Input::*;
         use self::Key::*;
         use self::MetaKey::*;
 
+        // Without this check the last scancode would be read again and again
+        if self.status.read() & OUTPUT_BUFFER_FULL == 0 {
+            return None;
+        }
+
         match self.port.read() {
             0x01 => Some(Pressed(Meta(Esc))),
             0x02 => Some(Pressed(Char('1'))),
@@ -139,6 +172,86 @@ impl Keyboard {
     }
 }
 
+// Claims the controller's data and status ports for good. Without them
+// there's no keyboard input.
+pub fn init() {
+    let log = log!("Claiming keyboard ports");
+
+    let data = match cpuio::claim_ports("keyboard", PortRange::new(DATA_PORT, 1)) {
+        Ok(claim) => claim,
+        Err(_) => return log.fail(),
+    };
+    let status = match cpuio::claim_ports("keyboard", PortRange::new(STATUS_PORT, 1)) {
+        Ok(claim) => claim,
+        Err(_) => {
+            cpuio::release_ports(data);
+            return log.fail();
+        }
+    };
+
+    *KEYBOARD.lock() = Some(ClaimedKeyboard {
+        keyboard: Keyboard::from_claims(&data, &status),
+        _ports: (data, status),
+    });
+    log.ok();
+}
+
 pub fn next_key() -> Key {
-    KEYBOARD.lock().next_key()
-}
\ No newline at end of file
+    loop {
+        if let Some(key) = try_next_key() {
+            return key;
+        }
+    }
+}
+
+pub fn try_next_key() -> Option<Key> {
+    match KEYBOARD.lock().as_ref().and_then(|claimed| claimed.keyboard.poll()) {
+        Some(Input::Pressed(k)) => Some(k),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+use cpuio::fake_bus::{Access, FakeBus};
+
+#[test]
+fn test_poll_empty() {
+    let bus = FakeBus::new();
+    bus.script(STATUS_PORT, &[0x1c]);
+
+    assert!(Keyboard::on_bus(&bus).poll().is_none());
+    // The data port must not be read without a scancode waiting
+    assert_eq!(bus.accesses(), vec![Access::Read(STATUS_PORT, 0x1c)]);
+}
+
+#[test]
+fn test_poll_scancode() {
+    let bus = FakeBus::new();
+    bus.script(STATUS_PORT, &[0x1d, 0x1d]);
+    bus.script(DATA_PORT, &[0x1e, 0x01]);
+    let keyboard = Keyboard::on_bus(&bus);
+
+    match keyboard.poll() {
+        Some(Input::Pressed(Key::Char('a'))) => {}
+        _ => panic!("expected 'a' to be pressed"),
+    }
+    match keyboard.poll() {
+        Some(Input::Pressed(Key::Meta(MetaKey::Esc))) => {}
+        _ => panic!("expected Esc to be pressed"),
+    }
+    assert_eq!(bus.accesses(), vec![Access::Read(STATUS_PORT, 0x1d), Access::Read(DATA_PORT, 0x1e),
+                                    Access::Read(STATUS_PORT, 0x1d), Access::Read(DATA_PORT, 0x01)]);
+}
+
+#[test]
+fn test_next_key_skips_releases() {
+    let bus = FakeBus::new();
+    bus.set_default(STATUS_PORT, 0x1d);
+    // 'a' released, unknown scancode, then 'q' pressed
+    bus.script(DATA_PORT, &[0x9e, 0x60, 0x10]);
+
+    match Keyboard::on_bus(&bus).next_key() {
+        Key::Char('q') => {}
+        key => panic!("expected 'q', got {:?}", key),
+    }
+}
@@ -0,0 +1,145 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpuid;
+use memory::paging;
+
+// New-style kvmclock MSR. The legacy one (0x12) is only used
+// if KVM_CLOCKSOURCE2 isn't offered.
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+const MSR_KVM_SYSTEM_TIME: u32 = 0x12;
+
+// Low bit of the MSR value enables updates of the time info structure
+const ENABLE: u64 = 1;
+
+// Set in `flags` if the TSC is synchronized between all vcpus
+const PVCLOCK_TSC_STABLE: u8 = 1 << 0;
+
+// Layout shared with the hypervisor, see Documentation/virtual/kvm/msr.txt
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct PvClockTimeInfo {
+    version: u32,
+    _pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad: [u8; 2],
+}
+
+// Updated by the hypervisor, needs to be 4-byte aligned
+static mut TIME_INFO: PvClockTimeInfo = PvClockTimeInfo {
+    version: 0,
+    _pad0: 0,
+    tsc_timestamp: 0,
+    system_time: 0,
+    tsc_to_system_mul: 0,
+    tsc_shift: 0,
+    flags: 0,
+    _pad: [0; 2],
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init() -> bool {
+    let msr = match cpuid::get_kvm_features() {
+        Some(features) if features.contains(cpuid::KVM_CLOCKSOURCE2) => MSR_KVM_SYSTEM_TIME_NEW,
+        Some(features) if features.contains(cpuid::KVM_CLOCKSOURCE) => MSR_KVM_SYSTEM_TIME,
+        _ => return false,
+    };
+
+    let log = log!("Setting up kvmclock");
+
+    let addr = unsafe { &TIME_INFO as *const _ as usize };
+    let phys = paging::P4_TABLE_EXCL.lock()
+        .translate(addr)
+        .expect("kvmclock time info not mapped");
+
+    unsafe {
+        ::x86::shared::msr::wrmsr(msr, phys as u64 | ENABLE);
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+
+    log.ok();
+    true
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+pub fn is_stable() -> bool {
+    is_enabled() && snapshot().flags & PVCLOCK_TSC_STABLE != 0
+}
+
+// Consistent copy of the time info. The hypervisor makes the version odd
+// while it updates the structure.
+fn snapshot() -> PvClockTimeInfo {
+    loop {
+        let version = unsafe { ptr::read_volatile(&TIME_INFO.version) };
+        if version & 1 != 0 {
+            continue;
+        }
+
+        let info = unsafe { ptr::read_volatile(&TIME_INFO) };
+        if unsafe { ptr::read_volatile(&TIME_INFO.version) } == version {
+            return info;
+        }
+    }
+}
+
+// Nanoseconds since the host started the guest clock
+pub fn system_time_ns() -> Option<u64> {
+    if !is_enabled() {
+        return None;
+    }
+
+    loop {
+        let info = snapshot();
+        let tsc = unsafe { ::x86::shared::time::rdtsc() };
+
+        // Retry if the hypervisor updated the structure in the meantime
+        if unsafe { ptr::read_volatile(&TIME_INFO.version) } != info.version {
+            continue;
+        }
+
+        let delta = tsc.wrapping_sub(info.tsc_timestamp);
+        return Some(info.system_time.wrapping_add(scale_delta(delta, info.tsc_to_system_mul, info.tsc_shift)));
+    }
+}
+
+// Computes ((delta << shift) * mul) >> 32 without 128 bit arithmetic,
+// truncated to 64 bits like the hypervisor does
+fn scale_delta(delta: u64, mul: u32, shift: i8) -> u64 {
+    // Through i32, as negating -128 overflows an i8
+    let amount = (shift as i32).abs() as u32;
+    let delta = if shift < 0 {
+        delta.checked_shr(amount).unwrap_or(0)
+    } else {
+        delta.checked_shl(amount).unwrap_or(0)
+    };
+
+    let high = delta >> 32;
+    let low = delta & 0xffff_ffff;
+
+    high.wrapping_mul(mul as u64).wrapping_add((low * (mul as u64)) >> 32)
+}
+
+
+
+#[test]
+fn test_scale_delta() {
+    // mul = 2^31 halves the delta
+    assert_eq!(scale_delta(1000, 1 << 31, 0), 500);
+    assert_eq!(scale_delta(1000, 1 << 31, 1), 1000);
+    assert_eq!(scale_delta(1000, 1 << 31, -1), 250);
+    assert_eq!(scale_delta(3 << 40, 1 << 31, 0), 3 << 39);
+}
+
+#[test]
+fn test_scale_delta_extremes() {
+    assert_eq!(scale_delta(!0, 1 << 31, -128), 0);
+    assert_eq!(scale_delta(!0, 1 << 31, 127), 0);
+    assert_eq!(scale_delta(!0, !0, 0), 0xffff_fffe_ffff_ffff);
+}
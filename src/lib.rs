@@ -7,9 +7,12 @@
 #![feature(iter_min_by)]
 #![feature(core_intrinsics)]
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![allow(dead_code)]
 
+#[cfg(test)]
+extern crate core;
+
 extern crate rlibc;
 extern crate spin;
 extern crate multiboot2;
@@ -23,22 +26,41 @@ extern crate lazy_static;
 
 mod vga_buffer;
 mod memory;
+mod mmio;
+mod backtrace;
+mod fixup;
 mod cpuio;
 mod keyboard;
+mod input;
 mod cpuid;
 mod control_regs;
+mod debug_regs;
+mod cpu_features;
+mod fpu;
+mod mca;
+mod mtrr;
+mod kvmclock;
+mod trace;
+mod serial;
+mod gdb;
+mod monitor;
+mod testing;
 
 use memory::*;
 use keyboard::Key::*;
 use keyboard::MetaKey::*;
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn rust_main(multiboot_information_addr: usize) {
+    serial::init_console(serial::COM1);
+
     println!("\n{}               ########################", LIGHT_GRAY);
     println!("               #{}     BorOS v0.0.1     {}#", CYAN, LIGHT_GRAY);
     println!("               ########################");
     
-    let boot_info = unsafe { multiboot2::load(multiboot_information_addr) };
+    // The boot code passes the physical address of the boot information
+    let boot_info = unsafe { multiboot2::load(memory::phys_to_virt(multiboot_information_addr)) };
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
 
     /*
@@ -58,8 +80,12 @@ pub extern "C" fn rust_main(multiboot_information_addr: usize) {
     // }
     //
 
-    let kernel_start = elf_sections_tag.sections().map(|sect| sect.addr).min().unwrap();
-    let kernel_end = elf_sections_tag.sections().map(|sect| sect.addr + sect.size).max().unwrap();
+    backtrace::init(elf_sections_tag);
+
+    // Physical addresses, only the boot code is linked at its load address
+    let section_phys = |addr: u64| if addr >= KERNEL_OFFSET as u64 { addr - KERNEL_OFFSET as u64 } else { addr };
+    let kernel_start = elf_sections_tag.sections().map(|sect| section_phys(sect.addr)).min().unwrap();
+    let kernel_end = elf_sections_tag.sections().map(|sect| section_phys(sect.addr + sect.size)).max().unwrap();
     let kernel_len_kb = (kernel_end - kernel_start) / 1024;
     set_color!(LIGHT_GRAY);
     println!("{}Kernel Space:    {}{:#x} {}~{} {:#x} [{}{:4} KiB{}]",
@@ -76,19 +102,45 @@ pub extern "C" fn rust_main(multiboot_information_addr: usize) {
         GREEN, multiboot_len_kb, LIGHT_GRAY);
 
 
-    let mut alloc = AreaFrameAllocator::new(kernel_start as usize,
-                                            kernel_end as usize,
-                                            multiboot_start,
-                                            multiboot_end,
-                                            cpuio::APIC_ADDRESS_BASE,
-                                            memory_map_tag.memory_areas());
+    let command_line = boot_info.command_line_tag()
+        .map(|tag| tag.command_line())
+        .unwrap_or("");
+    cpu_features::enable_supported(command_line);
+    gdb::init_from_command_line(command_line);
+
+    let boot_alloc = AreaFrameAllocator::new(kernel_start as usize,
+                                             kernel_end as usize,
+                                             multiboot_start,
+                                             multiboot_end,
+                                             cpuio::APIC_ADDRESS_BASE,
+                                             memory_map_tag.memory_areas().map(|area| {
+                                                 (area.base_addr as usize, area.length as usize)
+                                             }));
+    memory::init_frame_allocator(boot_alloc);
+    memory::stats::record_memory_map(memory_map_tag);
+    let memory_info = memory::stats::info();
+    println!("{}Memory:          {}{} KiB {}usable, {}{} KiB {}reserved, {}{} KiB {}ACPI",
+        LIGHT_GRAY, WHITE, memory_info.usable / 1024, LIGHT_GRAY,
+        WHITE, memory_info.reserved / 1024, LIGHT_GRAY,
+        WHITE, memory_info.acpi / 1024, LIGHT_GRAY);
+    let mut alloc = GlobalFrameAllocator;
 
     
     cpuio::setup_apic(&mut alloc);
+    keyboard::init();
+    fpu::init();
+    mca::init();
+    kvmclock::init();
+    serial::enable_console_interrupts();
+    cpuio::enable_hardware_interrupts();
+
+    if cfg!(feature = "qemu-test") {
+        testing::run_all();
+    }
 
     
     loop {
-        match keyboard::next_key() {
+        match next_key() {
             Meta(Esc) | Char('q') => {
                 println!("{}> quit", LIGHT_GRAY);
                 println!("{}Until next time!", CYAN);
@@ -98,18 +150,81 @@ pub extern "C" fn rust_main(multiboot_information_addr: usize) {
                 println!("{}> vendor", LIGHT_GRAY);
                 println!("{}CPU Vendor is {:?}", 
                     CYAN, cpuid::get_vendor());
+                if let Some(hypervisor) = cpuid::get_hypervisor() {
+                    println!("{}Running under {:?}", CYAN, hypervisor);
+                }
             }
             Char('f') => {
                 println!("{}> features", LIGHT_GRAY);
                 println!("{}CPU Features are {:?}", 
                     CYAN, cpuid::get_features());
             }
+            Char('c') => {
+                println!("{}> cpu info", LIGHT_GRAY);
+                print_cpu_info();
+            }
+            Char('k') => {
+                println!("{}> kvmclock", LIGHT_GRAY);
+                match kvmclock::system_time_ns() {
+                    Some(ns) => println!("{}System time: {}{}.{:09}s {}(stable: {})",
+                        LIGHT_GRAY, CYAN, ns / 1_000_000_000, ns % 1_000_000_000,
+                        LIGHT_GRAY, kvmclock::is_stable()),
+                    None => println!("{}kvmclock not available", RED),
+                }
+            }
+            Char('m') => {
+                println!("{}> machine check", LIGHT_GRAY);
+                let found = mca::poll();
+                println!("{}{} corrected machine check errors", CYAN, found);
+            }
+            Char('b') => {
+                println!("{}> backtrace", LIGHT_GRAY);
+                backtrace::print();
+            }
             Char('t') => {
                 println!("{}> trigger", LIGHT_GRAY);
                 println!("{}Triggering breakpoint", CYAN);
 
                 unsafe { int!(3); }
             }
+            Char('g') => {
+                println!("{}> gdb", LIGHT_GRAY);
+                if !gdb::is_enabled() {
+                    gdb::init(serial::COM2);
+                }
+
+                unsafe { int!(3); }
+            }
+            Char('s') => {
+                println!("{}> single step", LIGHT_GRAY);
+                let cr0 = trace::single_step(true, || control_regs::cr0::CR0::load());
+                trace::dump();
+                println!("{}CR0: {}{:?}", LIGHT_GRAY, CYAN, cr0);
+            }
+            Char('p') => {
+                println!("{}> ports", LIGHT_GRAY);
+                cpuio::print_port_claims();
+            }
+            Char('y') => {
+                println!("{}> memory types", LIGHT_GRAY);
+                mtrr::print();
+            }
+            Char('i') => {
+                println!("{}> meminfo", LIGHT_GRAY);
+                memory::stats::print();
+            }
+            Char('d') => {
+                println!("{}> page tables", LIGHT_GRAY);
+                memory::paging::print_mappings();
+            }
+            Char('a') => {
+                println!("{}> slab caches", LIGHT_GRAY);
+                memory::slab::print_stats();
+            }
+            Char('w') => {
+                println!("{}> watch", LIGHT_GRAY);
+                toggle_page_table_watchpoint();
+            }
             Char('z') => {
                 println!("{}> trigger2", LIGHT_GRAY);
                 println!("{}Triggering page fault", CYAN);
@@ -160,6 +275,68 @@ pub extern "C" fn rust_main(multiboot_information_addr: usize) {
 
 }
 
+// Waits for the next key press, doing background polling in the meantime
+fn next_key() -> keyboard::Key {
+    loop {
+        if let Some(key) = input::try_next_key() {
+            return key;
+        }
+
+        mca::poll_periodically();
+    }
+}
+
+// Reports writes to the first P4 entry, which maps the lower half
+fn toggle_page_table_watchpoint() {
+    use debug_regs::{Breakpoint, BreakCondition, BreakSize};
+
+    let p4_entry = memory::paging::P4_TABLE_ADDRESS as u64;
+    if let Some(slot) = debug_regs::find(p4_entry, BreakCondition::Write) {
+        debug_regs::remove(slot).unwrap();
+        println!("{}Removed watchpoint #{}", CYAN, slot);
+        return;
+    }
+
+    let watch = Breakpoint {
+        name: "P4 entry 0",
+        address: p4_entry,
+        condition: BreakCondition::Write,
+        size: BreakSize::QuadWord,
+    };
+    match debug_regs::install(watch) {
+        Ok(slot) => println!("{}Watching writes to {:#x} in slot #{}", CYAN, p4_entry, slot),
+        Err(e) => println!("{}Can't install watchpoint: {:?}", RED, e),
+    }
+}
+
+fn print_cpu_info() {
+    if let Some(brand) = cpuid::get_brand_string() {
+        println!("{}Brand:     {}{}", LIGHT_GRAY, CYAN, brand);
+    }
+
+    let widths = cpuid::get_address_widths();
+    println!("{}Addresses: {}{} bit physical, {} bit linear",
+        LIGHT_GRAY, CYAN, widths.physical, widths.linear);
+
+    if let Some(topology) = cpuid::get_topology() {
+        println!("{}Topology:  {}x2APIC id {}, {} threads/core, {} logical/package",
+            LIGHT_GRAY, CYAN,
+            topology.x2apic_id, topology.threads_per_core,
+            topology.logical_processors_per_package);
+    }
+
+    for cache in cpuid::get_cache_info() {
+        println!("{}Cache:     {}L{} {:?} {} KiB, {}-way, {} byte lines",
+            LIGHT_GRAY, CYAN,
+            cache.level, cache.cache_type, cache.size() / 1024,
+            cache.ways, cache.line_size);
+    }
+
+    println!("{}Extended:  {}{:?}", LIGHT_GRAY, CYAN, cpuid::get_extended_features());
+    println!("{}Ext. Proc: {}{:?}", LIGHT_GRAY, CYAN, cpuid::get_extended_processor_features());
+}
+
+#[cfg(not(test))]
 #[lang = "eh_personality"]
 #[no_mangle]
 pub extern "C" fn eh_personality() {
@@ -167,6 +344,7 @@ pub extern "C" fn eh_personality() {
     println!("\n\neh personality called");
 }
 
+#[cfg(not(test))]
 #[lang = "panic_fmt"]
 #[no_mangle]
 pub extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &str, line: u32) -> ! {
@@ -176,5 +354,8 @@ pub extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &str, line: u32) ->
         LIGHT_GRAY, file, RED,
         LIGHT_GRAY, line, RED);
     println!("    {}", fmt);
+    backtrace::print();
+    testing::handle_panic();
+    gdb::enter_on_panic();
     loop {}
 }
\ No newline at end of file
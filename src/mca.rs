@@ -0,0 +1,405 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86::shared::msr::{rdmsr, wrmsr};
+use cpuid;
+use control_regs::cr4::{self, CR4};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+const IA32_MC0_CTL: u32 = 0x400;
+
+// Time stamp counter ticks between two polls of the corrected error banks
+const POLL_INTERVAL: u64 = 1 << 32;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LAST_POLL: AtomicUsize = AtomicUsize::new(0);
+
+bitflags! {
+    flags McgCapabilities: u64 {
+        const MCG_CTL_PRESENT           = 1 <<  8,
+        const MCG_EXTENDED_REGISTERS    = 1 <<  9,
+        const MCG_CORRECTED_INTERRUPT   = 1 << 10,
+        const MCG_THRESHOLD_STATUS      = 1 << 11,
+        const MCG_SOFTWARE_RECOVERY     = 1 << 24,
+        const MCG_EXTENDED_LOGGING      = 1 << 26,
+        const MCG_LOCAL_MACHINE_CHECK   = 1 << 27,
+    }
+}
+
+bitflags! {
+    flags McgStatus: u64 {
+        // Execution can be restarted at the pushed instruction pointer
+        const RESTART_IP_VALID          = 1 << 0,
+        // The pushed instruction pointer is related to the error
+        const ERROR_IP_VALID            = 1 << 1,
+        // A machine check is in progress, a second one causes a shutdown
+        const MACHINE_CHECK_IN_PROGRESS = 1 << 2,
+        const LOCAL_MACHINE_CHECK       = 1 << 3,
+    }
+}
+
+bitflags! {
+    flags BankStatus: u64 {
+        const ACTION_REQUIRED           = 1 << 55,
+        const SIGNALING                 = 1 << 56,
+        const CONTEXT_CORRUPT           = 1 << 57,
+        const ADDR_VALID                = 1 << 58,
+        const MISC_VALID                = 1 << 59,
+        const ERROR_ENABLED             = 1 << 60,
+        const UNCORRECTED               = 1 << 61,
+        const OVERFLOW                  = 1 << 62,
+        const VALID                     = 1 << 63,
+    }
+}
+
+fn bank_count() -> u32 {
+    (capabilities_raw() & 0xff) as u32
+}
+
+fn capabilities_raw() -> u64 {
+    unsafe { rdmsr(IA32_MCG_CAP) }
+}
+
+fn bank_msr(bank: u32, offset: u32) -> u32 {
+    IA32_MC0_CTL + 4 * bank + offset
+}
+
+
+
+pub fn init() {
+    let features = cpuid::get_features();
+    if !features.contains(cpuid::MACHINE_CHECK_EXCEPTION) {
+        return;
+    }
+
+    let log = log!("Setting up machine check architecture");
+
+    if features.contains(cpuid::MACHINE_CHECK_ARCHITECTURE) {
+        let caps = McgCapabilities::from_bits_truncate(capabilities_raw());
+        unsafe {
+            if caps.contains(MCG_CTL_PRESENT) {
+                wrmsr(IA32_MCG_CTL, !0);
+            }
+
+            // Report every error class. Errors logged before boot, often
+            // the reason for the reboot, are shown before clearing them.
+            let skip_bank0 = firmware_owns_bank0();
+            for bank in 0..bank_count() {
+                if bank != 0 || !skip_bank0 {
+                    wrmsr(bank_msr(bank, 0), !0);
+                }
+                if let Some(error) = BankError::read(bank) {
+                    println!("{}  Error logged before boot:", YELLOW);
+                    error.log();
+                }
+                wrmsr(bank_msr(bank, 1), 0);
+            }
+        }
+
+        ENABLED.store(true, Ordering::SeqCst);
+        println!("{}  {} banks, capabilities {:?}", LIGHT_GRAY, bank_count(), caps);
+    }
+
+    (CR4::load() | cr4::MACHINE_CHECK).store();
+
+    log.ok();
+}
+
+// Software must not write MC0_CTL on P6 family processors before Nehalem
+// (SDM 15.3.2.1), the firmware sets it up
+fn firmware_owns_bank0() -> bool {
+    let signature = cpuid::get_signature();
+    cpuid::get_vendor() == cpuid::Vendor::INTEL && signature.family == 6 && signature.model < 0x1a
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    // Corrected by hardware, logged for information only
+    Corrected,
+    // Uncorrected, but no action required (UCNA)
+    UncorrectedNoAction,
+    // Uncorrected, software recovery optional (SRAO)
+    ActionOptional,
+    // Uncorrected, software recovery needed before resuming (SRAR)
+    ActionRequired,
+    // Processor context corrupt
+    Fatal,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BankError {
+    pub bank: u32,
+    pub status: u64,
+    pub addr: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl BankError {
+    fn read(bank: u32) -> Option<BankError> {
+        let status = unsafe { rdmsr(bank_msr(bank, 1)) };
+        BankError::from_registers(bank, status, || unsafe { rdmsr(bank_msr(bank, 2)) },
+                                  || unsafe { rdmsr(bank_msr(bank, 3)) })
+    }
+
+    fn from_registers<A, M>(bank: u32, status: u64, addr: A, misc: M) -> Option<BankError>
+        where A: FnOnce() -> u64, M: FnOnce() -> u64
+    {
+        let flags = BankStatus::from_bits_truncate(status);
+        if !flags.contains(VALID) {
+            return None;
+        }
+
+        Some(BankError {
+            bank: bank,
+            status: status,
+            addr: if flags.contains(ADDR_VALID) { Some(addr()) } else { None },
+            misc: if flags.contains(MISC_VALID) { Some(misc()) } else { None },
+        })
+    }
+
+    fn clear(&self) {
+        unsafe { wrmsr(bank_msr(self.bank, 1), 0) }
+    }
+
+    pub fn flags(&self) -> BankStatus {
+        BankStatus::from_bits_truncate(self.status)
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        ErrorCode(self.status as u16)
+    }
+
+    pub fn model_specific_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+
+    pub fn severity(&self) -> Severity {
+        let flags = self.flags();
+
+        if !flags.contains(UNCORRECTED) {
+            Severity::Corrected
+        } else if flags.contains(CONTEXT_CORRUPT) {
+            Severity::Fatal
+        } else if flags.contains(SIGNALING) && flags.contains(ACTION_REQUIRED) {
+            Severity::ActionRequired
+        } else if flags.contains(SIGNALING) {
+            Severity::ActionOptional
+        } else {
+            Severity::UncorrectedNoAction
+        }
+    }
+
+    fn log(&self) {
+        println!("{}  Bank {}: {}{} {}({:?})",
+            LIGHT_GRAY, self.bank, WHITE, self.error_code(), LIGHT_GRAY, self.severity());
+        println!("{}    Status {:#018x}, model specific {:#06x}{}",
+            LIGHT_GRAY, self.status, self.model_specific_code(),
+            if self.flags().contains(OVERFLOW) { ", overflowed" } else { "" });
+
+        if let Some(addr) = self.addr {
+            println!("{}    Address {:#x}", LIGHT_GRAY, addr);
+        }
+        if let Some(misc) = self.misc {
+            println!("{}    Misc {:#x}", LIGHT_GRAY, misc);
+        }
+    }
+}
+
+
+
+// Simple and compound MCA error codes (bits 15:0 of MCi_STATUS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorCode(pub u16);
+
+const LEVELS: [&'static str; 4] = ["L0", "L1", "L2", "generic level"];
+const TRANSACTIONS: [&'static str; 4] = ["instruction", "data", "generic", "reserved"];
+const REQUESTS: [&'static str; 16] = ["generic", "read", "write", "data read", "data write",
+                                      "instruction fetch", "prefetch", "eviction", "snoop",
+                                      "reserved", "reserved", "reserved", "reserved",
+                                      "reserved", "reserved", "reserved"];
+const MEMORY_OPS: [&'static str; 8] = ["generic", "read", "write", "address/command",
+                                       "scrubbing", "reserved", "reserved", "reserved"];
+const PARTICIPATION: [&'static str; 4] = ["local processor originated",
+                                          "local processor responded",
+                                          "local processor observed", "generic"];
+
+impl ErrorCode {
+    fn level(&self) -> &'static str {
+        LEVELS[(self.0 & 0b11) as usize]
+    }
+
+    fn transaction(&self) -> &'static str {
+        TRANSACTIONS[((self.0 >> 2) & 0b11) as usize]
+    }
+
+    fn request(&self) -> &'static str {
+        REQUESTS[((self.0 >> 4) & 0b1111) as usize]
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Bit 12 only marks corrected error filtering
+        let code = self.0 & !(1 << 12);
+
+        match code {
+            0x0000 => write!(f, "No error"),
+            0x0001 => write!(f, "Unclassified error"),
+            0x0002 => write!(f, "Microcode ROM parity error"),
+            0x0003 => write!(f, "External error"),
+            0x0004 => write!(f, "FRC error"),
+            0x0005 => write!(f, "Internal parity error"),
+            0x0006 => write!(f, "SMM handler code access violation"),
+            0x0400 => write!(f, "Internal timer error"),
+            0x0e0b => write!(f, "I/O error"),
+            c if c & 0xfc00 == 0x0400 => write!(f, "Internal unclassified error"),
+            c if c & 0xeffc == 0x000c => write!(f, "Cache error ({})", self.level()),
+            c if c & 0xeff0 == 0x0010 => {
+                write!(f, "TLB error ({} {})", self.transaction(), self.level())
+            }
+            c if c & 0xef80 == 0x0080 => {
+                write!(f, "Memory controller error ({}, channel ", MEMORY_OPS[((c >> 4) & 0b111) as usize])?;
+                match c & 0b1111 {
+                    0b1111 => write!(f, "unspecified)"),
+                    channel => write!(f, "{})", channel),
+                }
+            }
+            c if c & 0xef00 == 0x0100 => {
+                write!(f, "Cache hierarchy error ({} {} {})",
+                    self.request(), self.transaction(), self.level())
+            }
+            c if c & 0xe800 == 0x0800 => {
+                write!(f, "Bus error ({}, {}{}, {})",
+                    PARTICIPATION[((c >> 9) & 0b11) as usize],
+                    self.request(),
+                    if c & (1 << 8) != 0 { ", timeout" } else { "" },
+                    self.level())
+            }
+            c => write!(f, "Unknown error code {:#06x}", c),
+        }
+    }
+}
+
+
+
+// Called from the #MC handler. Logs and clears every bank and returns true
+// if execution can continue.
+pub fn handle_machine_check() -> bool {
+    if !is_enabled() {
+        return false;
+    }
+
+    let status = McgStatus::from_bits_truncate(unsafe { rdmsr(IA32_MCG_STATUS) });
+    println!("{}  Global status: {:?}", LIGHT_GRAY, status);
+
+    let mut recoverable = status.contains(RESTART_IP_VALID);
+    for bank in 0..bank_count() {
+        if let Some(error) = BankError::read(bank) {
+            error.log();
+
+            match error.severity() {
+                Severity::ActionRequired | Severity::Fatal => recoverable = false,
+                _ => error.clear(),
+            }
+        }
+    }
+
+    if recoverable {
+        unsafe { wrmsr(IA32_MCG_STATUS, 0) }
+    }
+
+    recoverable
+}
+
+// Logs and clears corrected errors, which don't raise #MC. Returns the
+// number of errors found.
+pub fn poll() -> usize {
+    if !is_enabled() {
+        return 0;
+    }
+
+    let mut found = 0;
+    for bank in 0..bank_count() {
+        if let Some(error) = BankError::read(bank) {
+            // Uncorrected errors are left for the #MC handler
+            if error.severity() == Severity::Corrected {
+                if found == 0 {
+                    println!("{}\nMACHINE CHECK: {}Corrected errors", YELLOW, WHITE);
+                }
+                error.log();
+                error.clear();
+                found += 1;
+            }
+        }
+    }
+
+    found
+}
+
+// Polls if at least POLL_INTERVAL time stamp counter ticks have passed
+// since the last poll. Meant to be called from idle loops.
+pub fn poll_periodically() {
+    let now = unsafe { ::x86::shared::time::rdtsc() } as usize;
+    let last = LAST_POLL.load(Ordering::Relaxed);
+
+    if (now.wrapping_sub(last) as u64) < POLL_INTERVAL {
+        return;
+    }
+
+    LAST_POLL.store(now, Ordering::Relaxed);
+    poll();
+}
+
+
+
+#[test]
+fn test_error_code_decoding() {
+    use core::fmt::Write;
+
+    struct Buf([u8; 128], usize);
+    impl Write for Buf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for b in s.bytes() {
+                self.0[self.1] = b;
+                self.1 += 1;
+            }
+            Ok(())
+        }
+    }
+
+    fn decode(code: u16) -> Buf {
+        let mut buf = Buf([0; 128], 0);
+        write!(buf, "{}", ErrorCode(code)).unwrap();
+        buf
+    }
+
+    let buf = decode(0x0000);
+    assert_eq!(&buf.0[..buf.1], b"No error");
+    let buf = decode(0x0146); // data write, data, L2
+    assert_eq!(&buf.0[..buf.1], b"Cache hierarchy error (data write data L2)");
+    let buf = decode(0x009f); // memory read, channel unspecified
+    assert_eq!(&buf.0[..buf.1], b"Memory controller error (read, channel unspecified)");
+    let buf = decode(0x1014); // filtered TLB error, data L0
+    assert_eq!(&buf.0[..buf.1], b"TLB error (data L0)");
+}
+
+#[test]
+fn test_severity() {
+    let error = |status: u64| BankError::from_registers(0, status, || 0, || 0);
+
+    assert!(error(0).is_none());
+    assert_eq!(error(1 << 63).unwrap().severity(), Severity::Corrected);
+    assert_eq!(error(1 << 63 | 1 << 61).unwrap().severity(), Severity::UncorrectedNoAction);
+    assert_eq!(error(1 << 63 | 1 << 61 | 1 << 56).unwrap().severity(), Severity::ActionOptional);
+    assert_eq!(error(1 << 63 | 1 << 61 | 1 << 56 | 1 << 55).unwrap().severity(),
+               Severity::ActionRequired);
+    assert_eq!(error(1 << 63 | 1 << 61 | 1 << 57).unwrap().severity(), Severity::Fatal);
+    assert_eq!(error(1 << 63 | 1 << 58).unwrap().addr, Some(0));
+}
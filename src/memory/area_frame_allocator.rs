@@ -1,10 +1,50 @@
-use memory::{FrameAllocator, Frame};
-use multiboot2::{MemoryArea, MemoryAreaIter};
+use memory::{FrameAllocator, Frame, PAGE_SIZE};
+
+// Usable areas beyond this are ignored
+const MAX_AREAS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    first_frame: Frame,
+    last_frame: Frame,
+}
+
+impl Area {
+    // An area of `length` bytes at `base`, `None` if it doesn't contain a
+    // whole frame
+    fn new(base: usize, length: usize) -> Option<Area> {
+        let first = Frame::for_address(base + PAGE_SIZE - 1);
+        let end = Frame::for_address(base + length);
+
+        if first < end {
+            Some(Area { first_frame: first, last_frame: Frame { number: end.number - 1 } })
+        } else {
+            None
+        }
+    }
+}
+
+// Freed frames are kept as runs of contiguous frames
+const MAX_FREE_RUNS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FreeRun {
+    first_frame: Frame,
+    count: usize,
+}
+
+fn align_up(number: usize, align: usize) -> usize {
+    (number + align - 1) & !(align - 1)
+}
 
 pub struct AreaFrameAllocator {
     next_free_frame: Frame,
-    current_area: Option<&'static MemoryArea>,
-    areas: MemoryAreaIter,
+    current_area: Option<Area>,
+    areas: [Option<Area>; MAX_AREAS],
+    free_runs: [Option<FreeRun>; MAX_FREE_RUNS],
+    // Freed frames that didn't fit into `free_runs`
+    lost_frames: usize,
+    allocated_frames: usize,
     kernel_start: Frame,
     kernel_end: Frame,
     multiboot_start: Frame,
@@ -12,34 +52,30 @@ pub struct AreaFrameAllocator {
     apic_frame: Frame,
 }
 
-trait MemoryAreaExt {
-    fn get_first_frame(&self) -> Frame;
-    fn get_last_frame(&self) -> Frame;
-}
-
-impl MemoryAreaExt for MemoryArea {
-    fn get_first_frame(&self) -> Frame {
-        Frame::for_address(self.base_addr as usize)
-    }
-
-    fn get_last_frame(&self) -> Frame {
-        Frame::for_address((self.base_addr + self.length - 1) as usize)
-    }
-}
-
 impl AreaFrameAllocator {
-    pub fn new(kernel_start: usize,
-               kernel_end: usize,
-               multiboot_start: usize,
-               multiboot_end: usize,
-               apic_loc: usize,
-               memory_areas: MemoryAreaIter)
-               -> AreaFrameAllocator {
+    // `memory_areas` yields the (base address, length) of each usable area
+    pub fn new<I>(kernel_start: usize,
+                  kernel_end: usize,
+                  multiboot_start: usize,
+                  multiboot_end: usize,
+                  apic_loc: usize,
+                  memory_areas: I)
+                  -> AreaFrameAllocator
+        where I: IntoIterator<Item = (usize, usize)>
+    {
+        let mut areas = [None; MAX_AREAS];
+        let usable = memory_areas.into_iter().filter_map(|(base, length)| Area::new(base, length));
+        for (slot, area) in areas.iter_mut().zip(usable) {
+            *slot = Some(area);
+        }
 
         let mut alloc = AreaFrameAllocator {
             next_free_frame: Frame::for_address(0),
             current_area: None,
-            areas: memory_areas,
+            areas: areas,
+            free_runs: [None; MAX_FREE_RUNS],
+            lost_frames: 0,
+            allocated_frames: 0,
             kernel_start: Frame::for_address(kernel_start),
             kernel_end: Frame::for_address(kernel_end),
             multiboot_start: Frame::for_address(multiboot_start),
@@ -51,54 +87,308 @@ impl AreaFrameAllocator {
         alloc
     }
 
+    // Allocates `count` contiguous frames. The first frame number is a
+    // multiple of `align` (a power of two), all frames lie below `limit`.
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize, limit: Frame) -> Option<Frame> {
+        assert!(count > 0 && align.is_power_of_two());
+
+        let frame = self.take_from_free_runs(count, align, limit)
+                        .or_else(|| self.take_from_areas(count, align, limit));
+        if frame.is_some() {
+            self.allocated_frames += count;
+        }
+        frame
+    }
+
+    pub fn dealloc_contiguous(&mut self, first_frame: Frame, count: usize) {
+        self.allocated_frames -= count;
+        self.add_free_run(first_frame, count);
+    }
+
+    // Frames handed out and not freed yet
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated_frames
+    }
+
     fn choose_next_area(&mut self) {
+        let next_free_frame = self.next_free_frame;
         self.current_area = self.areas
-                                .clone()
-                                .filter(|area| area.get_last_frame() > self.next_free_frame)
-                                .min_by(|obj, reference| obj.base_addr.cmp(&reference.base_addr));
+                                .iter()
+                                .filter_map(|area| *area)
+                                .filter(|area| area.last_frame >= next_free_frame)
+                                .min_by_key(|area| area.first_frame);
 
         if let Some(area) = self.current_area {
             // We found a new area
-            if self.next_free_frame < area.get_first_frame() {
-                self.next_free_frame = area.get_first_frame()
+            if self.next_free_frame < area.first_frame {
+                self.next_free_frame = area.first_frame
             }
         }
     }
-}
 
-impl FrameAllocator for AreaFrameAllocator {
-    fn alloc(&mut self) -> Option<Frame> {
-        if self.current_area.is_none() {
-            return None;
+    // Last frame of the reserved range containing `frame`, if any
+    fn reserved_until(&self, frame: Frame) -> Option<Frame> {
+        if frame >= self.kernel_start && frame <= self.kernel_end {
+            Some(self.kernel_end)
+        } else if frame >= self.multiboot_start && frame <= self.multiboot_end {
+            Some(self.multiboot_end)
+        } else if frame == self.apic_frame {
+            Some(self.apic_frame)
+        } else {
+            None
+        }
+    }
+
+    // Last frame of the first reserved range overlapping `first..end`
+    fn reserved_in(&self, first: Frame, end: Frame) -> Option<Frame> {
+        if let Some(last) = self.reserved_until(first) {
+            return Some(last);
         }
 
-        let frame = self.next_free_frame;
-        let last_for_current_area = self.current_area.unwrap().get_last_frame();
+        [self.kernel_start, self.multiboot_start, self.apic_frame]
+            .iter()
+            .filter(|&&start| start > first && start < end)
+            .min()
+            .and_then(|&start| self.reserved_until(start))
+    }
 
-        if self.next_free_frame >= last_for_current_area {
-            self.choose_next_area();
-        } else {
-            self.next_free_frame = self.next_free_frame.next();
+    fn take_from_free_runs(&mut self, count: usize, align: usize, limit: Frame) -> Option<Frame> {
+        for idx in 0..MAX_FREE_RUNS {
+            if let Some(run) = self.free_runs[idx] {
+                let start = align_up(run.first_frame.number, align);
+                let end = start + count;
+                let run_end = run.first_frame.number + run.count;
+
+                if end <= run_end && end <= limit.number {
+                    self.free_runs[idx] = None;
+                    if start > run.first_frame.number {
+                        self.add_free_run(run.first_frame, start - run.first_frame.number);
+                    }
+                    if run_end > end {
+                        self.add_free_run(Frame::new(end), run_end - end);
+                    }
+                    return Some(Frame::new(start));
+                }
+            }
         }
 
+        None
+    }
+
+    fn take_from_areas(&mut self, count: usize, align: usize, limit: Frame) -> Option<Frame> {
         loop {
-            if self.next_free_frame >= self.kernel_start &&
-               self.next_free_frame <= self.kernel_end {
-                self.next_free_frame = self.kernel_end.next();
-            } else if self.next_free_frame >= self.multiboot_start &&
-               self.next_free_frame <= self.multiboot_end {
-                self.next_free_frame = self.multiboot_end.next();
-            } else if self.next_free_frame == self.apic_frame {
-                self.next_free_frame = self.apic_frame.next()
-            } else {
-                break;
+            let area = match self.current_area {
+                Some(area) => area,
+                None => return None,
+            };
+
+            if self.next_free_frame > area.last_frame {
+                self.choose_next_area();
+                continue;
+            }
+
+            let skipped = self.next_free_frame;
+            let start = Frame::new(align_up(skipped.number, align));
+            let end = Frame::new(start.number + count);
+            if end > limit {
+                return None;
+            }
+
+            // Frames passed over for alignment or at the end of an area
+            // are kept for later
+            if end > area.last_frame.next() {
+                self.release_range(skipped, area.last_frame.next());
+                self.next_free_frame = area.last_frame.next();
+                continue;
             }
+
+            if let Some(last_reserved) = self.reserved_in(start, end) {
+                self.release_range(skipped, last_reserved.next());
+                self.next_free_frame = last_reserved.next();
+                continue;
+            }
+
+            self.release_range(skipped, start);
+            self.next_free_frame = end;
+            return Some(start);
         }
+    }
+
+    // Makes the frames in `first..end` that aren't reserved available again
+    fn release_range(&mut self, first: Frame, end: Frame) {
+        let mut frame = first;
+        while frame < end {
+            if let Some(last_reserved) = self.reserved_until(frame) {
+                frame = last_reserved.next();
+                continue;
+            }
 
-        return Some(frame);
+            let run_end = match self.reserved_in(frame, end) {
+                Some(_) => {
+                    [self.kernel_start, self.multiboot_start, self.apic_frame]
+                        .iter()
+                        .cloned()
+                        .filter(|&start| start > frame && start < end)
+                        .min()
+                        .unwrap()
+                }
+                None => end,
+            };
+            self.add_free_run(frame, run_end.number - frame.number);
+            frame = run_end;
+        }
     }
 
-    fn dealloc(&mut self, _: Frame) {
-        // unimplemented!()
+    fn add_free_run(&mut self, first_frame: Frame, count: usize) {
+        let mut run = FreeRun { first_frame: first_frame, count: count };
+
+        // Merge with the runs right before and after
+        for slot in self.free_runs.iter_mut() {
+            if let Some(other) = *slot {
+                if other.first_frame.number + other.count == run.first_frame.number {
+                    run = FreeRun { first_frame: other.first_frame, count: other.count + run.count };
+                    *slot = None;
+                } else if run.first_frame.number + run.count == other.first_frame.number {
+                    run.count += other.count;
+                    *slot = None;
+                }
+            }
+        }
+
+        match self.free_runs.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(run),
+            None => self.lost_frames += run.count,
+        }
+    }
+}
+
+impl FrameAllocator for AreaFrameAllocator {
+    fn alloc(&mut self) -> Option<Frame> {
+        self.alloc_contiguous(1, 1, Frame::new(!0))
+    }
+
+    fn dealloc(&mut self, frame: Frame) {
+        self.dealloc_contiguous(frame, 1);
+    }
+}
+
+
+#[cfg(test)]
+fn test_allocator(areas: &[(usize, usize)]) -> AreaFrameAllocator {
+    // Kernel in frames 0x100-0x10f, multiboot info in 0x110, APIC at 0xfee00
+    AreaFrameAllocator::new(0x100000, 0x10f000, 0x110000, 0x110fff, 0xfee00000,
+                            areas.iter().cloned())
+}
+
+#[test]
+fn test_alloc_in_order() {
+    let mut alloc = test_allocator(&[(0x1000, 3 * PAGE_SIZE)]);
+
+    assert_eq!(alloc.alloc(), Some(Frame::new(1)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(2)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(3)));
+    assert_eq!(alloc.alloc(), None);
+}
+
+#[test]
+fn test_alloc_skips_reserved() {
+    let mut alloc = test_allocator(&[(0xff000, 0x13000), (0xfedff000, 3 * PAGE_SIZE)]);
+
+    assert_eq!(alloc.alloc(), Some(Frame::new(0xff)));
+    // Kernel and multiboot frames are back to back
+    assert_eq!(alloc.alloc(), Some(Frame::new(0x111)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(0xfedff)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(0xfee01)));
+    assert_eq!(alloc.alloc(), None);
+}
+
+#[test]
+fn test_alloc_reserved_at_area_start() {
+    let mut alloc = test_allocator(&[(0x100000, 0x12000)]);
+
+    assert_eq!(alloc.alloc(), Some(Frame::new(0x111)));
+    assert_eq!(alloc.alloc(), None);
+}
+
+#[test]
+fn test_alloc_areas_by_address() {
+    let mut alloc = test_allocator(&[(0x8000, PAGE_SIZE), (0x2000, PAGE_SIZE), (0x5000, PAGE_SIZE)]);
+
+    assert_eq!(alloc.alloc(), Some(Frame::new(2)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(5)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(8)));
+    assert_eq!(alloc.alloc(), None);
+}
+
+#[test]
+fn test_area_partial_frames() {
+    // Only whole frames are usable
+    let mut alloc = test_allocator(&[(0x1800, 0x2000), (0x4000, 0x800)]);
+
+    assert_eq!(alloc.alloc(), Some(Frame::new(2)));
+    assert_eq!(alloc.alloc(), None);
+}
+
+#[test]
+fn test_dealloc_reuse() {
+    let mut alloc = test_allocator(&[(0x1000, 4 * PAGE_SIZE)]);
+
+    let first = alloc.alloc().unwrap();
+    let second = alloc.alloc().unwrap();
+    alloc.dealloc(first);
+    assert_eq!(alloc.alloc(), Some(first));
+    alloc.dealloc(second);
+    assert_eq!(alloc.alloc(), Some(second));
+    assert_eq!(alloc.alloc(), Some(Frame::new(3)));
+    assert_eq!(alloc.allocated_frames(), 3);
+}
+
+#[test]
+fn test_free_runs_merge() {
+    let mut alloc = test_allocator(&[(0x1000, 8 * PAGE_SIZE)]);
+    for _ in 0..8 {
+        alloc.alloc().unwrap();
     }
+
+    alloc.dealloc(Frame::new(3));
+    alloc.dealloc(Frame::new(5));
+    alloc.dealloc(Frame::new(4));
+    assert_eq!(alloc.free_runs.iter().filter(|run| run.is_some()).count(), 1);
+    assert_eq!(alloc.alloc_contiguous(3, 1, Frame::new(!0)), Some(Frame::new(3)));
+    assert_eq!(alloc.alloc(), None);
+}
+
+#[test]
+fn test_alloc_contiguous() {
+    let mut alloc = test_allocator(&[(0x1000, 0x40 * PAGE_SIZE)]);
+
+    assert_eq!(alloc.alloc(), Some(Frame::new(1)));
+    // Frames 2-15 are skipped for alignment, but still handed out later
+    assert_eq!(alloc.alloc_contiguous(4, 16, Frame::new(!0)), Some(Frame::new(0x10)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(2)));
+    assert_eq!(alloc.alloc_contiguous(13, 1, Frame::new(!0)), Some(Frame::new(3)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(0x14)));
+}
+
+#[test]
+fn test_alloc_contiguous_limit() {
+    let mut alloc = test_allocator(&[(0x1000, 0x20 * PAGE_SIZE)]);
+
+    assert_eq!(alloc.alloc_contiguous(0x10, 1, Frame::new(0x10)), None);
+    assert_eq!(alloc.alloc_contiguous(0xf, 1, Frame::new(0x10)), Some(Frame::new(1)));
+    assert_eq!(alloc.alloc_contiguous(1, 1, Frame::new(0x10)), None);
+    assert_eq!(alloc.alloc(), Some(Frame::new(0x10)));
+}
+
+#[test]
+fn test_alloc_contiguous_around_reserved() {
+    let mut alloc = test_allocator(&[(0xf0000, 0x40 * PAGE_SIZE), (0x200000, 0x10 * PAGE_SIZE)]);
+
+    // The kernel and multiboot info (frames 0x100-0x110) split the first area
+    assert_eq!(alloc.alloc_contiguous(0x20, 1, Frame::new(!0)), None);
+    assert_eq!(alloc.alloc_contiguous(0x1e, 1, Frame::new(!0)), Some(Frame::new(0x111)));
+    assert_eq!(alloc.alloc_contiguous(0x10, 1, Frame::new(!0)), Some(Frame::new(0xf0)));
+    assert_eq!(alloc.alloc_contiguous(0x10, 1, Frame::new(!0)), Some(Frame::new(0x200)));
+    assert_eq!(alloc.alloc(), Some(Frame::new(0x12f)));
+    assert_eq!(alloc.alloc(), None);
 }
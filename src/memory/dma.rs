@@ -0,0 +1,100 @@
+use core::{cmp, slice};
+use memory::{self, Frame, GlobalFrameAllocator, PAGE_SIZE, KERNEL_MAPPED_LIMIT};
+use memory::paging::{self, CacheMode, PhysicalAddress, VirtualAddress};
+
+// Ceilings for devices that can only address part of physical memory
+pub const ISA_DMA_LIMIT: PhysicalAddress = 16 << 20;
+pub const DMA32_LIMIT: PhysicalAddress = 4 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    // Alignment isn't a power of two or the size is 0
+    InvalidLayout,
+    // No run of free frames fits the request
+    OutOfMemory,
+}
+
+// Physically contiguous memory for device transfers, mapped uncached.
+// Frames in the kernel's mapping of low memory are made uncached there as
+// well. The frames and the mapping are freed on drop.
+pub struct DmaBuffer {
+    virt: VirtualAddress,
+    phys: PhysicalAddress,
+    len: usize,
+}
+
+impl DmaBuffer {
+    // Allocates `len` bytes at a physical address aligned to `align` bytes
+    // that end below `limit`
+    pub fn new(len: usize, align: usize, limit: PhysicalAddress) -> Result<DmaBuffer, DmaError> {
+        if len == 0 || !align.is_power_of_two() {
+            return Err(DmaError::InvalidLayout);
+        }
+
+        let count = frame_count(len);
+        let align_frames = if align > PAGE_SIZE { align / PAGE_SIZE } else { 1 };
+        let first_frame = memory::alloc_contiguous(count, align_frames, Frame::for_address(limit))
+            .ok_or(DmaError::OutOfMemory)?;
+
+        let phys = first_frame.first_addr();
+        let aliased = aliased_frames(phys, count);
+        if aliased > 0 {
+            paging::set_kernel_cache_mode(first_frame, aliased, CacheMode::Uncached);
+        }
+        let virt = paging::map_mmio(phys, count * PAGE_SIZE, CacheMode::Uncached,
+                                    &mut GlobalFrameAllocator);
+
+        Ok(DmaBuffer {
+            virt: virt,
+            phys: phys,
+            len: len,
+        })
+    }
+
+    // Address to program into the device
+    pub fn phys_addr(&self) -> PhysicalAddress {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtualAddress {
+        self.virt
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt as *const u8, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt as *mut u8, self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let count = frame_count(self.len);
+        paging::unmap_mmio(self.virt, count * PAGE_SIZE);
+
+        let aliased = aliased_frames(self.phys, count);
+        if aliased > 0 {
+            paging::set_kernel_cache_mode(Frame::for_address(self.phys), aliased, CacheMode::WriteBack);
+        }
+        memory::dealloc_contiguous(Frame::for_address(self.phys), count);
+    }
+}
+
+fn frame_count(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+// How many of the `count` frames at `phys` the kernel's mapping covers
+fn aliased_frames(phys: PhysicalAddress, count: usize) -> usize {
+    if phys >= KERNEL_MAPPED_LIMIT {
+        0
+    } else {
+        cmp::min(count, (KERNEL_MAPPED_LIMIT - phys) / PAGE_SIZE)
+    }
+}
@@ -1,9 +1,19 @@
 pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::dma::{DmaBuffer, DmaError, ISA_DMA_LIMIT, DMA32_LIMIT};
+use spin::Mutex;
 mod area_frame_allocator;
+mod dma;
 pub mod paging;
+pub mod slab;
+pub mod stats;
 
 pub const PAGE_SIZE: usize = 4096;
 
+// The kernel is linked at KERNEL_OFFSET. The boot code maps the first GiB
+// of physical memory there, the lower half is left unmapped.
+pub const KERNEL_OFFSET: usize = 0xffff_ffff_8000_0000;
+pub const KERNEL_MAPPED_LIMIT: usize = 1 << 30;
+
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
@@ -41,6 +51,53 @@ pub trait FrameAllocator {
     fn dealloc(&mut self, frame: Frame);
 }
 
+static FRAME_ALLOCATOR: Mutex<Option<AreaFrameAllocator>> = Mutex::new(None);
+
+// Hands the boot frame allocator to GlobalFrameAllocator
+pub fn init_frame_allocator(alloc: AreaFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(alloc);
+}
+
+// Allocates from the allocator passed to init_frame_allocator, so frames
+// can be allocated without threading an allocator through every caller
+pub struct GlobalFrameAllocator;
+
+impl FrameAllocator for GlobalFrameAllocator {
+    fn alloc(&mut self) -> Option<Frame> {
+        FRAME_ALLOCATOR.lock().as_mut().and_then(|alloc| alloc.alloc())
+    }
+
+    fn dealloc(&mut self, frame: Frame) {
+        FRAME_ALLOCATOR.lock().as_mut().expect("No frame allocator").dealloc(frame)
+    }
+}
+
+// See AreaFrameAllocator::alloc_contiguous
+pub fn alloc_contiguous(count: usize, align: usize, limit: Frame) -> Option<Frame> {
+    FRAME_ALLOCATOR.lock().as_mut().and_then(|alloc| alloc.alloc_contiguous(count, align, limit))
+}
+
+pub fn dealloc_contiguous(first_frame: Frame, count: usize) {
+    FRAME_ALLOCATOR.lock().as_mut().expect("No frame allocator").dealloc_contiguous(first_frame, count)
+}
+
+pub fn allocated_frames() -> usize {
+    FRAME_ALLOCATOR.lock().as_ref().map(|alloc| alloc.allocated_frames()).unwrap_or(0)
+}
+
+// Address the physical memory at `phys` is mapped at in the kernel's
+// mapping of low memory
+pub fn phys_to_virt(phys: usize) -> usize {
+    assert!(phys < KERNEL_MAPPED_LIMIT, "{:#x} is not mapped by the kernel", phys);
+    phys + KERNEL_OFFSET
+}
+
+pub fn virt_to_phys(virt: usize) -> usize {
+    assert!(virt >= KERNEL_OFFSET && virt - KERNEL_OFFSET < KERNEL_MAPPED_LIMIT,
+            "{:#x} is not in the kernel's mapping", virt);
+    virt - KERNEL_OFFSET
+}
+
 
 #[test]
 fn test_frame() {
@@ -0,0 +1,162 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86::shared::msr::{rdmsr, wrmsr};
+use memory::paging::entry::*;
+
+const IA32_PAT: u32 = 0x277;
+
+// Memory types as encoded in the PAT and the MTRRs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    Uncacheable,
+    WriteCombining,
+    WriteThrough,
+    WriteProtected,
+    WriteBack,
+    // Uncacheable, but can be overridden by write-combining MTRRs
+    UncachedMinus,
+    Reserved(u8),
+}
+
+impl MemoryType {
+    pub fn from_bits(bits: u8) -> MemoryType {
+        match bits {
+            0x00 => MemoryType::Uncacheable,
+            0x01 => MemoryType::WriteCombining,
+            0x04 => MemoryType::WriteThrough,
+            0x05 => MemoryType::WriteProtected,
+            0x06 => MemoryType::WriteBack,
+            0x07 => MemoryType::UncachedMinus,
+            bits => MemoryType::Reserved(bits),
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        match *self {
+            MemoryType::Uncacheable => 0x00,
+            MemoryType::WriteCombining => 0x01,
+            MemoryType::WriteThrough => 0x04,
+            MemoryType::WriteProtected => 0x05,
+            MemoryType::WriteBack => 0x06,
+            MemoryType::UncachedMinus => 0x07,
+            MemoryType::Reserved(bits) => bits,
+        }
+    }
+}
+
+// Entries 0-3 keep their power-on types, so PWT and PCD mean the same with
+// and without PAT support. Entry 4, selected by the PAT bit, is added for
+// write-combining.
+const PAT_LAYOUT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteCombining,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+];
+
+// The power-on layout, repeating entries 0-3
+const DEFAULT_PAT_LAYOUT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+];
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Memory type of a mapping, selected through the PAT, PCD and PWT bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    // Strongly ordered, for device registers
+    Uncached,
+    // Writes are buffered and combined, for framebuffers. Uncached if the
+    // CPU doesn't support PAT.
+    WriteCombining,
+}
+
+impl CacheMode {
+    // Flags selecting the mode's entry in the page attribute table, only
+    // valid for 4 KiB pages
+    pub fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheMode::WriteBack => EntryFlags::empty(),
+            CacheMode::WriteThrough => WRITE_THROUGH,
+            CacheMode::Uncached => NO_CACHE | WRITE_THROUGH,
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) => PAT,
+            CacheMode::WriteCombining => NO_CACHE | WRITE_THROUGH,
+        }
+    }
+}
+
+fn pat_value(layout: &[MemoryType; 8]) -> u64 {
+    layout.iter()
+          .enumerate()
+          .fold(0, |value, (i, memory_type)| value | (memory_type.bits() as u64) << (i * 8))
+}
+
+// Index into the page attribute table selected by the flags of a 4 KiB page
+pub fn pat_index(flags: EntryFlags) -> usize {
+    (flags.contains(PAT) as usize) << 2 |
+    (flags.contains(NO_CACHE) as usize) << 1 |
+    flags.contains(WRITE_THROUGH) as usize
+}
+
+// The page attribute table currently in use
+pub fn pat_layout() -> [MemoryType; 8] {
+    if !PAT_ENABLED.load(Ordering::Relaxed) {
+        return DEFAULT_PAT_LAYOUT;
+    }
+
+    let value = unsafe { rdmsr(IA32_PAT) };
+    let mut layout = [MemoryType::Uncacheable; 8];
+    for (i, memory_type) in layout.iter_mut().enumerate() {
+        *memory_type = MemoryType::from_bits((value >> (i * 8)) as u8);
+    }
+    layout
+}
+
+// Programs the page attribute table. Nothing is mapped through entry 4
+// before, so no caches need to be flushed.
+pub fn init_pat() {
+    unsafe { wrmsr(IA32_PAT, pat_value(&PAT_LAYOUT)) };
+    PAT_ENABLED.store(true, Ordering::SeqCst);
+}
+
+
+#[test]
+fn test_pat_value() {
+    // Matches the power-on value of IA32_PAT
+    assert_eq!(pat_value(&DEFAULT_PAT_LAYOUT), 0x0007_0406_0007_0406);
+    assert_eq!(pat_value(&PAT_LAYOUT), 0x0007_0401_0007_0406);
+}
+
+#[test]
+fn test_cache_mode_entries() {
+    for &mode in [CacheMode::WriteBack, CacheMode::WriteThrough, CacheMode::Uncached].iter() {
+        let expected = match mode {
+            CacheMode::WriteBack => MemoryType::WriteBack,
+            CacheMode::WriteThrough => MemoryType::WriteThrough,
+            _ => MemoryType::Uncacheable,
+        };
+        assert_eq!(PAT_LAYOUT[pat_index(mode.flags())], expected);
+        assert_eq!(DEFAULT_PAT_LAYOUT[pat_index(mode.flags())], expected);
+    }
+
+    assert_eq!(PAT_LAYOUT[pat_index(PAT)], MemoryType::WriteCombining);
+}
+
+#[test]
+fn test_memory_type_bits() {
+    for bits in 0..8 {
+        assert_eq!(MemoryType::from_bits(bits).bits(), bits);
+    }
+}
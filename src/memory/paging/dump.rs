@@ -0,0 +1,146 @@
+use memory::PAGE_SIZE;
+use memory::paging::{Page, PageTableHead, PhysicalAddress, VirtualAddress, MemoryType, P4_TABLE_EXCL};
+use memory::paging::{pat_index, pat_layout};
+use memory::paging::entry::*;
+use memory::paging::table::{PhysicalMemory, ENTRY_COUNT, RECURSIVE_INDEX};
+
+const HUGE_2M: usize = PAGE_SIZE * ENTRY_COUNT;
+const HUGE_1G: usize = HUGE_2M * ENTRY_COUNT;
+
+// Neighbouring pages mapping contiguous frames with the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtualAddress,
+    pub len: usize,
+    pub phys: PhysicalAddress,
+    pub page_size: usize,
+    // Without ACCESSED and DIRTY, and HUGE_PAGE dropped so bit 7 is always
+    // the PAT bit. The PAT bit of huge pages (bit 12) is ignored.
+    pub flags: EntryFlags,
+}
+
+impl MappedRange {
+    pub fn end(&self) -> VirtualAddress {
+        self.start + self.len
+    }
+
+    pub fn memory_type(&self) -> MemoryType {
+        pat_layout()[pat_index(self.flags)]
+    }
+
+    fn extend(&mut self, other: &MappedRange) -> bool {
+        let contiguous = self.end() == other.start && self.phys + self.len == other.phys;
+        if contiguous && self.page_size == other.page_size && self.flags == other.flags {
+            self.len += other.len;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn virtual_address(p4: usize, p3: usize, p2: usize, p1: usize) -> VirtualAddress {
+    Page::for_table_indices(p4, p3, p2, p1).first_addr()
+}
+
+fn leaf(start: VirtualAddress, entry: &Entry, page_size: usize) -> MappedRange {
+    let huge = if page_size > PAGE_SIZE { HUGE_PAGE } else { EntryFlags::empty() };
+    MappedRange {
+        start: start,
+        len: page_size,
+        phys: entry.target_frame().unwrap().first_addr(),
+        page_size: page_size,
+        flags: entry.flags() - ACCESSED - DIRTY - huge,
+    }
+}
+
+// Extends `current` by `range` or hands it to `f` and starts a new one
+fn coalesce<F>(current: &mut Option<MappedRange>, range: MappedRange, f: &mut F)
+    where F: FnMut(MappedRange)
+{
+    if let Some(ref mut last) = *current {
+        if last.extend(&range) {
+            return;
+        }
+        f(*last);
+    }
+    *current = Some(range);
+}
+
+impl<M: PhysicalMemory> PageTableHead<M> {
+    // Calls `f` for every mapped range in address order. The recursive P4
+    // entry is skipped.
+    pub fn for_each_mapping<F>(&self, mut f: F)
+        where F: FnMut(MappedRange)
+    {
+        let memory = &self.memory;
+        let p4 = self.get_p4();
+        let mut current = None;
+
+        for i4 in (0..ENTRY_COUNT).filter(|&i| i != RECURSIVE_INDEX) {
+            let p3 = match p4.next_table(i4, memory) {
+                Some(p3) => p3,
+                None => continue,
+            };
+
+            for i3 in 0..ENTRY_COUNT {
+                let entry = &p3[i3];
+                if entry.target_frame().is_some() && entry.flags().contains(HUGE_PAGE) {
+                    coalesce(&mut current, leaf(virtual_address(i4, i3, 0, 0), entry, HUGE_1G), &mut f);
+                    continue;
+                }
+                let p2 = match p3.next_table(i3, memory) {
+                    Some(p2) => p2,
+                    None => continue,
+                };
+
+                for i2 in 0..ENTRY_COUNT {
+                    let entry = &p2[i2];
+                    if entry.target_frame().is_some() && entry.flags().contains(HUGE_PAGE) {
+                        coalesce(&mut current, leaf(virtual_address(i4, i3, i2, 0), entry, HUGE_2M), &mut f);
+                        continue;
+                    }
+                    let p1 = match p2.next_table(i2, memory) {
+                        Some(p1) => p1,
+                        None => continue,
+                    };
+
+                    for i1 in 0..ENTRY_COUNT {
+                        if p1[i1].target_frame().is_some() {
+                            let range = leaf(virtual_address(i4, i3, i2, i1), &p1[i1], PAGE_SIZE);
+                            coalesce(&mut current, range, &mut f);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(last) = current {
+            f(last);
+        }
+    }
+}
+
+fn size_name(page_size: usize) -> &'static str {
+    match page_size {
+        HUGE_1G => "1G",
+        HUGE_2M => "2M",
+        _ => "4K",
+    }
+}
+
+// Prints the mappings of the active page tables, one line per range
+pub fn print_mappings() {
+    println!("{}virtual                               physical          page flags",
+        LIGHT_GRAY);
+
+    P4_TABLE_EXCL.lock().for_each_mapping(|range| {
+        let flag = |flag, name| if range.flags.contains(flag) { name } else { "-" };
+        println!("{}{:016x}-{:016x} {}{:016x} {}{}   {}{}{}{} {}{:?}",
+            CYAN, range.start, range.end(), WHITE, range.phys,
+            LIGHT_GRAY, size_name(range.page_size),
+            flag(WRITEABLE, "W"), flag(USER_ACCESSIBLE, "U"),
+            flag(NO_EXECUTE, "N"), flag(GLOBAL, "G"),
+            DARK_GRAY, range.memory_type());
+    });
+}
@@ -17,6 +17,9 @@ bitflags! {
         const ACCESSED =        1 <<  5,
         const DIRTY =           1 <<  6,
         const HUGE_PAGE =       1 <<  7,
+        // Same bit as HUGE_PAGE, but in P1 entries it selects the upper
+        // half of the page attribute table
+        const PAT =             1 <<  7,
         const GLOBAL =          1 <<  8,
         const NO_EXECUTE =      1 << 63
     }
@@ -0,0 +1,203 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use memory::paging::{Page, PhysicalAddress, VirtualAddress, CacheMode, EntryFlags, P4_TABLE};
+use memory::paging::entry::NO_EXECUTE;
+use control_regs::efer::{self, Efer};
+
+// Device memory is mapped into this window instead of identity mapping
+// it, so it can't collide with RAM mappings. Unmapped ranges are reused.
+pub const MMIO_WINDOW_START: VirtualAddress = 0xffff_e000_0000_0000;
+pub const MMIO_WINDOW_SIZE: usize = 1 << 30;
+
+// Holes the window keeps track of, further unmapped ranges are leaked
+const FREE_RANGES: usize = 32;
+
+static WINDOW: Mutex<Window> = Mutex::new(Window::new(MMIO_WINDOW_START, MMIO_WINDOW_SIZE));
+static MAPPED_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+// Hands out address ranges of a window, first fit from the holes left by
+// freed ranges, then from the never used end
+struct Window {
+    // End of the highest range in use
+    end: VirtualAddress,
+    limit: VirtualAddress,
+    // (start, length) of the holes below `end`, sorted and never adjacent
+    free: [(VirtualAddress, usize); FREE_RANGES],
+    free_count: usize,
+}
+
+impl Window {
+    const fn new(start: VirtualAddress, size: usize) -> Window {
+        Window {
+            end: start,
+            limit: start + size,
+            free: [(0, 0); FREE_RANGES],
+            free_count: 0,
+        }
+    }
+
+    fn alloc(&mut self, len: usize) -> Option<VirtualAddress> {
+        for idx in 0..self.free_count {
+            let (start, free_len) = self.free[idx];
+            if free_len == len {
+                self.remove(idx);
+                return Some(start);
+            } else if free_len > len {
+                self.free[idx] = (start + len, free_len - len);
+                return Some(start);
+            }
+        }
+
+        if self.limit - self.end < len {
+            return None;
+        }
+        self.end += len;
+        Some(self.end - len)
+    }
+
+    fn free(&mut self, mut start: VirtualAddress, mut len: usize) {
+        let mut idx = self.free[..self.free_count]
+            .iter()
+            .position(|&(free_start, _)| free_start > start)
+            .unwrap_or(self.free_count);
+
+        // Merge with the neighbouring holes
+        if idx > 0 && self.free[idx - 1].0 + self.free[idx - 1].1 == start {
+            idx -= 1;
+            start = self.free[idx].0;
+            len += self.free[idx].1;
+            self.remove(idx);
+        }
+        if idx < self.free_count && start + len == self.free[idx].0 {
+            len += self.free[idx].1;
+            self.remove(idx);
+        }
+
+        if start + len == self.end {
+            self.end = start;
+        } else if self.free_count < FREE_RANGES {
+            for i in (idx..self.free_count).rev() {
+                self.free[i + 1] = self.free[i];
+            }
+            self.free[idx] = (start, len);
+            self.free_count += 1;
+        }
+    }
+
+    fn remove(&mut self, idx: usize) {
+        for i in idx..self.free_count - 1 {
+            self.free[i] = self.free[i + 1];
+        }
+        self.free_count -= 1;
+    }
+}
+
+// Maps `len` bytes of device memory at `phys` and returns the virtual
+// address of `phys`
+pub fn map_mmio<A>(phys: PhysicalAddress, len: usize, cache_mode: CacheMode, alloc: &mut A)
+    -> VirtualAddress
+    where A: FrameAllocator
+{
+    assert!(len > 0, "Empty MMIO mapping at {:#x}", phys);
+
+    let first = Frame::for_address(phys);
+    let last = Frame::for_address(phys + len - 1);
+    let pages = last.number - first.number + 1;
+
+    let start = match WINDOW.lock().alloc(pages * PAGE_SIZE) {
+        Some(start) => start,
+        None => panic!("MMIO window exhausted mapping {:#x} bytes at {:#x}", len, phys),
+    };
+
+    let flags = cache_mode.flags() | no_execute();
+    let mut table = P4_TABLE;
+    for i in 0..pages {
+        let page = Page::for_address(start + i * PAGE_SIZE);
+        let frame = Frame::new(first.number + i);
+        table.map_to(&page, frame, flags, alloc);
+    }
+    MAPPED_PAGES.fetch_add(pages, Ordering::SeqCst);
+
+    start + phys % PAGE_SIZE
+}
+
+// Nothing in the window is executable. The bit is reserved while NX is
+// off, e.g. with `nonx`.
+fn no_execute() -> EntryFlags {
+    if Efer::load().contains(efer::EXECUTE_DISABLE_BIT_ENABLED) {
+        NO_EXECUTE
+    } else {
+        EntryFlags::empty()
+    }
+}
+
+// Removes a mapping made by map_mmio. The frames aren't freed.
+pub fn unmap_mmio(virt: VirtualAddress, len: usize) {
+    assert!(virt >= MMIO_WINDOW_START && virt + len <= MMIO_WINDOW_START + MMIO_WINDOW_SIZE,
+            "{:#x} is not in the MMIO window", virt);
+
+    let mut table = P4_TABLE;
+    let first = Page::for_address(virt);
+    let last = Page::for_address(virt + len - 1);
+    for number in first.number..last.number + 1 {
+        table.unmap_frame(&Page { number: number });
+    }
+    let pages = last.number - first.number + 1;
+    WINDOW.lock().free(first.first_addr(), pages * PAGE_SIZE);
+    MAPPED_PAGES.fetch_sub(pages, Ordering::SeqCst);
+}
+
+// Pages currently mapped by map_mmio
+pub fn mmio_mapped_pages() -> usize {
+    MAPPED_PAGES.load(Ordering::SeqCst)
+}
+
+// Bytes of the window up to the end of the highest mapping, holes included
+pub fn mmio_window_used() -> usize {
+    WINDOW.lock().end - MMIO_WINDOW_START
+}
+
+// Maps the register block `T` at `phys`
+pub unsafe fn map_registers<T, A>(phys: PhysicalAddress, cache_mode: CacheMode, alloc: &mut A)
+    -> &'static T
+    where A: FrameAllocator
+{
+    &*(map_mmio(phys, ::core::mem::size_of::<T>(), cache_mode, alloc) as *const T)
+}
+
+
+#[test]
+fn test_window_reuses_freed_ranges() {
+    let mut window = Window::new(0x10000, 0x10000);
+    let a = window.alloc(0x1000).unwrap();
+    let b = window.alloc(0x2000).unwrap();
+    let c = window.alloc(0x1000).unwrap();
+    assert_eq!((a, b, c), (0x10000, 0x11000, 0x13000));
+
+    window.free(b, 0x2000);
+    assert_eq!(window.alloc(0x1000), Some(0x11000));
+    assert_eq!(window.alloc(0x1000), Some(0x12000));
+    assert_eq!(window.free_count, 0);
+    assert_eq!(window.alloc(0x1000), Some(0x14000));
+}
+
+#[test]
+fn test_window_merges_holes() {
+    let mut window = Window::new(0x10000, 0x4000);
+    let pages: Vec<_> = (0..4).map(|_| window.alloc(0x1000).unwrap()).collect();
+    assert_eq!(window.alloc(0x1000), None);
+
+    window.free(pages[0], 0x1000);
+    window.free(pages[2], 0x1000);
+    assert_eq!(window.free_count, 2);
+    window.free(pages[1], 0x1000);
+    assert_eq!(window.free_count, 1);
+    assert_eq!(window.free[0], (0x10000, 0x3000));
+
+    // Freeing the highest range gives everything back
+    window.free(pages[3], 0x1000);
+    assert_eq!(window.free_count, 0);
+    assert_eq!(window.end, 0x10000);
+    assert_eq!(window.alloc(0x4000), Some(0x10000));
+}
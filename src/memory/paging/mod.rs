@@ -1,18 +1,42 @@
 use core::ptr::Unique;
-use memory::FrameAllocator;
-use memory::PAGE_SIZE;
+use memory::{self, FrameAllocator};
+use memory::{PAGE_SIZE, KERNEL_MAPPED_LIMIT};
 use spin::Mutex;
 pub use memory::Frame;
 
 mod entry;
 mod table;
+mod cache;
+mod mmio;
+mod dump;
+#[cfg(test)]
+mod tests;
 
 use self::entry::*;
 use self::table::*;
+pub use self::entry::{EntryFlags, HUGE_PAGE};
+pub use self::cache::{CacheMode, MemoryType, init_pat, pat_index, pat_layout};
+pub use self::mmio::{map_mmio, unmap_mmio, map_registers, mmio_mapped_pages, mmio_window_used,
+                     MMIO_WINDOW_START, MMIO_WINDOW_SIZE};
+pub use self::table::table_frames;
+pub use self::dump::{MappedRange, print_mappings};
 
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
+// Virtual address of the active P4 table through the recursive mapping
+pub const P4_TABLE_ADDRESS: VirtualAddress = P4_TABLE_MASK;
+
+
+// One level of a page table walk
+#[derive(Clone, Copy)]
+pub struct WalkStep {
+    pub level: usize,
+    pub index: usize,
+    pub flags: EntryFlags,
+    // Physical address the entry points to, if present
+    pub target: Option<PhysicalAddress>,
+}
 
 const P4_TABLE: PageTableHead = unsafe { PageTableHead::new() };
 pub const P4_TABLE_EXCL: Mutex<PageTableHead> = Mutex::new(P4_TABLE);
@@ -41,7 +65,9 @@ impl Page {
     fn for_table_indices(p4: usize, p3: usize, p2: usize, p1: usize) -> Page {
         assert!(p4 < ENTRY_COUNT && p3 < ENTRY_COUNT && p2 < ENTRY_COUNT && p1 < ENTRY_COUNT);
 
-        Page { number: (p4 << 27) | (p3 << 18) | (p2 << 9) | p1 }
+        // Addresses in the upper half of the P4 are sign extended
+        let addr = p4 << 39 | p3 << 30 | p2 << 21 | p1 << 12;
+        Page::for_address(if p4 >= ENTRY_COUNT / 2 { addr | 0xffff_0000_0000_0000 } else { addr })
     }
 
     pub fn first_addr(&self) -> VirtualAddress {
@@ -72,13 +98,28 @@ impl Page {
 
 
 
-pub struct PageTableHead {
+pub struct PageTableHead<M: PhysicalMemory = RecursiveMapping> {
     p4: Unique<PageTable<Level4>>,
+    memory: M,
+}
+
+impl PageTableHead<RecursiveMapping> {
+    const unsafe fn new() -> PageTableHead<RecursiveMapping> {
+        PageTableHead {
+            p4: Unique::new(P4_TABLE_MASK as *mut _),
+            memory: RecursiveMapping,
+        }
+    }
 }
 
-impl PageTableHead {
-    const unsafe fn new() -> PageTableHead {
-        PageTableHead { p4: Unique::new(P4_TABLE_MASK as *mut _) }
+impl<M: PhysicalMemory> PageTableHead<M> {
+    // Page tables with the P4 at virtual address `p4`, whose lower levels
+    // are found through `memory`
+    unsafe fn with_memory(p4: usize, memory: M) -> PageTableHead<M> {
+        PageTableHead {
+            p4: Unique::new(p4 as *mut _),
+            memory: memory,
+        }
     }
 
     fn get_p4(&self) -> &PageTable<Level4> {
@@ -96,9 +137,44 @@ impl PageTableHead {
             .map(|frame| frame.number * PAGE_SIZE + offset)
     }
 
+    // Returns the entries used to translate `addr`, from the P4 down to
+    // the first entry that isn't present or maps a huge page
+    pub fn walk(&self, addr: VirtualAddress) -> [Option<WalkStep>; 4] {
+        fn step(level: usize, index: usize, entry: &Entry) -> Option<WalkStep> {
+            Some(WalkStep {
+                level: level,
+                index: index,
+                flags: entry.flags(),
+                target: entry.target_frame().map(|frame| frame.first_addr()),
+            })
+        }
+
+        let page = Page::for_address(addr);
+        let mut steps = [None; 4];
+
+        let memory = &self.memory;
+        let p4 = self.get_p4();
+        steps[0] = step(4, page.p4_index(), &p4[page.p4_index()]);
+
+        if let Some(p3) = p4.next_table(page.p4_index(), memory) {
+            steps[1] = step(3, page.p3_index(), &p3[page.p3_index()]);
+
+            if let Some(p2) = p3.next_table(page.p3_index(), memory) {
+                steps[2] = step(2, page.p2_index(), &p2[page.p2_index()]);
+
+                if let Some(p1) = p2.next_table(page.p2_index(), memory) {
+                    steps[3] = step(1, page.p1_index(), &p1[page.p1_index()]);
+                }
+            }
+        }
+
+        steps
+    }
+
     fn translate_page(&self, page: Page) -> Option<Frame> {
+        let memory = &self.memory;
         let p4 = self.get_p4();
-        let p3 = p4.next_table(page.p4_index());
+        let p3 = p4.next_table(page.p4_index(), memory);
 
         let huge_page = || {
             return p3.and_then(|p3| {
@@ -117,7 +193,7 @@ impl PageTableHead {
                     }
                 }
 
-                if let Some(p2) = p3.next_table(page.p3_index()) {
+                if let Some(p2) = p3.next_table(page.p3_index(), memory) {
                     let p2_entry = &p2[page.p2_index()];
                     if let Some(frame) = p2_entry.target_frame() {
                         if p2_entry.flags().contains(HUGE_PAGE) {
@@ -134,8 +210,8 @@ impl PageTableHead {
             });
         };
 
-        p3.and_then(|p3| p3.next_table(page.p3_index()))
-          .and_then(|p2| p2.next_table(page.p2_index()))
+        p3.and_then(|p3| p3.next_table(page.p3_index(), memory))
+          .and_then(|p2| p2.next_table(page.p2_index(), memory))
           .and_then(|p1| p1[page.p1_index()].target_frame())
           .or_else(huge_page)
     }
@@ -145,10 +221,11 @@ impl PageTableHead {
         where A: FrameAllocator
     {
 
-        let p4 = self.get_p4_mut();
-        let mut p3 = p4.next_table_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_table_create(page.p2_index(), allocator);
+        let memory = &self.memory;
+        let p4 = unsafe { self.p4.get_mut() };
+        let mut p3 = p4.next_table_create(page.p4_index(), allocator, memory);
+        let mut p2 = p3.next_table_create(page.p3_index(), allocator, memory);
+        let mut p1 = p2.next_table_create(page.p2_index(), allocator, memory);
 
         assert!(p1[page.p1_index()].is_unused());
         p1[page.p1_index()].set(frame, flags | PRESENT | WRITEABLE);
@@ -162,67 +239,141 @@ impl PageTableHead {
         self.map_to(page, frame, flags, allocator);
     }
 
-    fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, alloc: &mut A)
+    fn unmap<A>(&mut self, page: Page, alloc: &mut A)
         where A: FrameAllocator
     {
-
-        let page = Page::for_address(frame.first_addr());
-        self.map_to(&page, frame, flags, alloc);
+        let frame = self.unmap_frame(&page);
+        // TODO free p(1,2,3) table if empty
+        alloc.dealloc(frame);
     }
 
-    fn unmap<A>(&mut self, page: Page, alloc: &mut A)
-        where A: FrameAllocator
+    // Sets the cache mode of the 4 KiB page `page`. A 2 MiB page mapping it
+    // is split up using the table returned by `new_table`, as its frame and
+    // an address it can be written at.
+    fn set_cache_mode<F>(&mut self, page: &Page, cache_mode: CacheMode, new_table: F)
+        where F: FnOnce() -> (Frame, VirtualAddress)
     {
+        let memory = &self.memory;
+        let p2 = self.get_p4()
+                     .next_table_mut(page.p4_index(), memory)
+                     .and_then(|p3| p3.next_table_mut(page.p3_index(), memory))
+                     .expect("mapping code does not support 1 GiB pages");
+
+        if p2[page.p2_index()].flags().contains(HUGE_PAGE) {
+            let (frame, addr) = new_table();
+            p2.split_huge_page(page.p2_index(), unsafe { &mut *(addr as *mut _) }, frame);
+            memory.flush_tlb(page.first_addr());
+        }
+
+        let p1 = p2.next_table_mut(page.p2_index(), memory).expect("page not mapped");
+        let entry = &mut p1[page.p1_index()];
+        let frame = entry.target_frame().expect("page not mapped");
+        let flags = entry.flags() - NO_CACHE - WRITE_THROUGH - PAT;
+        entry.set(frame, flags | cache_mode.flags());
+        memory.flush_tlb(page.first_addr());
+    }
+
+    // Removes the mapping of `page` and returns the frame it mapped
+    fn unmap_frame(&mut self, page: &Page) -> Frame {
         assert!(self.translate(page.first_addr()).is_some());
 
-        let p1 = self.get_p4_mut()
-                     .next_table_mut(page.p4_index())
-                     .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                     .and_then(|p2| p2.next_table_mut(page.p2_index()))
+        let memory = &self.memory;
+        let p1 = self.get_p4()
+                     .next_table_mut(page.p4_index(), memory)
+                     .and_then(|p3| p3.next_table_mut(page.p3_index(), memory))
+                     .and_then(|p2| p2.next_table_mut(page.p2_index(), memory))
                      .expect("mapping code does not support huge pages");
         let frame = p1[page.p1_index()].target_frame().unwrap();
         p1[page.p1_index()].set_unused();
-        unsafe {
-            ::x86::shared::tlb::flush(page.first_addr());
-        }
-        // TODO free p(1,2,3) table if empty
-        alloc.dealloc(frame);
+        memory.flush_tlb(page.first_addr());
+        frame
     }
-}
 
-pub fn simple_id_map<A: FrameAllocator>(frame: Frame, alloc: &mut A) {
-    P4_TABLE.identity_map(frame, EntryFlags::empty(), alloc)
-}
+    // The first page below an unused entry of the existing tables, without
+    // adding P4 entries. The recursive mapping and the MMIO window are
+    // skipped.
+    fn free_page(&self) -> Option<Page> {
+        let memory = &self.memory;
+        let p4 = self.get_p4();
+        let mmio_index = Page::for_address(MMIO_WINDOW_START).p4_index();
 
-pub fn alloc_any<A>(alloc: &mut A) -> &'static mut [u8; PAGE_SIZE]
-    where A: FrameAllocator
-{
-    let mutex = P4_TABLE_EXCL;
-    let table_head = mutex.lock();
-    let p4 = table_head.get_p4();
+        for p4_idx in (0..ENTRY_COUNT).filter(|&i| i != RECURSIVE_INDEX && i != mmio_index) {
+            let p3 = match p4.next_table(p4_idx, memory) {
+                Some(p3) => p3,
+                None => continue,
+            };
 
-    for p4_idx in 0..ENTRY_COUNT {
-        if let Some(p3) = p4.next_table(p4_idx) {
             for p3_idx in 0..ENTRY_COUNT {
-                if let Some(p2) = p3.next_table(p3_idx) {
-                    for p2_idx in 0..ENTRY_COUNT {
-                        if let Some(p1) = p2.next_table(p2_idx) {
-                            for p1_idx in 0..ENTRY_COUNT {
-                                if p1[p1_idx].is_unused() {
-                                    let page = Page::for_table_indices(p4_idx,
-                                                                       p3_idx,
-                                                                       p2_idx,
-                                                                       p1_idx);
-                                    P4_TABLE.map(&page, EntryFlags::empty(), alloc);
-                                    return unsafe { &mut *((page.number * PAGE_SIZE) as *mut _) };
-                                }
-                            }
+                if p3[p3_idx].is_unused() {
+                    return Some(Page::for_table_indices(p4_idx, p3_idx, 0, 0));
+                }
+                let p2 = match p3.next_table(p3_idx, memory) {
+                    Some(p2) => p2,
+                    None => continue,
+                };
+
+                for p2_idx in 0..ENTRY_COUNT {
+                    if p2[p2_idx].is_unused() {
+                        return Some(Page::for_table_indices(p4_idx, p3_idx, p2_idx, 0));
+                    }
+                    let p1 = match p2.next_table(p2_idx, memory) {
+                        Some(p1) => p1,
+                        None => continue,
+                    };
+
+                    for p1_idx in 0..ENTRY_COUNT {
+                        if p1[p1_idx].is_unused() {
+                            return Some(Page::for_table_indices(p4_idx, p3_idx, p2_idx, p1_idx));
                         }
                     }
                 }
             }
         }
+
+        None
     }
+}
 
-    panic!("Out of memory");
+// Sets the cache mode of the kernel's mapping of the `count` frames from
+// `first`, which must lie below KERNEL_MAPPED_LIMIT. Memory handed to
+// devices must not stay mapped with a conflicting type there.
+pub fn set_kernel_cache_mode(first: Frame, count: usize, cache_mode: CacheMode) {
+    let mut table = P4_TABLE;
+    for number in first.number..first.number + count {
+        let page = Page::for_address(memory::phys_to_virt(Frame::new(number).first_addr()));
+        table.set_cache_mode(&page, cache_mode, || {
+            // Written through the kernel's mapping before it's linked in
+            let frame = memory::alloc_contiguous(1, 1, Frame::for_address(KERNEL_MAPPED_LIMIT))
+                .expect("Out of frames");
+            (frame, memory::phys_to_virt(frame.first_addr()))
+        });
+    }
+
+    // Writes back lines cached through the old mapping
+    unsafe { asm!("wbinvd" :::: "volatile") };
+}
+
+// True if `addr` is canonical and backed by a present mapping, i.e.
+// accessing it won't page fault
+pub fn is_mapped(addr: VirtualAddress) -> bool {
+    let canonical = addr < 0x0000_8000_0000_0000 || addr >= 0xffff_8000_0000_0000;
+
+    canonical && P4_TABLE_EXCL.lock().translate(addr).is_some()
+}
+
+// Maps a fresh frame at an unused page and returns it
+pub fn alloc_any<A>(alloc: &mut A) -> &'static mut [u8; PAGE_SIZE]
+    where A: FrameAllocator
+{
+    let page = P4_TABLE_EXCL.lock().free_page().expect("Out of memory");
+    P4_TABLE.map(&page, EntryFlags::empty(), alloc);
+    unsafe { &mut *(page.first_addr() as *mut _) }
+}
+
+// Unmaps a page returned by alloc_any and frees its frame. The page must
+// not be used afterwards.
+pub unsafe fn free_any<A>(page: &mut [u8; PAGE_SIZE], alloc: &mut A)
+    where A: FrameAllocator
+{
+    P4_TABLE.unmap(Page::for_address(page.as_ptr() as usize), alloc);
 }
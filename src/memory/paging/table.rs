@@ -1,21 +1,32 @@
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use memory::paging::entry::*;
-use memory::FrameAllocator;
+use memory::{Frame, FrameAllocator};
 
 pub const ENTRY_COUNT: usize = 512; // Entries in a page table
 
-pub const P4_TABLE_MASK: usize = 0o177777_777_777_777_777_0000;
-pub const P3_TABLE_MASK: usize = 0o177777_777_777_777_000_0000;
+// The P4 entry pointing back to the P4 itself. Entry 511 maps the kernel.
+pub const RECURSIVE_INDEX: usize = 510;
+
+pub const P4_TABLE_MASK: usize = 0o177777_776_776_776_776_0000;
+pub const P3_TABLE_MASK: usize = 0o177777_776_776_776_000_0000;
 // ^ index into p4
-pub const P2_TABLE_MASK: usize = 0o177777_777_777_000_000_0000;
+pub const P2_TABLE_MASK: usize = 0o177777_776_776_000_000_0000;
 //                                                ^   ^ index into p3
 //                                                \ index into p4
-pub const P1_TABLE_MASK: usize = 0o177777_777_000_000_000_0000;
+pub const P1_TABLE_MASK: usize = 0o177777_776_000_000_000_0000;
 //                                            ^   ^   ^ index into p2
 //                                            |   \ index into p3
 //                                            \ index into p4
 
+// Frames allocated for page tables, tables set up by the boot code aren't
+// included
+static TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn table_frames() -> usize {
+    TABLE_FRAMES.load(Ordering::SeqCst)
+}
 
 pub trait TableLevel {}
 pub trait HierarchicalLevel: TableLevel {
@@ -73,37 +84,93 @@ impl<Lvl> PageTable<Lvl> where Lvl: TableLevel
     }
 }
 
+impl PageTable<Level2> {
+    // Maps the 2 MiB page of entry `idx` through `table` in `table_frame`
+    // instead. The table is filled before the entry points to it, so the
+    // memory stays mapped throughout.
+    pub fn split_huge_page(&mut self, idx: usize, table: &mut PageTable<Level1>, table_frame: Frame) {
+        let flags = self.entries[idx].flags();
+        assert!(flags.contains(HUGE_PAGE), "P2 entry {} is not a huge page", idx);
+        let first = self.entries[idx].target_frame().unwrap();
+
+        // Bit 7 is the PAT bit in P1 entries. The PAT bit of huge pages
+        // (bit 12) is left clear by the boot code.
+        for i in 0..ENTRY_COUNT {
+            table.entries[i].set(Frame::new(first.number + i), flags - HUGE_PAGE);
+        }
+        TABLE_FRAMES.fetch_add(1, Ordering::SeqCst);
+        self.entries[idx].set(table_frame, PRESENT | WRITEABLE);
+    }
+}
+
 impl<Lvl> PageTable<Lvl> where Lvl: HierarchicalLevel
 {
-    fn next_table_address(&self, idx: usize) -> Option<usize> {
-        let flags = self.entries[idx].flags();
-        if flags.contains(PRESENT) && !flags.contains(HUGE_PAGE) {
-            let table = self as *const _ as usize;
-            Some(table << 9 | idx << 12)
-        } else {
-            None
+    fn next_table_address<M>(&self, idx: usize, memory: &M) -> Option<usize>
+        where M: PhysicalMemory
+    {
+        let entry = &self.entries[idx];
+        if entry.flags().contains(HUGE_PAGE) {
+            return None;
         }
+
+        entry.target_frame()
+             .map(|frame| memory.table_address(self as *const _ as usize, idx, frame))
     }
 
-    pub fn next_table(&self, idx: usize) -> Option<&PageTable<Lvl::Next>> {
-        self.next_table_address(idx).map(|addr| unsafe { &*(addr as *const _) })
+    pub fn next_table<M>(&self, idx: usize, memory: &M) -> Option<&PageTable<Lvl::Next>>
+        where M: PhysicalMemory
+    {
+        self.next_table_address(idx, memory).map(|addr| unsafe { &*(addr as *const _) })
     }
 
-    pub fn next_table_mut(&self, idx: usize) -> Option<&mut PageTable<Lvl::Next>> {
-        self.next_table_address(idx).map(|addr| unsafe { &mut *(addr as *mut _) })
+    pub fn next_table_mut<M>(&self, idx: usize, memory: &M) -> Option<&mut PageTable<Lvl::Next>>
+        where M: PhysicalMemory
+    {
+        self.next_table_address(idx, memory).map(|addr| unsafe { &mut *(addr as *mut _) })
     }
 
-    pub fn next_table_create<A>(&mut self, idx: usize, alloc: &mut A) -> &mut PageTable<Lvl::Next>
-        where A: FrameAllocator
+    pub fn next_table_create<A, M>(&mut self, idx: usize, alloc: &mut A, memory: &M)
+        -> &mut PageTable<Lvl::Next>
+        where A: FrameAllocator, M: PhysicalMemory
     {
 
-        if self.next_table(idx).is_none() {
+        if self.next_table(idx, memory).is_none() {
             assert!(!self.entries[idx].flags().contains(HUGE_PAGE));
             let frame = alloc.alloc().expect("Out of frames");
+            TABLE_FRAMES.fetch_add(1, Ordering::SeqCst);
             self.entries[idx].set(frame, PRESENT | WRITEABLE);
-            self.next_table_mut(idx).unwrap().zero();
+            self.next_table_mut(idx, memory).unwrap().zero();
         }
 
-        self.next_table_mut(idx).unwrap()
+        self.next_table_mut(idx, memory).unwrap()
+    }
+}
+
+
+
+// Access to page tables in physical memory
+pub trait PhysicalMemory {
+    // Virtual address of the table in `frame`, which is referenced by entry
+    // `idx` of the table at virtual address `parent`
+    fn table_address(&self, parent: usize, idx: usize, frame: Frame) -> usize;
+
+    // Called after an existing mapping of `addr` was changed
+    fn flush_tlb(&self, addr: usize);
+}
+
+// The active page tables, reached through the recursive P4 entry
+pub struct RecursiveMapping;
+
+impl PhysicalMemory for RecursiveMapping {
+    fn table_address(&self, parent: usize, idx: usize, _frame: Frame) -> usize {
+        // The recursive index ends up in bit 47 and has to be sign extended
+        let addr = (parent << 9 | idx << 12) & 0x0000_ffff_ffff_ffff;
+        if addr & 1 << 47 != 0 { addr | 0xffff_0000_0000_0000 } else { addr }
+    }
+
+    fn flush_tlb(&self, addr: usize) {
+        unsafe {
+            ::x86::shared::tlb::flush(addr);
+        }
     }
 }
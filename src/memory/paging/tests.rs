@@ -0,0 +1,357 @@
+use std::cell::Cell;
+use memory::{Frame, FrameAllocator};
+use super::{Page, PageTableHead, MappedRange, CacheMode, pat_index};
+use super::entry::{EntryFlags, PRESENT, WRITEABLE, HUGE_PAGE, NO_EXECUTE};
+use super::table::{PhysicalMemory, RecursiveMapping, ENTRY_COUNT, P4_TABLE_MASK};
+
+const RAM_FRAMES: usize = 64;
+
+// Page tables in a heap buffer standing in for physical memory. Physical
+// address 0 is the start of the buffer and the P4 lives in frame 0.
+struct SimulatedMemory {
+    ram: Vec<u64>,
+    flushes: Cell<usize>,
+}
+
+impl SimulatedMemory {
+    fn new() -> SimulatedMemory {
+        SimulatedMemory {
+            ram: vec![0; RAM_FRAMES * ENTRY_COUNT],
+            flushes: Cell::new(0),
+        }
+    }
+}
+
+impl PhysicalMemory for SimulatedMemory {
+    fn table_address(&self, _parent: usize, _idx: usize, frame: Frame) -> usize {
+        assert!(frame.number < RAM_FRAMES, "table outside simulated RAM: {:?}", frame);
+        self.ram.as_ptr() as usize + frame.first_addr()
+    }
+
+    fn flush_tlb(&self, _addr: usize) {
+        self.flushes.set(self.flushes.get() + 1);
+    }
+}
+
+// Hands out the frames after the P4 in order and remembers what was freed
+struct MockAllocator {
+    next: usize,
+    freed: Vec<Frame>,
+}
+
+impl MockAllocator {
+    fn new() -> MockAllocator {
+        MockAllocator { next: 1, freed: Vec::new() }
+    }
+
+    fn allocated(&self) -> usize {
+        self.next - 1
+    }
+}
+
+impl FrameAllocator for MockAllocator {
+    fn alloc(&mut self) -> Option<Frame> {
+        if self.next == RAM_FRAMES {
+            return None;
+        }
+
+        self.next += 1;
+        Some(Frame::new(self.next - 1))
+    }
+
+    fn dealloc(&mut self, frame: Frame) {
+        self.freed.push(frame);
+    }
+}
+
+fn simulated_tables() -> PageTableHead<SimulatedMemory> {
+    let memory = SimulatedMemory::new();
+    let p4 = memory.ram.as_ptr() as usize;
+    unsafe { PageTableHead::with_memory(p4, memory) }
+}
+
+// Maps `addr` with a huge page by writing the entry at `level` directly,
+// creating the tables above it
+fn map_huge(tables: &mut PageTableHead<SimulatedMemory>, level: usize, addr: usize, frame: Frame,
+            alloc: &mut MockAllocator) {
+    let page = Page::for_address(addr);
+    let memory = &tables.memory;
+    let p4 = unsafe { tables.p4.get_mut() };
+    let p3 = p4.next_table_create(page.p4_index(), alloc, memory);
+
+    if level == 3 {
+        p3[page.p3_index()].set(frame, PRESENT | WRITEABLE | HUGE_PAGE);
+    } else {
+        let p2 = p3.next_table_create(page.p3_index(), alloc, memory);
+        p2[page.p2_index()].set(frame, PRESENT | WRITEABLE | HUGE_PAGE);
+    }
+}
+
+#[test]
+fn test_translate_unmapped() {
+    let tables = simulated_tables();
+
+    assert_eq!(tables.translate(0), None);
+    assert_eq!(tables.translate(0xdead_beef), None);
+    assert_eq!(tables.translate(0xffff_8000_0000_0000), None);
+}
+
+#[test]
+fn test_map_translate() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    let page = Page::for_address(0x4020_3000);
+    tables.map_to(&page, Frame::new(0x1234), EntryFlags::empty(), &mut alloc);
+
+    // One table per level below the P4
+    assert_eq!(alloc.allocated(), 3);
+    assert_eq!(tables.translate(0x4020_3000), Some(0x1234000));
+    assert_eq!(tables.translate(0x4020_3abc), Some(0x1234abc));
+    assert_eq!(tables.translate(0x4020_4000), None);
+    assert_eq!(tables.translate(0x4020_2fff), None);
+}
+
+#[test]
+fn test_map_shares_tables() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    tables.map_to(&Page::for_address(0x1000), Frame::new(0x10), EntryFlags::empty(), &mut alloc);
+    tables.map_to(&Page::for_address(0x2000), Frame::new(0x20), EntryFlags::empty(), &mut alloc);
+    assert_eq!(alloc.allocated(), 3);
+
+    // A different P2 entry needs a new P1
+    tables.map_to(&Page::for_address(0x20_0000), Frame::new(0x30), EntryFlags::empty(), &mut alloc);
+    assert_eq!(alloc.allocated(), 4);
+
+    assert_eq!(tables.translate(0x1000), Some(0x10000));
+    assert_eq!(tables.translate(0x2000), Some(0x20000));
+    assert_eq!(tables.translate(0x20_0000), Some(0x30000));
+}
+
+#[test]
+fn test_map_allocates_frame() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    tables.map(&Page::for_address(0x7000), EntryFlags::empty(), &mut alloc);
+
+    // The target is taken before the tables
+    assert_eq!(tables.translate(0x7000), Some(Frame::new(1).first_addr()));
+}
+
+#[test]
+fn test_unmap() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    tables.map_to(&Page::for_address(0x5000), Frame::new(0x42), EntryFlags::empty(), &mut alloc);
+    tables.unmap(Page::for_address(0x5000), &mut alloc);
+
+    assert_eq!(tables.translate(0x5000), None);
+    assert_eq!(alloc.freed, vec![Frame::new(0x42)]);
+    assert_eq!(tables.memory.flushes.get(), 1);
+
+    // The tables are kept and reused
+    tables.map_to(&Page::for_address(0x5000), Frame::new(0x43), EntryFlags::empty(), &mut alloc);
+    assert_eq!(alloc.allocated(), 3);
+    assert_eq!(tables.translate(0x5000), Some(0x43000));
+}
+
+#[test]
+#[should_panic]
+fn test_unmap_unmapped() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    tables.unmap(Page::for_address(0x5000), &mut alloc);
+}
+
+#[test]
+#[should_panic]
+fn test_map_twice() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    tables.map_to(&Page::for_address(0x5000), Frame::new(0x42), EntryFlags::empty(), &mut alloc);
+    tables.map_to(&Page::for_address(0x5000), Frame::new(0x43), EntryFlags::empty(), &mut alloc);
+}
+
+#[test]
+#[should_panic]
+fn test_non_canonical() {
+    simulated_tables().translate(0x0000_8000_0000_0000);
+}
+
+#[test]
+fn test_translate_2mib_page() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    map_huge(&mut tables, 2, 0x4000_0000, Frame::for_address(0x20_0000), &mut alloc);
+
+    assert_eq!(tables.translate(0x4000_0000), Some(0x20_0000));
+    assert_eq!(tables.translate(0x4012_3456), Some(0x32_3456));
+    assert_eq!(tables.translate(0x401f_ffff), Some(0x3f_ffff));
+    assert_eq!(tables.translate(0x4020_0000), None);
+}
+
+#[test]
+fn test_translate_1gib_page() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    map_huge(&mut tables, 3, 0xffff_8000_0000_0000, Frame::for_address(0x4000_0000), &mut alloc);
+
+    assert_eq!(tables.translate(0xffff_8000_0000_0000), Some(0x4000_0000));
+    assert_eq!(tables.translate(0xffff_8000_1234_5678), Some(0x5234_5678));
+    assert_eq!(tables.translate(0xffff_8000_4000_0000), None);
+}
+
+#[test]
+#[should_panic]
+fn test_translate_misaligned_huge_page() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    map_huge(&mut tables, 2, 0x4000_0000, Frame::new(0x201), &mut alloc);
+    tables.translate(0x4000_0000);
+}
+
+#[test]
+#[should_panic]
+fn test_map_into_huge_page() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    map_huge(&mut tables, 2, 0x4000_0000, Frame::for_address(0x20_0000), &mut alloc);
+    tables.map_to(&Page::for_address(0x4000_1000), Frame::new(0x42), EntryFlags::empty(), &mut alloc);
+}
+
+#[test]
+fn test_walk() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    tables.map_to(&Page::for_address(0x4020_3000), Frame::new(0x1234), NO_EXECUTE, &mut alloc);
+
+    let steps = tables.walk(0x4020_3000);
+    let levels: Vec<_> = steps.iter().map(|step| step.unwrap().level).collect();
+    let indices: Vec<_> = steps.iter().map(|step| step.unwrap().index).collect();
+    assert_eq!(levels, vec![4, 3, 2, 1]);
+    assert_eq!(indices, vec![0, 1, 1, 3]);
+
+    let p1_entry = steps[3].unwrap();
+    assert_eq!(p1_entry.target, Some(0x1234000));
+    assert!(p1_entry.flags.contains(PRESENT | WRITEABLE | NO_EXECUTE));
+}
+
+#[test]
+fn test_walk_stops() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    map_huge(&mut tables, 2, 0x4000_0000, Frame::for_address(0x20_0000), &mut alloc);
+
+    let steps = tables.walk(0x4000_1000);
+    assert!(steps[2].unwrap().flags.contains(HUGE_PAGE));
+    assert_eq!(steps[2].unwrap().target, Some(0x20_0000));
+    assert!(steps[3].is_none());
+
+    let steps = tables.walk(0x8000_0000_0000 - 1);
+    assert!(steps[0].unwrap().target.is_none());
+    assert!(steps[1].is_none());
+}
+
+#[test]
+fn test_set_cache_mode_splits_huge_page() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    map_huge(&mut tables, 2, 0x4000_0000, Frame::for_address(0x20_0000), &mut alloc);
+    let table = alloc.alloc().unwrap();
+    let table_addr = tables.memory.table_address(0, 0, table);
+    tables.set_cache_mode(&Page::for_address(0x4000_3000), CacheMode::Uncached, || (table, table_addr));
+
+    // The rest of the 2 MiB keeps its mapping and memory type
+    assert_eq!(tables.translate(0x4000_3000), Some(0x20_3000));
+    assert_eq!(tables.translate(0x401f_f000), Some(0x3f_f000));
+    let step = tables.walk(0x4000_3000)[3].unwrap();
+    assert_eq!(pat_index(step.flags), pat_index(CacheMode::Uncached.flags()));
+    let step = tables.walk(0x4000_4000)[3].unwrap();
+    assert_eq!(pat_index(step.flags), 0);
+    assert!(step.flags.contains(PRESENT | WRITEABLE));
+
+    tables.set_cache_mode(&Page::for_address(0x4000_3000), CacheMode::WriteBack, || unreachable!());
+    assert_eq!(pat_index(tables.walk(0x4000_3000)[3].unwrap().flags), 0);
+}
+
+#[test]
+fn test_for_each_mapping() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+
+    tables.map_to(&Page::for_address(0x1000), Frame::new(0x100), EntryFlags::empty(), &mut alloc);
+    tables.map_to(&Page::for_address(0x2000), Frame::new(0x101), EntryFlags::empty(), &mut alloc);
+    // Not contiguous in physical memory
+    tables.map_to(&Page::for_address(0x3000), Frame::new(0x200), EntryFlags::empty(), &mut alloc);
+    // Different flags
+    tables.map_to(&Page::for_address(0x4000), Frame::new(0x201), NO_EXECUTE, &mut alloc);
+    map_huge(&mut tables, 2, 0x4000_0000, Frame::new(0x400), &mut alloc);
+    tables.map_to(&Page::for_address(0xffff_8000_0000_0000), Frame::new(0x300), EntryFlags::empty(),
+                  &mut alloc);
+
+    let mut ranges = Vec::new();
+    tables.for_each_mapping(|range| ranges.push(range));
+
+    let range = |start, len, phys, page_size, flags| MappedRange {
+        start: start,
+        len: len,
+        phys: phys,
+        page_size: page_size,
+        flags: flags,
+    };
+    assert_eq!(ranges, vec![
+        range(0x1000, 0x2000, 0x100000, 0x1000, PRESENT | WRITEABLE),
+        range(0x3000, 0x1000, 0x200000, 0x1000, PRESENT | WRITEABLE),
+        range(0x4000, 0x1000, 0x201000, 0x1000, PRESENT | WRITEABLE | NO_EXECUTE),
+        range(0x4000_0000, 0x20_0000, 0x400000, 0x20_0000, PRESENT | WRITEABLE),
+        range(0xffff_8000_0000_0000, 0x1000, 0x300000, 0x1000, PRESENT | WRITEABLE),
+    ]);
+}
+
+#[test]
+fn test_free_page() {
+    let mut tables = simulated_tables();
+    let mut alloc = MockAllocator::new();
+    assert!(tables.free_page().is_none());
+
+    // The recursive entry and the MMIO window are never handed out
+    tables.map_to(&Page::for_address(0xffff_ff00_0000_0000), Frame::new(0x100), EntryFlags::empty(),
+                  &mut alloc);
+    tables.map_to(&Page::for_address(0xffff_e000_0000_0000), Frame::new(0x101), EntryFlags::empty(),
+                  &mut alloc);
+    assert!(tables.free_page().is_none());
+
+    tables.map_to(&Page::for_address(0xffff_ff80_0000_0000), Frame::new(0x102), EntryFlags::empty(),
+                  &mut alloc);
+    assert_eq!(tables.free_page().unwrap().first_addr(), 0xffff_ff80_0000_1000);
+
+    // Unused P3 and P2 entries count as well
+    map_huge(&mut tables, 3, 0xffff_8000_0000_0000, Frame::for_address(0x4000_0000), &mut alloc);
+    assert_eq!(tables.free_page().unwrap().first_addr(), 0xffff_8000_4000_0000);
+    map_huge(&mut tables, 2, 0, Frame::for_address(0x20_0000), &mut alloc);
+    assert_eq!(tables.free_page().unwrap().first_addr(), 0x20_0000);
+}
+
+#[test]
+fn test_recursive_table_address() {
+    let memory = RecursiveMapping;
+    let p3 = memory.table_address(P4_TABLE_MASK, 3, Frame::new(0));
+    let p2 = memory.table_address(p3, 5, Frame::new(0));
+
+    assert_eq!(P4_TABLE_MASK, 0xffff_ff7f_bfdf_e000);
+    assert_eq!(p3, 0o177777_776_776_776_003_0000);
+    assert_eq!(p2, 0o177777_776_776_003_005_0000);
+}
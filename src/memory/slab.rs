@@ -0,0 +1,517 @@
+// Object caches for small fixed-size kernel objects. Each cache carves
+// page sized slabs into equal objects and keeps the free ones in a list
+// threaded through the objects themselves. The slab header sits at the
+// start of its page, so the slab of an object is found by rounding down.
+
+use core::{cmp, mem, ptr};
+use spin::Mutex;
+use memory::{self, Frame, PAGE_SIZE, KERNEL_MAPPED_LIMIT};
+use memory::paging::VirtualAddress;
+
+// Written over freed objects, except for the free list link
+const POISON_FREE: u8 = 0x6b;
+
+// Object sizes of the general purpose caches. Larger objects would leave
+// most of a slab's page unused next to the header.
+const GENERAL_SIZES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+// Source of the pages slabs live in
+pub trait SlabPages {
+    fn alloc_page(&mut self) -> Option<VirtualAddress>;
+    fn free_page(&mut self, page: VirtualAddress);
+}
+
+// Frames from the global frame allocator, taken from the low memory the
+// kernel has mapped so they don't need to be mapped
+pub struct FramePages;
+
+impl SlabPages for FramePages {
+    fn alloc_page(&mut self) -> Option<VirtualAddress> {
+        memory::alloc_contiguous(1, 1, Frame::for_address(KERNEL_MAPPED_LIMIT))
+            .map(|frame| memory::phys_to_virt(frame.first_addr()))
+    }
+
+    fn free_page(&mut self, page: VirtualAddress) {
+        memory::dealloc_contiguous(Frame::for_address(memory::virt_to_phys(page)), 1);
+    }
+}
+
+#[repr(C)]
+struct Slab {
+    // Address of the owning cache, to catch objects freed to the wrong one
+    cache: usize,
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+impl Slab {
+    unsafe fn is_free(&self, object: *mut u8) -> bool {
+        let mut free = self.free;
+        while !free.is_null() {
+            if free as *mut u8 == object {
+                return true;
+            }
+            free = (*free).next;
+        }
+        false
+    }
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+// Slabs point back to their cache, so it must not move once it has any
+pub struct Cache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    // Runs on every allocated object before it's returned
+    constructor: Option<fn(*mut u8)>,
+    poison: bool,
+    // Slabs with at least one free object, and completely used ones
+    partial: *mut Slab,
+    full: *mut Slab,
+    stats: CacheStats,
+}
+
+// The slabs are only reached through the cache
+unsafe impl Send for Cache {}
+
+impl Cache {
+    // Objects of `size` bytes aligned to `align` (a power of two). With
+    // `poison`, freed objects are filled with a pattern that is checked on
+    // the next allocation to catch writes after free, and frees are checked
+    // against the free list to catch double frees.
+    pub const fn new(name: &'static str,
+                     size: usize,
+                     align: usize,
+                     constructor: Option<fn(*mut u8)>,
+                     poison: bool)
+                     -> Cache {
+        Cache {
+            name: name,
+            size: size,
+            align: align,
+            constructor: constructor,
+            poison: poison,
+            partial: 0 as *mut _,
+            full: 0 as *mut _,
+            stats: CacheStats {
+                object_size: 0,
+                objects_per_slab: 0,
+                slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            object_size: self.object_size(),
+            objects_per_slab: self.objects_per_slab(),
+            ..self.stats
+        }
+    }
+
+    // Objects hold the free list link while free
+    fn object_align(&self) -> usize {
+        cmp::max(self.align, mem::align_of::<FreeObject>())
+    }
+
+    fn object_size(&self) -> usize {
+        let size = cmp::max(self.size, mem::size_of::<FreeObject>());
+        align_up(size, self.object_align())
+    }
+
+    fn first_object_offset(&self) -> usize {
+        align_up(mem::size_of::<Slab>(), self.object_align())
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        (PAGE_SIZE - self.first_object_offset()) / self.object_size()
+    }
+
+    pub fn alloc<P: SlabPages>(&mut self, pages: &mut P) -> Option<*mut u8> {
+        assert!(self.align.is_power_of_two() && self.objects_per_slab() > 0,
+                "Invalid object layout for cache {}", self.name);
+
+        if self.partial.is_null() {
+            let slab = match pages.alloc_page() {
+                Some(page) => self.init_slab(page),
+                None => return None,
+            };
+            self.partial = slab;
+        }
+
+        unsafe {
+            let slab = &mut *self.partial;
+            let object = slab.free;
+            slab.free = (*object).next;
+            slab.in_use += 1;
+
+            if slab.free.is_null() {
+                self.partial = slab.next;
+                slab.next = self.full;
+                self.full = slab;
+            }
+
+            let object = object as *mut u8;
+            if self.poison {
+                self.check_poison(object);
+            }
+            if let Some(constructor) = self.constructor {
+                constructor(object);
+            }
+
+            self.stats.allocations += 1;
+            self.stats.objects_in_use += 1;
+            Some(object)
+        }
+    }
+
+    // Frees an object allocated from this cache. A slab without any
+    // objects in use is returned to `pages`.
+    pub unsafe fn free<P: SlabPages>(&mut self, object: *mut u8, pages: &mut P) {
+        let slab_ptr = (object as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+        let slab = &mut *slab_ptr;
+        assert!(slab.cache == self as *const _ as usize,
+                "{:#x} was not allocated from cache {}", object as usize, self.name);
+        assert!((object as usize - slab_ptr as usize - self.first_object_offset())
+                % self.object_size() == 0,
+                "{:#x} is not an object of cache {}", object as usize, self.name);
+
+        if self.poison {
+            if slab.is_free(object) {
+                panic!("Double free of {:#x} in cache {}", object as usize, self.name);
+            }
+            ptr::write_bytes(object, POISON_FREE, self.object_size());
+        }
+
+        let was_full = slab.free.is_null();
+        let free_object = object as *mut FreeObject;
+        (*free_object).next = slab.free;
+        slab.free = free_object;
+        slab.in_use -= 1;
+
+        self.stats.frees += 1;
+        self.stats.objects_in_use -= 1;
+
+        if was_full {
+            self.unlink(slab_ptr, true);
+            slab.next = self.partial;
+            self.partial = slab_ptr;
+        }
+
+        if slab.in_use == 0 {
+            self.unlink(slab_ptr, false);
+            self.stats.slabs -= 1;
+            pages.free_page(slab_ptr as VirtualAddress);
+        }
+    }
+
+    fn init_slab(&mut self, page: VirtualAddress) -> *mut Slab {
+        let size = self.object_size();
+        let first = page + self.first_object_offset();
+
+        unsafe {
+            let mut free = ptr::null_mut();
+            for idx in (0..self.objects_per_slab()).rev() {
+                let object = (first + idx * size) as *mut FreeObject;
+                if self.poison {
+                    ptr::write_bytes(object as *mut u8, POISON_FREE, size);
+                }
+                (*object).next = free;
+                free = object;
+            }
+
+            let slab = page as *mut Slab;
+            ptr::write(slab, Slab {
+                cache: self as *const _ as usize,
+                next: ptr::null_mut(),
+                free: free,
+                in_use: 0,
+            });
+
+            self.stats.slabs += 1;
+            slab
+        }
+    }
+
+    // Removes `slab` from the full or the partial list
+    unsafe fn unlink(&mut self, slab: *mut Slab, from_full: bool) {
+        let mut link: *mut *mut Slab = if from_full { &mut self.full } else { &mut self.partial };
+
+        while !(*link).is_null() {
+            if *link == slab {
+                *link = (*slab).next;
+                return;
+            }
+            link = &mut (**link).next;
+        }
+    }
+
+    // The free list link is the only part of a free object that changes
+    unsafe fn is_poisoned(&self, object: *mut u8) -> bool {
+        let start = mem::size_of::<FreeObject>();
+        (start..self.object_size()).all(|offset| *object.offset(offset as isize) == POISON_FREE)
+    }
+
+    unsafe fn check_poison(&self, object: *mut u8) {
+        if !self.is_poisoned(object) {
+            panic!("Object {:#x} of cache {} was written to after it was freed",
+                   object as usize, self.name);
+        }
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+static GENERAL_CACHES: [Mutex<Cache>; 7] = [
+    Mutex::new(Cache::new("size-16", 16, 16, None, cfg!(debug_assertions))),
+    Mutex::new(Cache::new("size-32", 32, 32, None, cfg!(debug_assertions))),
+    Mutex::new(Cache::new("size-64", 64, 64, None, cfg!(debug_assertions))),
+    Mutex::new(Cache::new("size-128", 128, 128, None, cfg!(debug_assertions))),
+    Mutex::new(Cache::new("size-256", 256, 256, None, cfg!(debug_assertions))),
+    Mutex::new(Cache::new("size-512", 512, 512, None, cfg!(debug_assertions))),
+    Mutex::new(Cache::new("size-1024", 1024, 1024, None, cfg!(debug_assertions))),
+];
+
+fn general_cache(size: usize) -> Option<&'static Mutex<Cache>> {
+    GENERAL_SIZES.iter()
+                 .position(|&cache_size| size <= cache_size)
+                 .map(|idx| &GENERAL_CACHES[idx])
+}
+
+// Allocates `size` bytes from the smallest general cache they fit in,
+// aligned to the object size. Fails for more than 1 KiB, larger objects
+// should get whole frames.
+pub fn alloc(size: usize) -> Option<*mut u8> {
+    general_cache(size).and_then(|cache| cache.lock().alloc(&mut FramePages))
+}
+
+// Frees memory from alloc(), `size` must match the allocation
+pub unsafe fn free(object: *mut u8, size: usize) {
+    general_cache(size).expect("Object too large for the slab caches")
+                       .lock()
+                       .free(object, &mut FramePages)
+}
+
+pub fn general_cache_stats() -> [(&'static str, CacheStats); 7] {
+    let mut stats = [("", CacheStats::default()); 7];
+    for (entry, cache) in stats.iter_mut().zip(GENERAL_CACHES.iter()) {
+        let cache = cache.lock();
+        *entry = (cache.name(), cache.stats());
+    }
+    stats
+}
+
+// Bytes in use by objects and bytes of the slabs holding them
+pub fn heap_usage() -> (usize, usize) {
+    general_cache_stats().iter().fold((0, 0), |(used, total), &(_, stats)| {
+        (used + stats.objects_in_use * stats.object_size, total + stats.slabs * PAGE_SIZE)
+    })
+}
+
+pub fn print_stats() {
+    println!("{}cache        size  per slab  slabs  in use   allocs    frees", LIGHT_GRAY);
+    for &(name, stats) in general_cache_stats().iter() {
+        println!("{}{:10} {:6} {:9} {:6} {:7} {:8} {:8}",
+            CYAN, name, stats.object_size, stats.objects_per_slab, stats.slabs,
+            stats.objects_in_use, stats.allocations, stats.frees);
+    }
+}
+
+
+#[cfg(test)]
+struct TestPages {
+    _memory: Vec<u8>,
+    free: Vec<VirtualAddress>,
+    allocated: usize,
+}
+
+#[cfg(test)]
+impl TestPages {
+    fn new(count: usize) -> TestPages {
+        let memory = vec![0; (count + 1) * PAGE_SIZE];
+        let first = align_up(memory.as_ptr() as usize, PAGE_SIZE);
+        let free = (0..count).rev().map(|idx| first + idx * PAGE_SIZE).collect();
+        TestPages { _memory: memory, free: free, allocated: 0 }
+    }
+}
+
+#[cfg(test)]
+impl SlabPages for TestPages {
+    fn alloc_page(&mut self) -> Option<VirtualAddress> {
+        let page = self.free.pop();
+        if page.is_some() {
+            self.allocated += 1;
+        }
+        page
+    }
+
+    fn free_page(&mut self, page: VirtualAddress) {
+        self.allocated -= 1;
+        self.free.push(page);
+    }
+}
+
+#[test]
+fn test_alloc_free() {
+    let mut pages = TestPages::new(4);
+    let mut cache = Cache::new("test", 100, 8, None, false);
+
+    let a = cache.alloc(&mut pages).unwrap();
+    let b = cache.alloc(&mut pages).unwrap();
+    assert_eq!(b as usize - a as usize, 104);
+    assert_eq!(a as usize % PAGE_SIZE, cache.first_object_offset());
+    assert_eq!(pages.allocated, 1);
+
+    unsafe {
+        cache.free(a, &mut pages);
+        // Freed objects are reused first
+        assert_eq!(cache.alloc(&mut pages), Some(a));
+        cache.free(a, &mut pages);
+        cache.free(b, &mut pages);
+    }
+    assert_eq!(pages.allocated, 0);
+
+    let stats = cache.stats();
+    assert_eq!(stats.allocations, 3);
+    assert_eq!(stats.frees, 3);
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(stats.slabs, 0);
+}
+
+#[test]
+fn test_slabs_fill_up() {
+    let mut pages = TestPages::new(4);
+    let mut cache = Cache::new("test", 512, 512, None, false);
+    let per_slab = cache.stats().objects_per_slab;
+    assert_eq!(per_slab, 7);
+
+    let objects: Vec<_> = (0..per_slab + 1).map(|_| cache.alloc(&mut pages).unwrap()).collect();
+    assert_eq!(cache.stats().slabs, 2);
+    assert!(objects.iter().all(|&object| object as usize % 512 == 0));
+
+    // Freeing from a full slab makes it usable again
+    unsafe { cache.free(objects[3], &mut pages) };
+    assert_eq!(cache.alloc(&mut pages), Some(objects[3]));
+    assert_eq!(pages.allocated, 2);
+}
+
+#[test]
+fn test_out_of_pages() {
+    let mut pages = TestPages::new(1);
+    let mut cache = Cache::new("test", 2048, 8, None, false);
+
+    assert!(cache.alloc(&mut pages).is_some());
+    assert_eq!(cache.alloc(&mut pages), None);
+}
+
+#[test]
+fn test_constructor() {
+    fn construct(object: *mut u8) {
+        unsafe { *(object as *mut u64) = 0x1234 };
+    }
+
+    let mut pages = TestPages::new(1);
+    let mut cache = Cache::new("test", 8, 8, Some(construct), true);
+
+    let object = cache.alloc(&mut pages).unwrap();
+    assert_eq!(unsafe { *(object as *mut u64) }, 0x1234);
+}
+
+#[test]
+fn test_poison() {
+    let mut pages = TestPages::new(1);
+    let mut cache = Cache::new("test", 32, 8, None, true);
+
+    let object = cache.alloc(&mut pages).unwrap();
+    unsafe {
+        cache.free(object, &mut pages);
+        assert!(cache.is_poisoned(object));
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_use_after_free() {
+    let mut pages = TestPages::new(1);
+    let mut cache = Cache::new("test", 32, 8, None, true);
+
+    let object = cache.alloc(&mut pages).unwrap();
+    let other = cache.alloc(&mut pages).unwrap();
+    unsafe {
+        cache.free(object, &mut pages);
+        *object.offset(16) = 1;
+    }
+    // Keeps the slab alive
+    let _ = other;
+    cache.alloc(&mut pages);
+}
+
+#[test]
+#[should_panic]
+fn test_double_free() {
+    let mut pages = TestPages::new(1);
+    let mut cache = Cache::new("test", 32, 8, None, true);
+
+    let object = cache.alloc(&mut pages).unwrap();
+    let _other = cache.alloc(&mut pages).unwrap();
+    unsafe {
+        cache.free(object, &mut pages);
+        cache.free(object, &mut pages);
+    }
+}
+
+#[test]
+fn test_free_poison_pattern() {
+    let mut pages = TestPages::new(1);
+    let mut cache = Cache::new("test", 32, 8, None, true);
+
+    // Live objects may hold the poison pattern
+    let object = cache.alloc(&mut pages).unwrap();
+    let _other = cache.alloc(&mut pages).unwrap();
+    unsafe {
+        ptr::write_bytes(object, POISON_FREE, 32);
+        cache.free(object, &mut pages);
+    }
+    assert_eq!(cache.stats().objects_in_use, 1);
+}
+
+#[test]
+fn test_alloc_too_large() {
+    assert_eq!(alloc(2048), None);
+    assert!(general_cache(1024).is_some());
+}
+
+#[test]
+#[should_panic]
+fn test_free_to_wrong_cache() {
+    let mut pages = TestPages::new(2);
+    let mut first = Cache::new("first", 32, 8, None, false);
+    let mut second = Cache::new("second", 32, 8, None, false);
+
+    let object = first.alloc(&mut pages).unwrap();
+    unsafe { second.free(object, &mut pages) };
+}
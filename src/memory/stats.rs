@@ -0,0 +1,127 @@
+// Memory usage, gathered from the frame allocator, the page tables, the
+// slab caches and the MMIO window for `meminfo` and the boot log
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use multiboot2::MemoryMapTag;
+use memory::{self, slab, PAGE_SIZE};
+use memory::paging;
+
+// Layout of the memory map multiboot tag: type, size, entry size, entry
+// version, followed by the entries
+const TAG_SIZE_OFFSET: usize = 4;
+const TAG_ENTRY_SIZE_OFFSET: usize = 8;
+const TAG_ENTRIES_OFFSET: usize = 16;
+
+// Memory map entry types, anything else is reserved
+const AVAILABLE: u32 = 1;
+const ACPI_RECLAIMABLE: u32 = 3;
+const ACPI_NVS: u32 = 4;
+
+#[repr(C)]
+struct MemoryMapEntry {
+    base: u64,
+    length: u64,
+    typ: u32,
+    _reserved: u32,
+}
+
+// Sums over all entries of the multiboot memory map. The multiboot2 crate
+// only hands out the available ones, so the tag is read directly.
+static TOTAL_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static USABLE_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static RESERVED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static ACPI_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryInfo {
+    pub total: usize,
+    pub usable: usize,
+    pub reserved: usize,
+    // ACPI reclaimable and NVS memory
+    pub acpi: usize,
+    pub frames_allocated: usize,
+    pub page_table_frames: usize,
+    pub heap_used: usize,
+    pub heap_size: usize,
+    pub mmio_pages: usize,
+    pub mmio_window_used: usize,
+}
+
+// (total, usable, reserved, acpi) bytes of the (length, type) entries
+fn memory_map_totals<I>(entries: I) -> (usize, usize, usize, usize)
+    where I: IntoIterator<Item = (usize, u32)>
+{
+    entries.into_iter().fold((0, 0, 0, 0), |(total, usable, reserved, acpi), (length, typ)| {
+        match typ {
+            AVAILABLE => (total + length, usable + length, reserved, acpi),
+            ACPI_RECLAIMABLE | ACPI_NVS => (total + length, usable, reserved, acpi + length),
+            _ => (total + length, usable, reserved + length, acpi),
+        }
+    })
+}
+
+pub fn record_memory_map(memory_map_tag: &MemoryMapTag) {
+    let tag = memory_map_tag as *const _ as usize;
+    let (size, entry_size) = unsafe {
+        (*((tag + TAG_SIZE_OFFSET) as *const u32) as usize,
+         *((tag + TAG_ENTRY_SIZE_OFFSET) as *const u32) as usize)
+    };
+    let entry = |idx: usize| unsafe {
+        &*((tag + TAG_ENTRIES_OFFSET + idx * entry_size) as *const MemoryMapEntry)
+    };
+
+    let count = (size - TAG_ENTRIES_OFFSET) / entry_size;
+    let (total, usable, reserved, acpi) =
+        memory_map_totals((0..count).map(entry).map(|entry| (entry.length as usize, entry.typ)));
+    TOTAL_MEMORY.store(total, Ordering::SeqCst);
+    USABLE_MEMORY.store(usable, Ordering::SeqCst);
+    RESERVED_MEMORY.store(reserved, Ordering::SeqCst);
+    ACPI_MEMORY.store(acpi, Ordering::SeqCst);
+}
+
+pub fn info() -> MemoryInfo {
+    let (heap_used, heap_size) = slab::heap_usage();
+
+    MemoryInfo {
+        total: TOTAL_MEMORY.load(Ordering::SeqCst),
+        usable: USABLE_MEMORY.load(Ordering::SeqCst),
+        reserved: RESERVED_MEMORY.load(Ordering::SeqCst),
+        acpi: ACPI_MEMORY.load(Ordering::SeqCst),
+        frames_allocated: memory::allocated_frames(),
+        page_table_frames: paging::table_frames(),
+        heap_used: heap_used,
+        heap_size: heap_size,
+        mmio_pages: paging::mmio_mapped_pages(),
+        mmio_window_used: paging::mmio_window_used(),
+    }
+}
+
+pub fn print() {
+    let info = info();
+
+    println!("{}Physical:    {}{:8} KiB {}total, {}{:8} KiB {}usable, {}{:8} KiB {}reserved, {}{} KiB {}ACPI",
+        LIGHT_GRAY, CYAN, info.total / 1024, LIGHT_GRAY,
+        CYAN, info.usable / 1024, LIGHT_GRAY,
+        CYAN, info.reserved / 1024, LIGHT_GRAY,
+        CYAN, info.acpi / 1024, LIGHT_GRAY);
+    println!("{}Frames:      {}{:8}     {}allocated ({} KiB), {}{} {}for page tables",
+        LIGHT_GRAY, CYAN, info.frames_allocated, LIGHT_GRAY, info.frames_allocated * PAGE_SIZE / 1024,
+        CYAN, info.page_table_frames, LIGHT_GRAY);
+    println!("{}Heap:        {}{:8} KiB {}in use of {}{} KiB {}in slabs",
+        LIGHT_GRAY, CYAN, info.heap_used / 1024, LIGHT_GRAY,
+        CYAN, info.heap_size / 1024, LIGHT_GRAY);
+    println!("{}MMIO:        {}{:8}     {}pages mapped, {}{} KiB {}of the window used",
+        LIGHT_GRAY, CYAN, info.mmio_pages, LIGHT_GRAY,
+        CYAN, info.mmio_window_used / 1024, LIGHT_GRAY);
+}
+
+
+#[test]
+fn test_memory_map_totals() {
+    // As reported by QEMU with 128 MiB, plus ACPI and a bad RAM entry
+    let entries = [(0x9fc00, 1), (0x400, 2), (0x10000, 2), (0x7ee0000, 1), (0x10000, 3),
+                   (0x10000, 4), (0x40000, 2), (0x1000, 5)];
+    assert_eq!(memory_map_totals(entries.iter().cloned()),
+               (0x7ff1000, 0x7f7fc00, 0x51400, 0x20000));
+    assert_eq!(memory_map_totals(None), (0, 0, 0, 0));
+}
@@ -0,0 +1,156 @@
+// Memory mapped device registers. Every access is volatile, so the
+// compiler neither drops nor merges nor reorders them. Register blocks are
+// `#[repr(C)]` structs of these types, with reserved fields as padding,
+// placed over the registers returned by paging::map_mmio.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+
+pub trait RegisterValue: Copy {
+    fn to_u64(self) -> u64;
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! register_value {
+    ($($t:ty),*) => {
+        $(
+            impl RegisterValue for $t {
+                fn to_u64(self) -> u64 {
+                    self as u64
+                }
+
+                fn from_u64(value: u64) -> $t {
+                    value as $t
+                }
+            }
+        )*
+    }
+}
+
+register_value!(u8, u16, u32, u64);
+
+// `width` bits starting at bit `shift` of a register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub shift: u32,
+    pub width: u32,
+}
+
+impl Field {
+    pub const fn new(shift: u32, width: u32) -> Field {
+        Field { shift: shift, width: width }
+    }
+
+    pub const fn bit(shift: u32) -> Field {
+        Field { shift: shift, width: 1 }
+    }
+
+    fn mask(&self) -> u64 {
+        if self.width >= 64 { !0 } else { (1 << self.width) - 1 }
+    }
+
+    pub fn get<T: RegisterValue>(&self, value: T) -> u64 {
+        (value.to_u64() >> self.shift) & self.mask()
+    }
+
+    // `value` with the field replaced, excess bits of `field` are dropped
+    pub fn set<T: RegisterValue>(&self, value: T, field: u64) -> T {
+        let mask = self.mask() << self.shift;
+        T::from_u64(value.to_u64() & !mask | (field << self.shift) & mask)
+    }
+}
+
+#[repr(C)]
+pub struct ReadOnly<T: RegisterValue> {
+    value: UnsafeCell<T>,
+}
+
+#[repr(C)]
+pub struct WriteOnly<T: RegisterValue> {
+    value: UnsafeCell<T>,
+}
+
+#[repr(C)]
+pub struct ReadWrite<T: RegisterValue> {
+    value: UnsafeCell<T>,
+}
+
+// Registers are shared with the device anyway
+unsafe impl<T: RegisterValue> Sync for ReadOnly<T> {}
+unsafe impl<T: RegisterValue> Sync for WriteOnly<T> {}
+unsafe impl<T: RegisterValue> Sync for ReadWrite<T> {}
+
+impl<T: RegisterValue> ReadOnly<T> {
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.value.get()) }
+    }
+
+    pub fn get(&self, field: Field) -> u64 {
+        field.get(self.read())
+    }
+}
+
+impl<T: RegisterValue> WriteOnly<T> {
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_volatile(self.value.get(), value) }
+    }
+}
+
+impl<T: RegisterValue> ReadWrite<T> {
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.value.get()) }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_volatile(self.value.get(), value) }
+    }
+
+    pub fn modify<F>(&self, f: F)
+        where F: FnOnce(T) -> T
+    {
+        self.write(f(self.read()));
+    }
+
+    pub fn get(&self, field: Field) -> u64 {
+        field.get(self.read())
+    }
+
+    // Read-modify-write of a single field
+    pub fn set(&self, field: Field, value: u64) {
+        self.modify(|old| field.set(old, value));
+    }
+}
+
+
+#[cfg(test)]
+use core::mem;
+
+#[test]
+fn test_field() {
+    let vector = Field::new(0, 8);
+    let enabled = Field::bit(8);
+
+    assert_eq!(vector.get(0x1ffu32), 0xff);
+    assert_eq!(enabled.get(0x1ffu32), 1);
+    assert_eq!(enabled.set(0x0ffu32, 1), 0x1ff);
+    assert_eq!(vector.set(0x1ffu32, 0x20), 0x120);
+    // Values are truncated to the field
+    assert_eq!(vector.set(0u32, 0x1234), 0x34);
+    assert_eq!(Field::new(0, 64).set(0u64, !0), !0);
+}
+
+#[test]
+fn test_registers() {
+    let mut memory = [0u32; 4];
+    let block = unsafe { &*(memory.as_mut_ptr() as *const [ReadWrite<u32>; 4]) };
+
+    block[1].write(0x1234);
+    block[1].set(Field::new(4, 4), 0xf);
+    block[2].modify(|value| value | 1);
+    assert_eq!(block[1].read(), 0x12f4);
+    assert_eq!(block[1].get(Field::new(8, 8)), 0x12);
+    assert_eq!(memory, [0, 0x12f4, 1, 0]);
+
+    assert_eq!(mem::size_of::<ReadOnly<u16>>(), 2);
+    assert_eq!(mem::size_of::<WriteOnly<u64>>(), 8);
+}
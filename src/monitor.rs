@@ -0,0 +1,309 @@
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpuio::InterruptContext;
+use control_regs::rflags;
+use debug_regs::{self, Breakpoint, BreakCondition, BreakSize};
+use keyboard::{Key, MetaKey};
+use input;
+use memory::{self, paging};
+use fixup;
+use mtrr;
+use backtrace::{self, Symbolized};
+
+// Name of the debug register breakpoints installed from the monitor
+pub const BREAKPOINT_NAME: &'static str = "monitor";
+
+const LINE_LENGTH: usize = 76;
+const DEFAULT_DUMP_LENGTH: u64 = 64;
+const MAX_DUMP_LENGTH: u64 = 512;
+
+// Set while the monitor single steps, so the next #DB comes back here
+static STEPPING: AtomicBool = AtomicBool::new(false);
+
+const REGISTER_NAMES: [&'static str; 18] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15", "rip", "rflags",
+];
+
+pub fn is_stepping() -> bool {
+    STEPPING.load(Ordering::SeqCst)
+}
+
+// Runs the monitor until the user continues or single steps. Changes to the
+// context are applied when the exception handler returns.
+pub fn enter(context: &mut InterruptContext, reason: &str) {
+    STEPPING.store(false, Ordering::SeqCst);
+    context.stack_frame.flags &= !rflags::SINGLE_STEP.bits();
+
+    println!("{}\n{}: {}at {:#x} {}{}",
+        YELLOW, reason, WHITE, context.stack_frame.instruction_pointer,
+        LIGHT_GRAY, Symbolized(context.stack_frame.instruction_pointer));
+    print_registers(context);
+    println!("{}Type `help` for a list of commands", DARK_GRAY);
+
+    let mut buf = [0; LINE_LENGTH];
+    loop {
+        let line = read_line(&mut buf);
+        let mut args = line.split_whitespace();
+
+        match args.next() {
+            Some("help") | Some("h") => print_help(),
+            Some("regs") | Some("r") => print_registers(context),
+            Some("set") => {
+                match (args.next(), args.next().and_then(parse_number)) {
+                    (Some(name), Some(value)) => {
+                        if !set_register(context, name, value) {
+                            println!("{}Unknown register {}", RED, name);
+                        }
+                    }
+                    _ => println!("{}Usage: set <register> <value>", RED),
+                }
+            }
+            Some("x") => {
+                match args.next().and_then(parse_number) {
+                    Some(addr) => {
+                        let len = args.next().and_then(parse_number).unwrap_or(DEFAULT_DUMP_LENGTH);
+                        dump_memory(addr, if len > MAX_DUMP_LENGTH { MAX_DUMP_LENGTH } else { len });
+                    }
+                    None => println!("{}Usage: x <address> [length]", RED),
+                }
+            }
+            Some("pt") | Some("translate") => {
+                match args.next().and_then(parse_number) {
+                    Some(addr) => print_page_walk(addr),
+                    None => println!("{}Usage: translate <address>", RED),
+                }
+            }
+            Some("maps") => paging::print_mappings(),
+            Some("meminfo") => memory::stats::print(),
+            Some("bt") => {
+                backtrace::print_fault(context.stack_frame.instruction_pointer,
+                    context.registers.rbp);
+            }
+            Some("b") | Some("w") | Some("rw") => {
+                let condition = match line.split_whitespace().next() {
+                    Some("b") => BreakCondition::Execute,
+                    Some("w") => BreakCondition::Write,
+                    _ => BreakCondition::ReadWrite,
+                };
+                let addr = args.next().and_then(parse_number);
+                let len = args.next().and_then(parse_number).unwrap_or(1);
+                match addr {
+                    Some(addr) => set_breakpoint(addr, condition, len),
+                    None => println!("{}Usage: b <address> | w/rw <address> [length]", RED),
+                }
+            }
+            Some("bl") => list_breakpoints(),
+            Some("bd") => {
+                let slot = args.next().and_then(parse_number).map(|slot| slot as usize);
+                match slot.map(debug_regs::remove) {
+                    Some(Ok(bp)) => println!("{}Removed {}{:#x}", LIGHT_GRAY, CYAN, bp.address),
+                    Some(Err(err)) => println!("{}Failed: {:?}", RED, err),
+                    None => println!("{}Usage: bd <slot>", RED),
+                }
+            }
+            Some("c") => {
+                // Don't trigger an instruction breakpoint at rip again
+                context.stack_frame.flags |= rflags::INSTRUCTION_BREAKPOINTS_DISABLED.bits();
+                break;
+            }
+            Some("s") => {
+                context.stack_frame.flags |= rflags::SINGLE_STEP.bits() |
+                                             rflags::INSTRUCTION_BREAKPOINTS_DISABLED.bits();
+                STEPPING.store(true, Ordering::SeqCst);
+                break;
+            }
+            Some(command) => println!("{}Unknown command {}", RED, command),
+            None => {}
+        }
+    }
+}
+
+fn print_help() {
+    println!("{}r               {}show registers", WHITE, LIGHT_GRAY);
+    println!("{}set <reg> <val> {}modify a register", WHITE, LIGHT_GRAY);
+    println!("{}x <addr> [len]  {}dump memory", WHITE, LIGHT_GRAY);
+    println!("{}translate <addr>{}walk the page table (alias pt)", WHITE, LIGHT_GRAY);
+    println!("{}maps            {}list all mappings", WHITE, LIGHT_GRAY);
+    println!("{}meminfo         {}show memory usage", WHITE, LIGHT_GRAY);
+    println!("{}bt              {}show backtrace", WHITE, LIGHT_GRAY);
+    println!("{}b <addr>        {}break on execution", WHITE, LIGHT_GRAY);
+    println!("{}w/rw <addr> [n] {}break on write/access", WHITE, LIGHT_GRAY);
+    println!("{}bl, bd <slot>   {}list/delete breakpoints", WHITE, LIGHT_GRAY);
+    println!("{}c, s            {}continue, single step", WHITE, LIGHT_GRAY);
+}
+
+// Reads a line from the keyboard or serial console, echoing it
+fn read_line(buf: &mut [u8; LINE_LENGTH]) -> &str {
+    print!("{}mon> {}", CYAN, WHITE);
+
+    let mut len = 0;
+    loop {
+        match input::next_key() {
+            Key::Meta(MetaKey::Enter) => break,
+            Key::Meta(MetaKey::Backspace) => {
+                if len > 0 {
+                    len -= 1;
+                    print!("\x08 \x08");
+                }
+            }
+            Key::Char(c) if (c as u32) < 0x80 && len < LINE_LENGTH => {
+                buf[len] = c as u8;
+                len += 1;
+                print!("{}", c);
+            }
+            _ => {}
+        }
+    }
+    println!("");
+
+    str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+// Accepts hex with or without a 0x prefix
+fn parse_number(arg: &str) -> Option<u64> {
+    let digits = if arg.starts_with("0x") { &arg[2..] } else { arg };
+    u64::from_str_radix(digits, 16).ok()
+}
+
+
+
+fn print_registers(context: &InterruptContext) {
+    let r = &context.registers;
+    let frame = &context.stack_frame;
+
+    println!("{}rax {}{:016x} {}rbx {}{:016x} {}rcx {}{:016x}",
+        LIGHT_GRAY, WHITE, r.rax, LIGHT_GRAY, WHITE, r.rbx, LIGHT_GRAY, WHITE, r.rcx);
+    println!("{}rdx {}{:016x} {}rsi {}{:016x} {}rdi {}{:016x}",
+        LIGHT_GRAY, WHITE, r.rdx, LIGHT_GRAY, WHITE, r.rsi, LIGHT_GRAY, WHITE, r.rdi);
+    println!("{}rbp {}{:016x} {}rsp {}{:016x} {}r8  {}{:016x}",
+        LIGHT_GRAY, WHITE, r.rbp, LIGHT_GRAY, WHITE, frame.stack_pointer, LIGHT_GRAY, WHITE, r.r8);
+    println!("{}r9  {}{:016x} {}r10 {}{:016x} {}r11 {}{:016x}",
+        LIGHT_GRAY, WHITE, r.r9, LIGHT_GRAY, WHITE, r.r10, LIGHT_GRAY, WHITE, r.r11);
+    println!("{}r12 {}{:016x} {}r13 {}{:016x} {}r14 {}{:016x}",
+        LIGHT_GRAY, WHITE, r.r12, LIGHT_GRAY, WHITE, r.r13, LIGHT_GRAY, WHITE, r.r14);
+    println!("{}r15 {}{:016x} {}rip {}{:016x} {}rflags {}{:x}",
+        LIGHT_GRAY, WHITE, r.r15, LIGHT_GRAY, WHITE, frame.instruction_pointer,
+        LIGHT_GRAY, WHITE, frame.flags);
+    println!("{}cs {}{:#x} {}ss {}{:#x}",
+        LIGHT_GRAY, WHITE, frame.code_segment, LIGHT_GRAY, WHITE, frame.stack_segment);
+}
+
+fn set_register(context: &mut InterruptContext, name: &str, value: u64) -> bool {
+    let r = &mut context.registers;
+    let frame = &mut context.stack_frame;
+
+    let target = match REGISTER_NAMES.iter().position(|&reg| reg == name) {
+        Some(0) => &mut r.rax,
+        Some(1) => &mut r.rbx,
+        Some(2) => &mut r.rcx,
+        Some(3) => &mut r.rdx,
+        Some(4) => &mut r.rsi,
+        Some(5) => &mut r.rdi,
+        Some(6) => &mut r.rbp,
+        Some(7) => &mut frame.stack_pointer,
+        Some(8) => &mut r.r8,
+        Some(9) => &mut r.r9,
+        Some(10) => &mut r.r10,
+        Some(11) => &mut r.r11,
+        Some(12) => &mut r.r12,
+        Some(13) => &mut r.r13,
+        Some(14) => &mut r.r14,
+        Some(15) => &mut r.r15,
+        Some(16) => &mut frame.instruction_pointer,
+        Some(17) => &mut frame.flags,
+        _ => return false,
+    };
+
+    *target = value;
+    true
+}
+
+// Hex dump, 16 bytes per line. Bytes that can't be read are shown as `??`.
+fn dump_memory(addr: u64, len: u64) {
+    for line in 0..(len + 15) / 16 {
+        let line_addr = addr.wrapping_add(line * 16);
+        print!("{}{:016x}  ", CYAN, line_addr);
+
+        let mut ascii = [b' '; 16];
+        for i in 0..16 {
+            if line * 16 + i >= len {
+                print!("   ");
+                continue;
+            }
+
+            match fixup::try_read::<u8>(line_addr.wrapping_add(i)) {
+                Ok(byte) => {
+                    print!("{}{:02x} ", WHITE, byte);
+                    ascii[i as usize] = if byte >= 0x20 && byte < 0x7f && byte != b'\\' { byte } else { b'.' };
+                }
+                Err(_) => print!("{}?? ", DARK_GRAY),
+            }
+        }
+
+        println!("{}{}", LIGHT_GRAY, str::from_utf8(&ascii).unwrap_or(""));
+    }
+}
+
+fn print_page_walk(addr: u64) {
+    let canonical = addr < 0x0000_8000_0000_0000 || addr >= 0xffff_8000_0000_0000;
+    if !canonical {
+        println!("{}{:#x} is not canonical", RED, addr);
+        return;
+    }
+
+    let steps = paging::P4_TABLE_EXCL.lock().walk(addr as usize);
+    for step in steps.iter().filter_map(|step| *step) {
+        match step.target {
+            Some(target) => println!("{}P{}[{:3}] {}{:#x} {}{:?}",
+                LIGHT_GRAY, step.level, step.index, CYAN, target, DARK_GRAY, step.flags),
+            None => println!("{}P{}[{:3}] {}not present", LIGHT_GRAY, step.level, step.index, RED),
+        }
+    }
+
+    match paging::P4_TABLE_EXCL.lock().translate(addr as usize) {
+        Some(phys) => {
+            // Bit 7 of the last entry is the PAT bit only in a P1
+            let last = steps.iter().filter_map(|step| *step).last().unwrap();
+            let flags = if last.level == 1 { last.flags } else { last.flags - paging::HUGE_PAGE };
+            println!("{}{:#x} -> {}{:#x} {}{:?}",
+                LIGHT_GRAY, addr, CYAN, phys, DARK_GRAY, mtrr::effective_type(phys as u64, flags));
+        }
+        None => println!("{}{:#x} is not mapped", RED, addr),
+    }
+}
+
+fn set_breakpoint(addr: u64, condition: BreakCondition, len: u64) {
+    let size = match len {
+        1 => BreakSize::Byte,
+        2 => BreakSize::Word,
+        4 => BreakSize::DoubleWord,
+        8 => BreakSize::QuadWord,
+        _ => {
+            println!("{}Length must be 1, 2, 4 or 8", RED);
+            return;
+        }
+    };
+
+    let breakpoint = Breakpoint {
+        name: BREAKPOINT_NAME,
+        address: addr,
+        condition: condition,
+        size: if condition == BreakCondition::Execute { BreakSize::Byte } else { size },
+    };
+
+    match debug_regs::install(breakpoint) {
+        Ok(slot) => println!("{}Breakpoint #{} at {}{:#x}", LIGHT_GRAY, slot, CYAN, addr),
+        Err(err) => println!("{}Failed: {:?}", RED, err),
+    }
+}
+
+fn list_breakpoints() {
+    for slot in 0..debug_regs::SLOT_COUNT {
+        if let Some(bp) = debug_regs::get(slot) {
+            println!("{}#{} {}{:#x} {}{:?} {} bytes ({})",
+                LIGHT_GRAY, slot, CYAN, bp.address, LIGHT_GRAY,
+                bp.condition, bp.size.bytes(), bp.name);
+        }
+    }
+}
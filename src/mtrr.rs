@@ -0,0 +1,357 @@
+use x86::shared::msr::rdmsr;
+use cpuid;
+use memory::paging::{self, EntryFlags, MemoryType};
+
+const IA32_MTRRCAP: u32 = 0xfe;
+const IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+const IA32_MTRR_FIX16K_A0000: u32 = 0x259;
+const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+
+// Capability bits
+const FIXED_SUPPORTED: u64 = 1 << 8;
+const WRITE_COMBINING_SUPPORTED: u64 = 1 << 10;
+
+// Default type register bits
+const FIXED_ENABLED: u64 = 1 << 10;
+const MTRRS_ENABLED: u64 = 1 << 11;
+
+// Set in PHYSMASK if the variable range is in use
+const RANGE_VALID: u64 = 1 << 11;
+
+const MAX_VARIABLE_RANGES: usize = 16;
+
+// The fixed ranges cover the first MiB: 8 ranges of 64 KiB, 16 of 16 KiB
+// and 64 of 4 KiB
+const FIXED_RANGE_COUNT: usize = 88;
+const FIXED_RANGES_END: u64 = 0x10_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VariableRange {
+    pub base: u64,
+    pub mask: u64,
+    pub memory_type: MemoryType,
+}
+
+impl VariableRange {
+    fn contains(&self, addr: u64) -> bool {
+        addr & self.mask == self.base & self.mask
+    }
+
+    // Size of the range if the mask is contiguous, which it is in practice
+    pub fn size(&self, physical_mask: u64) -> u64 {
+        (!self.mask & physical_mask) + 1
+    }
+}
+
+// Snapshot of the MTRRs
+#[derive(Copy)]
+pub struct Mtrrs {
+    pub enabled: bool,
+    pub fixed_enabled: bool,
+    pub default_type: MemoryType,
+    pub fixed: [MemoryType; FIXED_RANGE_COUNT],
+    pub variable: [Option<VariableRange>; MAX_VARIABLE_RANGES],
+    // Address bits implemented by the CPU, page aligned
+    pub physical_mask: u64,
+}
+
+// Start and size of fixed range `idx`
+fn fixed_range(idx: usize) -> (u64, u64) {
+    match idx {
+        0...7 => (idx as u64 * 0x1_0000, 0x1_0000),
+        8...23 => (0x8_0000 + (idx - 8) as u64 * 0x4000, 0x4000),
+        _ => (0xc_0000 + (idx - 24) as u64 * 0x1000, 0x1000),
+    }
+}
+
+fn fixed_range_index(addr: u64) -> usize {
+    match addr {
+        0...0x7_ffff => (addr / 0x1_0000) as usize,
+        0x8_0000...0xb_ffff => 8 + ((addr - 0x8_0000) / 0x4000) as usize,
+        _ => 24 + ((addr - 0xc_0000) / 0x1000) as usize,
+    }
+}
+
+impl Clone for Mtrrs {
+    fn clone(&self) -> Mtrrs {
+        *self
+    }
+}
+
+impl Mtrrs {
+    pub fn read() -> Mtrrs {
+        let physical_bits = cpuid::get_address_widths().physical as u64;
+        let mut mtrrs = Mtrrs {
+            enabled: false,
+            fixed_enabled: false,
+            default_type: MemoryType::Uncacheable,
+            fixed: [MemoryType::Uncacheable; FIXED_RANGE_COUNT],
+            variable: [None; MAX_VARIABLE_RANGES],
+            physical_mask: ((1 << physical_bits) - 1) & !0xfff,
+        };
+
+        if !is_supported() {
+            return mtrrs;
+        }
+
+        unsafe {
+            let capabilities = rdmsr(IA32_MTRRCAP);
+            let default_type = rdmsr(IA32_MTRR_DEF_TYPE);
+            mtrrs.enabled = default_type & MTRRS_ENABLED != 0;
+            mtrrs.fixed_enabled = capabilities & FIXED_SUPPORTED != 0 &&
+                                  default_type & FIXED_ENABLED != 0;
+            mtrrs.default_type = MemoryType::from_bits(default_type as u8);
+
+            if capabilities & FIXED_SUPPORTED != 0 {
+                // Each register holds the types of 8 consecutive ranges
+                let mut registers = [0; FIXED_RANGE_COUNT / 8];
+                registers[0] = rdmsr(IA32_MTRR_FIX64K_00000);
+                registers[1] = rdmsr(IA32_MTRR_FIX16K_80000);
+                registers[2] = rdmsr(IA32_MTRR_FIX16K_A0000);
+                for i in 0..8 {
+                    registers[3 + i] = rdmsr(IA32_MTRR_FIX4K_C0000 + i as u32);
+                }
+
+                for (idx, memory_type) in mtrrs.fixed.iter_mut().enumerate() {
+                    *memory_type = MemoryType::from_bits((registers[idx / 8] >> (idx % 8 * 8)) as u8);
+                }
+            }
+
+            let count = (capabilities & 0xff) as usize;
+            for idx in 0..::core::cmp::min(count, MAX_VARIABLE_RANGES) {
+                let base = rdmsr(IA32_MTRR_PHYSBASE0 + 2 * idx as u32);
+                let mask = rdmsr(IA32_MTRR_PHYSBASE0 + 2 * idx as u32 + 1);
+                if mask & RANGE_VALID != 0 {
+                    mtrrs.variable[idx] = Some(VariableRange {
+                        base: base & mtrrs.physical_mask,
+                        mask: mask & mtrrs.physical_mask,
+                        memory_type: MemoryType::from_bits(base as u8),
+                    });
+                }
+            }
+        }
+
+        mtrrs
+    }
+
+    // Memory type the MTRRs assign to the physical address `addr`
+    pub fn memory_type(&self, addr: u64) -> MemoryType {
+        if !self.enabled {
+            return MemoryType::Uncacheable;
+        }
+
+        if self.fixed_enabled && addr < FIXED_RANGES_END {
+            return self.fixed[fixed_range_index(addr)];
+        }
+
+        let mut matched = None;
+        for range in self.variable.iter().filter_map(|r| *r).filter(|r| r.contains(addr)) {
+            matched = match (matched, range.memory_type) {
+                (None, memory_type) => Some(memory_type),
+                // Uncacheable wins over everything
+                (_, MemoryType::Uncacheable) |
+                (Some(MemoryType::Uncacheable), _) => Some(MemoryType::Uncacheable),
+                // Write-through wins over write-back
+                (Some(MemoryType::WriteThrough), MemoryType::WriteBack) |
+                (Some(MemoryType::WriteBack), MemoryType::WriteThrough) => {
+                    Some(MemoryType::WriteThrough)
+                }
+                (Some(a), b) if a == b => Some(a),
+                // Any other overlap is undefined, assume the worst
+                _ => Some(MemoryType::Uncacheable),
+            };
+        }
+
+        matched.unwrap_or(self.default_type)
+    }
+}
+
+pub fn is_supported() -> bool {
+    cpuid::get_features().contains(cpuid::MEMORY_TYPE_RANGE_REGS)
+}
+
+pub fn write_combining_supported() -> bool {
+    is_supported() && unsafe { rdmsr(IA32_MTRRCAP) } & WRITE_COMBINING_SUPPORTED != 0
+}
+
+// Memory type of an access, given the MTRR type of the physical address
+// and the PAT type of the mapping (SDM volume 3, table 11-7)
+pub fn combine(mtrr: MemoryType, pat: MemoryType) -> MemoryType {
+    use memory::paging::MemoryType::*;
+
+    match (pat, mtrr) {
+        (Reserved(_), _) | (_, Reserved(_)) => Uncacheable,
+        (Uncacheable, _) => Uncacheable,
+        (UncachedMinus, WriteCombining) => WriteCombining,
+        (UncachedMinus, _) => Uncacheable,
+        (WriteCombining, _) => WriteCombining,
+        (_, Uncacheable) => Uncacheable,
+        (WriteThrough, WriteCombining) | (WriteProtected, WriteCombining) => Uncacheable,
+        (WriteBack, mtrr) => mtrr,
+        (WriteThrough, _) => WriteThrough,
+        (WriteProtected, _) => WriteProtected,
+    }
+}
+
+// Memory type of accesses to the physical address `addr` through a 4 KiB
+// page mapped with `flags`
+pub fn effective_type(addr: u64, flags: EntryFlags) -> MemoryType {
+    let pat = paging::pat_layout()[paging::pat_index(flags)];
+    combine(Mtrrs::read().memory_type(addr), pat)
+}
+
+pub fn print() {
+    if !is_supported() {
+        println!("{}MTRRs not supported", RED);
+        return;
+    }
+
+    let mtrrs = Mtrrs::read();
+    println!("{}MTRRs {}{}{}, default type {}{:?}",
+        LIGHT_GRAY, CYAN, if mtrrs.enabled { "enabled" } else { "disabled" },
+        LIGHT_GRAY, CYAN, mtrrs.default_type);
+
+    if mtrrs.fixed_enabled {
+        // Merge neighbouring fixed ranges of the same type
+        let mut start = 0;
+        for idx in 1..FIXED_RANGE_COUNT + 1 {
+            if idx < FIXED_RANGE_COUNT && mtrrs.fixed[idx] == mtrrs.fixed[start] {
+                continue;
+            }
+
+            let (first, _) = fixed_range(start);
+            let (last, size) = fixed_range(idx - 1);
+            println!("{}  fixed    {}{:#012x}-{:#012x} {:?}",
+                LIGHT_GRAY, CYAN, first, last + size - 1, mtrrs.fixed[start]);
+            start = idx;
+        }
+    }
+
+    for (idx, range) in mtrrs.variable.iter().enumerate() {
+        if let Some(range) = *range {
+            let size = range.size(mtrrs.physical_mask);
+            println!("{}  var {:2}   {}{:#012x}-{:#012x} {:?}",
+                LIGHT_GRAY, idx, CYAN, range.base, range.base + size - 1, range.memory_type);
+        }
+    }
+}
+
+
+#[cfg(test)]
+fn test_mtrrs() -> Mtrrs {
+    let mut fixed = [MemoryType::WriteBack; FIXED_RANGE_COUNT];
+    // VGA memory at 0xa0000-0xbffff
+    for idx in 8 + 8..24 {
+        fixed[idx] = MemoryType::Uncacheable;
+    }
+
+    let mut variable = [None; MAX_VARIABLE_RANGES];
+    let physical_mask = 0xf_ffff_f000;
+    // 0-2 GiB write-back, with 1-1.25 GiB write-through on top
+    variable[0] = Some(VariableRange {
+        base: 0,
+        mask: !(0x8000_0000 - 1) & physical_mask,
+        memory_type: MemoryType::WriteBack,
+    });
+    variable[1] = Some(VariableRange {
+        base: 0x4000_0000,
+        mask: !(0x1000_0000 - 1) & physical_mask,
+        memory_type: MemoryType::WriteThrough,
+    });
+    // A framebuffer at 3 GiB
+    variable[2] = Some(VariableRange {
+        base: 0xc000_0000,
+        mask: !(0x100_0000 - 1) & physical_mask,
+        memory_type: MemoryType::WriteCombining,
+    });
+
+    Mtrrs {
+        enabled: true,
+        fixed_enabled: true,
+        default_type: MemoryType::Uncacheable,
+        fixed: fixed,
+        variable: variable,
+        physical_mask: physical_mask,
+    }
+}
+
+#[test]
+fn test_fixed_ranges() {
+    assert_eq!(fixed_range(0), (0, 0x1_0000));
+    assert_eq!(fixed_range(8), (0x8_0000, 0x4000));
+    assert_eq!(fixed_range(24), (0xc_0000, 0x1000));
+    assert_eq!(fixed_range(87), (0xf_f000, 0x1000));
+
+    for idx in 0..FIXED_RANGE_COUNT {
+        let (start, size) = fixed_range(idx);
+        assert_eq!(fixed_range_index(start), idx);
+        assert_eq!(fixed_range_index(start + size - 1), idx);
+    }
+}
+
+#[test]
+fn test_memory_type() {
+    let mtrrs = test_mtrrs();
+
+    assert_eq!(mtrrs.memory_type(0x1000), MemoryType::WriteBack);
+    assert_eq!(mtrrs.memory_type(0xb8000), MemoryType::Uncacheable);
+    assert_eq!(mtrrs.memory_type(0x10_0000), MemoryType::WriteBack);
+    assert_eq!(mtrrs.memory_type(0x4800_0000), MemoryType::WriteThrough);
+    assert_eq!(mtrrs.memory_type(0x7fff_ffff), MemoryType::WriteBack);
+    assert_eq!(mtrrs.memory_type(0x8000_0000), MemoryType::Uncacheable);
+    assert_eq!(mtrrs.memory_type(0xc012_3000), MemoryType::WriteCombining);
+    assert_eq!(mtrrs.memory_type(0xfee0_0000), MemoryType::Uncacheable);
+
+    let disabled = Mtrrs { enabled: false, ..mtrrs };
+    assert_eq!(disabled.memory_type(0x1000), MemoryType::Uncacheable);
+
+    // Without fixed ranges the variable ones apply below 1 MiB too
+    let variable_only = Mtrrs { fixed_enabled: false, ..mtrrs };
+    assert_eq!(variable_only.memory_type(0xb8000), MemoryType::WriteBack);
+}
+
+#[test]
+fn test_overlapping_ranges() {
+    let mut mtrrs = test_mtrrs();
+    mtrrs.variable[3] = Some(VariableRange {
+        base: 0x4000_0000,
+        mask: !(0x1000 - 1) & mtrrs.physical_mask,
+        memory_type: MemoryType::Uncacheable,
+    });
+    mtrrs.variable[4] = Some(VariableRange {
+        base: 0xc000_0000,
+        mask: !(0x1000 - 1) & mtrrs.physical_mask,
+        memory_type: MemoryType::WriteBack,
+    });
+
+    assert_eq!(mtrrs.memory_type(0x4000_0000), MemoryType::Uncacheable);
+    assert_eq!(mtrrs.memory_type(0xc000_0000), MemoryType::Uncacheable);
+}
+
+#[test]
+fn test_variable_range_size() {
+    let mtrrs = test_mtrrs();
+
+    assert_eq!(mtrrs.variable[0].unwrap().size(mtrrs.physical_mask), 0x8000_0000);
+    assert_eq!(mtrrs.variable[2].unwrap().size(mtrrs.physical_mask), 0x100_0000);
+}
+
+#[test]
+fn test_combine() {
+    use memory::paging::MemoryType::*;
+
+    assert_eq!(combine(WriteBack, WriteBack), WriteBack);
+    assert_eq!(combine(WriteBack, Uncacheable), Uncacheable);
+    assert_eq!(combine(WriteBack, UncachedMinus), Uncacheable);
+    assert_eq!(combine(WriteCombining, UncachedMinus), WriteCombining);
+    assert_eq!(combine(Uncacheable, WriteCombining), WriteCombining);
+    assert_eq!(combine(Uncacheable, WriteBack), Uncacheable);
+    assert_eq!(combine(WriteCombining, WriteBack), WriteCombining);
+    assert_eq!(combine(WriteCombining, WriteThrough), Uncacheable);
+    assert_eq!(combine(WriteThrough, WriteBack), WriteThrough);
+    assert_eq!(combine(WriteProtected, WriteBack), WriteProtected);
+    assert_eq!(combine(WriteThrough, WriteProtected), WriteProtected);
+}
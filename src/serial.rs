@@ -0,0 +1,649 @@
+use core::fmt;
+use spin::Mutex;
+use cpuio::{self, Port, PortBus, CpuBus, PortClaim, PortRange, ExceptionStackFrame};
+
+pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
+pub const COM3: u16 = 0x3e8;
+pub const COM4: u16 = 0x2e8;
+
+const PORTS: [u16; 4] = [COM1, COM2, COM3, COM4];
+const NAMES: [&'static str; 4] = ["COM1", "COM2", "COM3", "COM4"];
+// Registers of a 16550
+const PORT_COUNT: u16 = 8;
+// COM1 and COM3 share IRQ 4, COM2 and COM4 share IRQ 3
+const IRQS: [u8; 4] = [4, 3, 4, 3];
+
+// The divisor latch is clocked at 115200 Hz
+const BASE_BAUD: u32 = 115200;
+
+// Line status bits
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// Interrupt enable bits
+const RECEIVED_DATA_INTERRUPT: u8 = 1 << 0;
+const TRANSMIT_EMPTY_INTERRUPT: u8 = 1 << 1;
+
+// Set in the interrupt identification register if nothing is pending
+const NO_INTERRUPT_PENDING: u8 = 1 << 0;
+
+// Bytes that can be written at once after the transmitter ran empty
+const FIFO_SIZE: usize = 16;
+const RING_BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    // 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+pub const LINE_8N1: LineConfig = LineConfig {
+    data_bits: 8,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+};
+
+impl LineConfig {
+    // Value of the line control register
+    fn bits(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            5 => 0b00,
+            6 => 0b01,
+            7 => 0b10,
+            _ => 0b11,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+
+        data_bits | stop_bits | parity << 3
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    InvalidPort,
+    InvalidBaudRate,
+    NotPresent,
+    // The ports are claimed by the named driver
+    PortInUse(&'static str),
+}
+
+// 16550 UART registers, accessed by polling
+pub struct SerialPort<B: PortBus = CpuBus> {
+    data: Port<u8, B>,
+    interrupt_enable: Port<u8, B>,
+    // Interrupt identification when read, FIFO control when written
+    fifo_control: Port<u8, B>,
+    line_control: Port<u8, B>,
+    modem_control: Port<u8, B>,
+    line_status: Port<u8, B>,
+    scratch: Port<u8, B>,
+}
+
+impl SerialPort<CpuBus> {
+    pub const unsafe fn new(base: u16) -> SerialPort<CpuBus> {
+        SerialPort {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            fifo_control: Port::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: Port::new(base + 5),
+            scratch: Port::new(base + 7),
+        }
+    }
+}
+
+impl SerialPort<CpuBus> {
+    pub fn from_claim(claim: &PortClaim) -> SerialPort<CpuBus> {
+        SerialPort {
+            data: claim.port(0),
+            interrupt_enable: claim.port(1),
+            fifo_control: claim.port(2),
+            line_control: claim.port(3),
+            modem_control: claim.port(4),
+            line_status: claim.port(5),
+            scratch: claim.port(7),
+        }
+    }
+}
+
+// Claims the registers of the UART at `port_base` for `name`
+pub fn claim_ports(name: &'static str, port_base: u16) -> Result<PortClaim, SerialError> {
+    cpuio::claim_ports(name, PortRange::new(port_base, PORT_COUNT))
+        .map_err(|err| match err {
+            cpuio::ClaimError::Conflict(owner, _) => SerialError::PortInUse(owner),
+            _ => SerialError::InvalidPort,
+        })
+}
+
+impl<B: PortBus + Clone> SerialPort<B> {
+    pub unsafe fn on_bus(base: u16, bus: B) -> SerialPort<B> {
+        SerialPort {
+            data: Port::on_bus(base, bus.clone()),
+            interrupt_enable: Port::on_bus(base + 1, bus.clone()),
+            fifo_control: Port::on_bus(base + 2, bus.clone()),
+            line_control: Port::on_bus(base + 3, bus.clone()),
+            modem_control: Port::on_bus(base + 4, bus.clone()),
+            line_status: Port::on_bus(base + 5, bus.clone()),
+            scratch: Port::on_bus(base + 7, bus),
+        }
+    }
+}
+
+impl<B: PortBus> SerialPort<B> {
+
+    pub fn init(&self, baud: u32) {
+        self.configure(baud, LINE_8N1);
+    }
+
+    pub fn configure(&self, baud: u32, line: LineConfig) {
+        let divisor = (BASE_BAUD / baud) as u16;
+
+        self.interrupt_enable.write(0x00);
+        // Set the divisor latch access bit to program the baud rate
+        self.line_control.write(0x80);
+        self.data.write(divisor as u8);
+        self.interrupt_enable.write((divisor >> 8) as u8);
+        self.line_control.write(line.bits());
+        // Enable and clear FIFOs, 14 byte threshold
+        self.fifo_control.write(0xc7);
+        // DTR, RTS and OUT2, which gates the interrupt line
+        self.modem_control.write(0x0b);
+    }
+
+    // A missing UART reads back 0xff from every register
+    pub fn is_present(&self) -> bool {
+        self.scratch.write(0x5a);
+        if self.scratch.read() != 0x5a {
+            return false;
+        }
+        self.scratch.write(0xa5);
+        self.scratch.read() == 0xa5
+    }
+
+    fn set_interrupts(&self, mask: u8) {
+        self.interrupt_enable.write(mask);
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.fifo_control.read() & NO_INTERRUPT_PENDING == 0
+    }
+
+    fn can_write(&self) -> bool {
+        self.line_status.read() & TRANSMIT_EMPTY != 0
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        while !self.can_write() {}
+        self.data.write(byte);
+    }
+
+    pub fn try_read_byte(&self) -> Option<u8> {
+        if self.line_status.read() & DATA_READY != 0 {
+            Some(self.data.read())
+        } else {
+            None
+        }
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+        }
+    }
+}
+
+
+
+struct RingBuffer {
+    buf: [u8; RING_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> RingBuffer {
+        RingBuffer {
+            buf: [0; RING_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == RING_BUFFER_SIZE {
+            return false;
+        }
+
+        self.buf[(self.head + self.len) % RING_BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % RING_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+// An opened COM port. With interrupts enabled, output is queued and sent
+// from the IRQ handler and input is buffered until it is read.
+struct Uart {
+    port: SerialPort,
+    ports: PortClaim,
+    interrupt_driven: bool,
+    transmitting: bool,
+    tx: RingBuffer,
+    rx: RingBuffer,
+    // Received bytes dropped because `rx` was full
+    overruns: usize,
+}
+
+impl Uart {
+    // `can_queue` is false if interrupts were off when writing started
+    fn write_byte(&mut self, byte: u8, can_queue: bool) {
+        // With interrupts off (e.g. in exception handlers) nothing would
+        // drain the queue, send it right away instead, keeping the order
+        if !self.interrupt_driven || !can_queue {
+            self.flush();
+            self.port.write_byte(byte);
+            return;
+        }
+
+        if !self.tx.push(byte) {
+            self.flush();
+            self.tx.push(byte);
+        }
+
+        if !self.transmitting {
+            self.transmitting = true;
+            self.port.set_interrupts(RECEIVED_DATA_INTERRUPT | TRANSMIT_EMPTY_INTERRUPT);
+        }
+    }
+
+    // Sends all queued bytes by polling
+    fn flush(&mut self) {
+        while let Some(byte) = self.tx.pop() {
+            self.port.write_byte(byte);
+        }
+    }
+
+    // Falls back to polling, so input also works with interrupts off
+    fn read_byte(&mut self) -> Option<u8> {
+        self.rx.pop().or_else(|| self.port.try_read_byte())
+    }
+
+    fn handle_interrupt(&mut self) {
+        while self.port.interrupt_pending() {
+            while let Some(byte) = self.port.try_read_byte() {
+                if !self.rx.push(byte) {
+                    self.overruns += 1;
+                }
+            }
+
+            if self.port.can_write() {
+                for _ in 0..FIFO_SIZE {
+                    match self.tx.pop() {
+                        Some(byte) => self.port.data.write(byte),
+                        None => break,
+                    }
+                }
+
+                if self.tx.is_empty() {
+                    self.transmitting = false;
+                    self.port.set_interrupts(RECEIVED_DATA_INTERRUPT);
+                }
+            }
+        }
+    }
+}
+
+static UARTS: [Mutex<Option<Uart>>; 4] = [
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+    Mutex::new(None),
+];
+
+fn index(port_base: u16) -> Result<usize, SerialError> {
+    PORTS.iter().position(|&base| base == port_base).ok_or(SerialError::InvalidPort)
+}
+
+// Configures the UART at `port_base` (one of COM1..COM4)
+pub fn open(port_base: u16, baud: u32, line: LineConfig) -> Result<(), SerialError> {
+    let idx = index(port_base)?;
+    if baud == 0 || baud > BASE_BAUD || BASE_BAUD % baud != 0 {
+        return Err(SerialError::InvalidBaudRate);
+    }
+
+    let ports = claim_ports(NAMES[idx], port_base)?;
+    let port = SerialPort::from_claim(&ports);
+    if !port.is_present() {
+        cpuio::release_ports(ports);
+        return Err(SerialError::NotPresent);
+    }
+    port.configure(baud, line);
+
+    cpuio::without_interrupts(|| {
+        *UARTS[idx].lock() = Some(Uart {
+            port: port,
+            ports: ports,
+            interrupt_driven: false,
+            transmitting: false,
+            tx: RingBuffer::new(),
+            rx: RingBuffer::new(),
+            overruns: 0,
+        });
+    });
+
+    Ok(())
+}
+
+// Switches an opened UART to interrupt driven transmission and reception
+pub fn enable_interrupts(port_base: u16) -> Result<(), SerialError> {
+    let idx = index(port_base)?;
+
+    cpuio::without_interrupts(|| {
+        match *UARTS[idx].lock() {
+            Some(ref mut uart) => {
+                uart.interrupt_driven = true;
+                uart.port.set_interrupts(RECEIVED_DATA_INTERRUPT);
+                Ok(())
+            }
+            None => Err(SerialError::NotPresent),
+        }
+    })?;
+
+    cpuio::unmask_legacy_irq(IRQS[idx]);
+    Ok(())
+}
+
+// Closes the UART, e.g. before it is handed to the gdb stub
+pub fn close(port_base: u16) {
+    if let Ok(idx) = index(port_base) {
+        cpuio::without_interrupts(|| {
+            if let Some(mut uart) = UARTS[idx].lock().take() {
+                uart.flush();
+                uart.port.set_interrupts(0);
+                cpuio::release_ports(uart.ports);
+            }
+        });
+    }
+}
+
+// True if `port_base` was opened, which only succeeds if the UART is
+// present
+pub fn is_open(port_base: u16) -> bool {
+    index(port_base).ok().map_or(false, |idx| {
+        cpuio::without_interrupts(|| UARTS[idx].lock().is_some())
+    })
+}
+
+pub fn write(port_base: u16, bytes: &[u8]) {
+    if let Ok(idx) = index(port_base) {
+        let can_queue = cpuio::interrupts_enabled();
+        cpuio::without_interrupts(|| {
+            if let Some(ref mut uart) = *UARTS[idx].lock() {
+                for &byte in bytes {
+                    uart.write_byte(byte, can_queue);
+                }
+            }
+        });
+    }
+}
+
+pub fn read_byte(port_base: u16) -> Option<u8> {
+    index(port_base).ok().and_then(|idx| {
+        cpuio::without_interrupts(|| {
+            UARTS[idx].lock().as_mut().and_then(|uart| uart.read_byte())
+        })
+    })
+}
+
+fn handle_irq(irq: u8) {
+    for idx in 0..PORTS.len() {
+        if IRQS[idx] != irq {
+            continue;
+        }
+
+        if let Some(ref mut uart) = *UARTS[idx].lock() {
+            if uart.interrupt_driven {
+                uart.handle_interrupt();
+            }
+        }
+    }
+
+    cpuio::legacy_irq_done(irq);
+}
+
+pub extern "C" fn irq3_handler(_stack_frame: &ExceptionStackFrame) {
+    handle_irq(3);
+}
+
+pub extern "C" fn irq4_handler(_stack_frame: &ExceptionStackFrame) {
+    handle_irq(4);
+}
+
+
+
+// Mirrors the console output to a UART, translating the `\fg,bg;` colour
+// escapes understood by the VGA writer into ANSI sequences
+pub struct Console {
+    port: Option<u16>,
+    escape_step: u8,
+    foreground: u8,
+    background: u8,
+}
+
+pub static CONSOLE: Mutex<Console> = Mutex::new(Console {
+    port: None,
+    escape_step: 0,
+    foreground: 0,
+    background: 0,
+});
+
+// VGA colour numbers to ANSI colour numbers, the VGA intensity bit
+// selects the bright ANSI variants
+const ANSI_COLORS: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+impl Console {
+    pub fn attach(&mut self, port_base: u16) {
+        self.port = Some(port_base);
+    }
+
+    pub fn detach(&mut self) {
+        self.port = None;
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    pub fn new_line(&mut self) {
+        self.write_raw(b"\r\n");
+    }
+
+    pub fn set_color(&mut self, foreground: u8, background: u8) {
+        let fg = ANSI_COLORS[(foreground & 7) as usize] + if foreground & 8 != 0 { 90 } else { 30 };
+        let bg = ANSI_COLORS[(background & 7) as usize] + if background & 8 != 0 { 100 } else { 40 };
+
+        let mut sequence = *b"\x1b[000;000m";
+        sequence[2] = b'0' + fg / 100;
+        sequence[3] = b'0' + fg / 10 % 10;
+        sequence[4] = b'0' + fg % 10;
+        sequence[6] = b'0' + bg / 100;
+        sequence[7] = b'0' + bg / 10 % 10;
+        sequence[8] = b'0' + bg % 10;
+        self.write_raw(&sequence);
+    }
+
+    fn write_raw(&self, bytes: &[u8]) {
+        if let Some(port) = self.port {
+            write(port, bytes);
+        }
+    }
+
+    // Same escape grammar as vga_buffer::Writer::write_byte
+    fn write_byte(&mut self, byte: u8) {
+        match (self.escape_step, byte) {
+            (0, b'\\') => {
+                self.escape_step = 1;
+                self.foreground = 0;
+                self.background = 0;
+            }
+            (0, b'\n') => self.new_line(),
+            (0, byte) => self.write_raw(&[byte]),
+            (1, b',') => self.escape_step = 2,
+            (_, b';') => {
+                self.escape_step = 0;
+                if self.foreground < 16 && self.background < 16 {
+                    let (fg, bg) = (self.foreground, self.background);
+                    self.set_color(fg, bg);
+                }
+            }
+            (1, byte) if byte >= b'0' && byte <= b'9' && self.foreground < 26 => {
+                self.foreground = self.foreground * 10 + byte - b'0';
+            }
+            (2, byte) if byte >= b'0' && byte <= b'9' && self.background < 26 => {
+                self.background = self.background * 10 + byte - b'0';
+            }
+            (_, byte) => {
+                self.escape_step = 0;
+                self.write_byte(byte);
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.port.is_some() {
+            for byte in s.bytes() {
+                self.write_byte(byte);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Opens `port_base` and mirrors all console output to it. Output is
+// polled until enable_console_interrupts is called.
+pub fn init_console(port_base: u16) {
+    match open(port_base, BASE_BAUD, LINE_8N1) {
+        Ok(()) => CONSOLE.lock().attach(port_base),
+        Err(err) => println!("{}Serial console on {:#x} not available: {:?}", LIGHT_GRAY, port_base, err),
+    }
+}
+
+// Switches the console port to interrupt driven I/O, once the IDT is loaded
+pub fn enable_console_interrupts() {
+    let port = CONSOLE.lock().port();
+
+    if let Some(port) = port {
+        let log = log!("Enabling serial console interrupts");
+        match enable_interrupts(port) {
+            Ok(()) => log.ok(),
+            Err(_) => log.fail(),
+        }
+    }
+}
+
+#[test]
+fn test_line_config() {
+    assert_eq!(LINE_8N1.bits(), 0x03);
+
+    let line = LineConfig { data_bits: 7, parity: Parity::Even, stop_bits: StopBits::Two };
+    assert_eq!(line.bits(), 0b0001_1110);
+}
+
+#[cfg(test)]
+use cpuio::fake_bus::{Access, FakeBus};
+
+#[test]
+fn test_configure() {
+    let bus = FakeBus::new();
+    let port = unsafe { SerialPort::on_bus(COM2, &bus) };
+    port.configure(9600, LINE_8N1);
+
+    assert_eq!(bus.writes(), vec![(0x2f9, 0x00), (0x2fb, 0x80),
+                                  // Divisor 12
+                                  (0x2f8, 0x0c), (0x2f9, 0x00),
+                                  (0x2fb, 0x03), (0x2fa, 0xc7), (0x2fc, 0x0b)]);
+}
+
+#[test]
+fn test_is_present() {
+    let bus = FakeBus::new();
+    bus.script(COM1 + 7, &[0x5a, 0xa5]);
+    assert!(unsafe { SerialPort::on_bus(COM1, &bus) }.is_present());
+    assert_eq!(bus.accesses(), vec![Access::Write(0x3ff, 0x5a), Access::Read(0x3ff, 0x5a),
+                                    Access::Write(0x3ff, 0xa5), Access::Read(0x3ff, 0xa5)]);
+
+    // Nothing answers on an empty bus
+    let bus = FakeBus::new();
+    assert!(!unsafe { SerialPort::on_bus(COM1, &bus) }.is_present());
+}
+
+#[test]
+fn test_write_waits_for_transmitter() {
+    let bus = FakeBus::new();
+    bus.script(COM1 + 5, &[0x00, 0x00, TRANSMIT_EMPTY as u32]);
+    unsafe { SerialPort::on_bus(COM1, &bus) }.write_byte(b'x');
+
+    assert_eq!(bus.accesses(), vec![Access::Read(0x3fd, 0x00), Access::Read(0x3fd, 0x00),
+                                    Access::Read(0x3fd, 0x20), Access::Write(0x3f8, 0x78)]);
+}
+
+#[test]
+fn test_try_read_byte() {
+    let bus = FakeBus::new();
+    bus.script(COM1 + 5, &[0x00, DATA_READY as u32]);
+    bus.script(COM1, &[b'y' as u32]);
+    let port = unsafe { SerialPort::on_bus(COM1, &bus) };
+
+    assert_eq!(port.try_read_byte(), None);
+    assert_eq!(port.try_read_byte(), Some(b'y'));
+    assert_eq!(bus.accesses(), vec![Access::Read(0x3fd, 0x00),
+                                    Access::Read(0x3fd, 0x01), Access::Read(0x3f8, 0x79)]);
+}
@@ -0,0 +1,201 @@
+use super::TestCase;
+use cpuid;
+use cpuio::{self, ClaimError, PortRange};
+use debug_regs::{self, Breakpoint, BreakCondition, BreakSize};
+use fixup;
+use fpu::{self, FpuContext};
+use memory::{self, DmaBuffer, GlobalFrameAllocator, slab};
+use memory::paging;
+use serial;
+use trace;
+
+pub static TESTS: [TestCase; 16] = [
+    TestCase { name: "cpuid::vendor", run: cpuid_vendor, should_panic: false },
+    TestCase { name: "paging::translate_kernel", run: translate_kernel, should_panic: false },
+    TestCase { name: "paging::translate_unmapped", run: translate_unmapped, should_panic: false },
+    TestCase { name: "paging::non_canonical", run: translate_non_canonical, should_panic: true },
+    TestCase { name: "fixup::try_read_mapped", run: try_read_mapped, should_panic: false },
+    TestCase { name: "fixup::try_read_unmapped", run: try_read_unmapped, should_panic: false },
+    TestCase { name: "fixup::try_read_non_canonical", run: try_read_non_canonical, should_panic: false },
+    TestCase { name: "fixup::copy_from_kernel", run: copy_from_kernel, should_panic: false },
+    TestCase { name: "debug_regs::install_remove", run: install_remove_breakpoint, should_panic: false },
+    TestCase { name: "trace::single_step", run: single_step, should_panic: false },
+    TestCase { name: "serial::com1_present", run: com1_present, should_panic: false },
+    TestCase { name: "cpuio::claim_conflict", run: claim_conflict, should_panic: false },
+    TestCase { name: "memory::dma_buffer", run: dma_buffer, should_panic: false },
+    TestCase { name: "memory::slab_alloc", run: slab_alloc, should_panic: false },
+    TestCase { name: "fpu::context_switch", run: fpu_context_switch, should_panic: false },
+    TestCase { name: "panic", run: explicit_panic, should_panic: true },
+];
+
+// Far away from anything the kernel maps
+const UNMAPPED: u64 = 0x0000_4000_0000_0000;
+
+fn cpuid_vendor() {
+    match cpuid::get_vendor() {
+        cpuid::Vendor::Unknown(name) => panic!("unknown vendor {:?}", name),
+        _ => {}
+    }
+    assert!(cpuid::get_address_widths().physical >= 32);
+}
+
+fn translate_kernel() {
+    let addr = translate_kernel as usize;
+    assert!(addr >= memory::KERNEL_OFFSET);
+    assert_eq!(paging::P4_TABLE_EXCL.lock().translate(addr), Some(addr - memory::KERNEL_OFFSET));
+}
+
+fn translate_unmapped() {
+    assert_eq!(paging::P4_TABLE_EXCL.lock().translate(UNMAPPED as usize), None);
+    assert!(!paging::is_mapped(UNMAPPED as usize));
+}
+
+fn translate_non_canonical() {
+    paging::P4_TABLE_EXCL.lock().translate(0x0000_8000_0000_0000);
+}
+
+fn try_read_mapped() {
+    let value: u64 = 0x1234_5678_9abc_def0;
+    assert_eq!(fixup::try_read::<u64>(&value as *const u64 as u64), Ok(value));
+}
+
+fn try_read_unmapped() {
+    assert_eq!(fixup::try_read::<u32>(UNMAPPED), Err(fixup::Fault { copied: 0 }));
+}
+
+fn try_read_non_canonical() {
+    assert!(fixup::try_read::<u8>(0x0000_8000_0000_0000).is_err());
+}
+
+fn copy_from_kernel() {
+    let value: u64 = 0x1234;
+    let mut buffer = [0u8; 8];
+    assert_eq!(fixup::copy_from_user(&mut buffer, &value as *const u64 as u64),
+               Err(fixup::Fault { copied: 0 }));
+    assert_eq!(buffer, [0; 8]);
+}
+
+fn install_remove_breakpoint() {
+    let value: u64 = 0;
+    let breakpoint = Breakpoint {
+        name: "test",
+        address: &value as *const u64 as u64,
+        condition: BreakCondition::ReadWrite,
+        size: BreakSize::QuadWord,
+    };
+
+    let slot = debug_regs::install(breakpoint).unwrap();
+    assert_eq!(debug_regs::find(breakpoint.address, BreakCondition::ReadWrite), Some(slot));
+    assert_eq!(debug_regs::remove(slot).unwrap().address, breakpoint.address);
+    assert_eq!(debug_regs::find(breakpoint.address, BreakCondition::ReadWrite), None);
+}
+
+fn single_step() {
+    let sum = trace::single_step(false, || (0..10u64).fold(0, |a, b| a + b));
+    assert_eq!(sum, 45);
+    assert!(trace::recorded() > 10);
+}
+
+// Opened for the console at boot, its registers belong to the driver
+fn com1_present() {
+    assert!(serial::is_open(serial::COM1));
+}
+
+fn claim_conflict() {
+    // Owned by the serial console
+    let range = PortRange::new(serial::COM1 + 4, 1);
+    assert_eq!(cpuio::claim_ports("test", range).unwrap_err(),
+               ClaimError::Conflict("COM1", PortRange::new(serial::COM1, 8)));
+
+    let claim = cpuio::claim_ports("test", PortRange::new(0x3b0, 4)).unwrap();
+    cpuio::release_ports(claim);
+}
+
+fn dma_buffer() {
+    let mut buffer = DmaBuffer::new(3 * 4096, 0x10000, memory::ISA_DMA_LIMIT).unwrap();
+    let phys = buffer.phys_addr();
+    assert_eq!(phys % 0x10000, 0);
+    assert!(phys + buffer.len() <= memory::ISA_DMA_LIMIT);
+    assert_eq!(paging::P4_TABLE_EXCL.lock().translate(buffer.virt_addr() + 4096), Some(phys + 4096));
+
+    // Also uncached in the kernel's mapping of low memory
+    let alias = paging::P4_TABLE_EXCL.lock().walk(memory::phys_to_virt(phys))[3].unwrap();
+    assert_eq!(paging::pat_index(alias.flags), paging::pat_index(paging::CacheMode::Uncached.flags()));
+
+    buffer.as_mut_slice()[0x2fff] = 0x5a;
+    assert_eq!(buffer.as_slice()[0x2fff], 0x5a);
+
+    // The frames are reused once freed
+    let virt = buffer.virt_addr();
+    drop(buffer);
+    assert!(!paging::is_mapped(virt));
+    assert_eq!(DmaBuffer::new(4096, 0x10000, memory::ISA_DMA_LIMIT).unwrap().phys_addr(), phys);
+}
+
+fn slab_alloc() {
+    let a = slab::alloc(48).unwrap();
+    let b = slab::alloc(64).unwrap();
+    assert_eq!(a as usize % 64, 0);
+    assert!(a != b);
+    assert!(a as usize >= memory::KERNEL_OFFSET);
+
+    unsafe {
+        *(a as *mut u64) = 0x1234;
+        assert_eq!(*(a as *const u64), 0x1234);
+        slab::free(a, 48);
+        slab::free(b, 64);
+    }
+}
+
+// Both raise #NM while the context isn't loaded into the FPU yet
+fn set_fpu_controls(control_word: u16, mxcsr: u32) {
+    unsafe {
+        asm!("fldcw [$0]
+              ldmxcsr [$1]"
+            :: "r"(&control_word), "r"(&mxcsr) : "memory" : "intel", "volatile");
+    }
+}
+
+fn fpu_controls() -> (u16, u32) {
+    let mut control_word = 0u16;
+    let mut mxcsr = 0u32;
+    unsafe {
+        asm!("fnstcw [$0]
+              stmxcsr [$1]"
+            :: "r"(&mut control_word), "r"(&mut mxcsr) : "memory" : "intel", "volatile");
+    }
+    (control_word, mxcsr)
+}
+
+fn fpu_context_switch() {
+    let a = FpuContext::new(&mut GlobalFrameAllocator);
+    let b = FpuContext::new(&mut GlobalFrameAllocator);
+
+    // Different rounding modes in each context
+    a.activate();
+    assert_eq!(fpu_controls(), (0x037f, 0x1f80));
+    set_fpu_controls(0x077f, 0x3f80);
+    b.activate();
+    assert_eq!(fpu_controls(), (0x037f, 0x1f80));
+    set_fpu_controls(0x0b7f, 0x5f80);
+
+    a.activate();
+    assert_eq!(fpu_controls(), (0x077f, 0x3f80));
+    b.activate();
+    assert_eq!(fpu_controls(), (0x0b7f, 0x5f80));
+    a.activate();
+    assert_eq!(fpu_controls(), (0x077f, 0x3f80));
+    fpu::deactivate();
+
+    drop(a);
+    drop(b);
+
+    // The save area's page is freed again
+    let frames = memory::allocated_frames();
+    drop(FpuContext::new(&mut GlobalFrameAllocator));
+    assert_eq!(memory::allocated_frames(), frames);
+}
+
+fn explicit_panic() {
+    panic!("expected");
+}
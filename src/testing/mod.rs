@@ -0,0 +1,111 @@
+// Runs the kernel test cases inside QEMU. Built into the kernel with the
+// `qemu-test` feature (`make test`, or `cargo test` through tests/qemu.rs),
+// results are printed to the serial console and reported to the host by
+// exiting QEMU through the isa-debug-exit device.
+
+mod cases;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use cpuio::{self, PortClaim, PortRange};
+
+// Port of `-device isa-debug-exit,iobase=0xf4,iosize=0x04`. QEMU exits
+// with status (value << 1) | 1, i.e. 33 for success and 35 for failure.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+const EXIT_SUCCESS: u32 = 0x10;
+const EXIT_FAILURE: u32 = 0x11;
+
+const NOT_RUNNING: usize = !0;
+
+pub struct TestCase {
+    pub name: &'static str,
+    pub run: fn(),
+    // The test passes only if it panics
+    pub should_panic: bool,
+}
+
+// Index of the running test into cases::TESTS
+static CURRENT: AtomicUsize = AtomicUsize::new(NOT_RUNNING);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static DEBUG_EXIT: Mutex<Option<PortClaim>> = Mutex::new(None);
+
+pub fn run_all() -> ! {
+    match cpuio::claim_ports("isa-debug-exit", PortRange::new(DEBUG_EXIT_PORT, 4)) {
+        Ok(claim) => *DEBUG_EXIT.lock() = Some(claim),
+        Err(err) => println!("{}Can't claim the QEMU exit port: {:?}", RED, err),
+    }
+    println!("{}running {} kernel tests", WHITE, cases::TESTS.len());
+    run_from(0)
+}
+
+// Panics can't unwind, so the remaining tests are continued from the
+// panic handler on top of the abandoned stack frames
+fn run_from(start: usize) -> ! {
+    for (idx, test) in cases::TESTS.iter().enumerate().skip(start) {
+        print!("{}test {} ... ", LIGHT_GRAY, test.name);
+        CURRENT.store(idx, Ordering::SeqCst);
+
+        (test.run)();
+
+        if test.should_panic {
+            fail("did not panic");
+        } else {
+            println!("{}ok", GREEN);
+        }
+    }
+
+    CURRENT.store(NOT_RUNNING, Ordering::SeqCst);
+
+    let failed = FAILED.load(Ordering::SeqCst);
+    if failed == 0 {
+        println!("{}test result: ok. {} passed", GREEN, cases::TESTS.len());
+        exit_qemu(EXIT_SUCCESS);
+    } else {
+        println!("{}test result: FAILED. {} passed, {} failed",
+            RED, cases::TESTS.len() - failed, failed);
+        exit_qemu(EXIT_FAILURE);
+    }
+}
+
+fn fail(reason: &str) {
+    FAILED.fetch_add(1, Ordering::SeqCst);
+    println!("{}FAILED {}({})", RED, LIGHT_GRAY, reason);
+}
+
+// Called by the panic handler, returns if no test is running
+pub fn handle_panic() {
+    let current = CURRENT.load(Ordering::SeqCst);
+    if current == NOT_RUNNING {
+        return;
+    }
+
+    if cases::TESTS[current].should_panic {
+        println!("{}ok", GREEN);
+    } else {
+        fail("panicked");
+    }
+
+    run_from(current + 1);
+}
+
+// Called for exceptions the kernel can't recover from. Returns if no test
+// is running, otherwise the remaining tests are skipped.
+pub fn handle_fault() {
+    if CURRENT.load(Ordering::SeqCst) == NOT_RUNNING {
+        return;
+    }
+
+    fail("unhandled exception");
+    exit_qemu(EXIT_FAILURE);
+}
+
+pub fn exit_qemu(code: u32) -> ! {
+    if let Some(ref claim) = *DEBUG_EXIT.lock() {
+        claim.port::<u32>(0).write(code);
+    }
+
+    // Not running under QEMU with the exit device, or its port wasn't claimed
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
@@ -0,0 +1,127 @@
+use spin::Mutex;
+use control_regs::rflags::{self, RFlags};
+use cpuio::{InterruptContext, Registers};
+use backtrace::Symbolized;
+
+const TRACE_CAPACITY: usize = 512;
+
+const NO_REGISTERS: Registers = Registers {
+    r15: 0, r14: 0, r13: 0, r12: 0, r11: 0, r10: 0, r9: 0, r8: 0,
+    rbp: 0, rdi: 0, rsi: 0, rdx: 0, rcx: 0, rbx: 0, rax: 0,
+};
+
+#[derive(Clone, Copy)]
+struct TraceEntry {
+    instruction_pointer: u64,
+    stack_pointer: u64,
+    registers: Registers,
+}
+
+struct TraceBuffer {
+    active: bool,
+    record_registers: bool,
+    len: usize,
+    // Steps that didn't fit into the buffer
+    dropped: usize,
+    entries: [TraceEntry; TRACE_CAPACITY],
+}
+
+static TRACE: Mutex<TraceBuffer> = Mutex::new(TraceBuffer {
+    active: false,
+    record_registers: false,
+    len: 0,
+    dropped: 0,
+    entries: [TraceEntry {
+        instruction_pointer: 0,
+        stack_pointer: 0,
+        registers: NO_REGISTERS,
+    }; TRACE_CAPACITY],
+});
+
+// Runs `f` with RFlags::SINGLE_STEP set, recording every executed
+// instruction into the trace buffer. The previous trace is discarded.
+//
+// `f` must not print or otherwise touch the trace buffer, and the trace
+// also contains the few instructions needed to toggle the flag.
+pub fn single_step<F, R>(record_registers: bool, f: F) -> R
+    where F: FnOnce() -> R
+{
+    {
+        let mut trace = TRACE.lock();
+        trace.active = true;
+        trace.record_registers = record_registers;
+        trace.len = 0;
+        trace.dropped = 0;
+    }
+
+    (RFlags::load() | rflags::SINGLE_STEP).store();
+    let result = f();
+    (RFlags::load() - rflags::SINGLE_STEP).store();
+
+    TRACE.lock().active = false;
+    result
+}
+
+pub fn is_active() -> bool {
+    TRACE.lock().active
+}
+
+// Number of instructions stepped by the last trace
+pub fn recorded() -> usize {
+    let trace = TRACE.lock();
+    trace.len + trace.dropped
+}
+
+// Called from the #DB handler after each single-stepped instruction
+pub fn record(context: &InterruptContext) {
+    let mut trace = TRACE.lock();
+    if !trace.active {
+        return;
+    }
+
+    if trace.len >= TRACE_CAPACITY {
+        trace.dropped += 1;
+        return;
+    }
+
+    let registers = if trace.record_registers {
+        context.registers
+    } else {
+        NO_REGISTERS
+    };
+
+    let idx = trace.len;
+    trace.entries[idx] = TraceEntry {
+        instruction_pointer: context.stack_frame.instruction_pointer,
+        stack_pointer: context.stack_frame.stack_pointer,
+        registers: registers,
+    };
+    trace.len += 1;
+}
+
+pub fn dump() {
+    let trace = TRACE.lock();
+
+    println!("{}Trace of {} instructions{}",
+        WHITE, trace.len + trace.dropped,
+        if trace.dropped > 0 { " (oldest shown)" } else { "" });
+
+    for (i, entry) in trace.entries[..trace.len].iter().enumerate() {
+        println!("{}#{:<4} {}{:#018x} {}{} {}rsp {:#x}",
+            LIGHT_GRAY, i, CYAN, entry.instruction_pointer,
+            LIGHT_GRAY, Symbolized(entry.instruction_pointer),
+            DARK_GRAY, entry.stack_pointer);
+
+        if trace.record_registers {
+            let r = &entry.registers;
+            println!("{}      rax {:#x} rbx {:#x} rcx {:#x} rdx {:#x}",
+                DARK_GRAY, r.rax, r.rbx, r.rcx, r.rdx);
+            println!("{}      rsi {:#x} rdi {:#x} rbp {:#x}",
+                DARK_GRAY, r.rsi, r.rdi, r.rbp);
+        }
+    }
+
+    if trace.dropped > 0 {
+        println!("{}{} further instructions not recorded", LIGHT_GRAY, trace.dropped);
+    }
+}
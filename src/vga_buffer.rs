@@ -4,9 +4,12 @@ use core::cell::Cell;
 use core::ptr::Unique;
 use core::fmt::{self, Write, Result};
 use core::mem;
+use core::str;
 use spin::Mutex;
+use memory::KERNEL_OFFSET;
 
-
+// The text buffer at 0xb8000, through the kernel's mapping of low memory
+const BUFFER_ADDRESS: usize = KERNEL_OFFSET + 0xb8000;
 
 const SCREEN_WIDTH: usize = 80;
 const SCREEN_HEIGHT: usize = 25;
@@ -16,7 +19,7 @@ pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     column_position: 0,
     scroll_count: 0,
     color_code: Cell::new(ColorCode::new(Color::WHITE, Color::BLACK)),
-    buffer: unsafe { Unique::new(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new(BUFFER_ADDRESS as *mut _) },
     escape_sequence_step: 0,
     escape_accumulator_1: 0,
     escape_accumulator_2: 0,
@@ -26,11 +29,11 @@ pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
 macro_rules! println {
     ($fmt:expr) => ({
         print!($fmt);
-        $crate::vga_buffer::WRITER.lock().new_line();
+        $crate::vga_buffer::new_line();
     });
     ($fmt:expr, $($arg:tt)*) => ({
         print!($fmt, $($arg)*);
-        $crate::vga_buffer::WRITER.lock().new_line();
+        $crate::vga_buffer::new_line();
     });
 }
 
@@ -42,7 +45,7 @@ macro_rules! print {
         use $crate::vga_buffer::Color::*;
 
         match format_args!($($arg)*) {
-            fmt => $crate::vga_buffer::WRITER.lock().write_fmt(fmt).unwrap()
+            fmt => $crate::vga_buffer::print(fmt)
         }
     });
 }
@@ -135,16 +138,26 @@ impl fmt::Display for ColorCode {
     }
 }
 
+// Length of the text kept for mirroring a finished line to serial
+const LINE_TEXT_LENGTH: usize = 64;
+
 pub struct Line {
     row: usize,
     col: usize,
     creation_point: u32,
+    text: [u8; LINE_TEXT_LENGTH],
 }
 
 impl Line {
     pub fn write(&mut self, msg: &str) {
         let col = self.col;
         self.write_at(msg, col);
+
+        for (i, byte) in msg.bytes().enumerate() {
+            if col + i < LINE_TEXT_LENGTH {
+                self.text[col + i] = byte;
+            }
+        }
         self.col += msg.len();
     }
 
@@ -170,7 +183,20 @@ impl Line {
         }
     }
 
+    // The serial console can't go back to a line, so it gets the whole
+    // line once the result is known
+    fn mirror_result(&self, result: &str) {
+        let len = if self.col < LINE_TEXT_LENGTH { self.col } else { LINE_TEXT_LENGTH };
+        let text = str::from_utf8(&self.text[..len]).unwrap_or("");
+
+        let mut console = ::serial::CONSOLE.lock();
+        let _ = write!(console, "{}{:<72}[{}{}{}]",
+            Color::LIGHT_GRAY, text, Color::GREEN, result, Color::WHITE);
+        console.new_line();
+    }
+
     pub fn ok(&self) {
+        self.mirror_result(" OK ");
         set_color!(WHITE);
         self.write_at("[", SCREEN_WIDTH - 7);
         set_color!(GREEN);
@@ -180,6 +206,7 @@ impl Line {
     }
 
     pub fn fail(&self) {
+        self.mirror_result("FAIL");
         set_color!(WHITE);
         self.write_at("[", SCREEN_WIDTH - 7);
         set_color!(GREEN);
@@ -219,7 +246,7 @@ impl Writer {
             column_position: 0,
             scroll_count: 0,
             color_code: Cell::new(ColorCode::new(Color::LIGHT_GREEN, Color::BLACK)),
-            buffer: unsafe { Unique::new(0xb8000 as *mut _) },
+            buffer: unsafe { Unique::new(BUFFER_ADDRESS as *mut _) },
             escape_sequence_step: 0,
             escape_accumulator_1: 0,
             escape_accumulator_2: 0,
@@ -229,6 +256,16 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match (self.escape_sequence_step, byte) {
             (0, b'\n') => self.new_line(),
+            // Backspace, used for line editing
+            (0, 0x08) => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+
+                    let row = self.row_position;
+                    let col = self.column_position;
+                    self.write_byte_at(b' ', row, col);
+                }
+            },
             (0, b'\\') => {
                 self.escape_sequence_step = 1;
             },
@@ -300,6 +337,7 @@ impl Writer {
             row: self.row_position - 1,
             col: 0,
             creation_point: self.scroll_count,
+            text: [b' '; LINE_TEXT_LENGTH],
         }
     }
 
@@ -349,11 +387,23 @@ impl Write for Writer {
     }
 }
 
+// Prints to the screen and the serial console
+pub fn print(args: fmt::Arguments) {
+    WRITER.lock().write_fmt(args).unwrap();
+    ::serial::CONSOLE.lock().write_fmt(args).unwrap();
+}
+
+pub fn new_line() {
+    WRITER.lock().new_line();
+    ::serial::CONSOLE.lock().new_line();
+}
+
 pub fn clear_screen() {
     WRITER.lock().clear_screen()
 }
 
 
 pub fn switch_color(front: Color, back: Color) {
-    WRITER.lock().color_code.set(ColorCode::new(front, back))
+    WRITER.lock().color_code.set(ColorCode::new(front, back));
+    ::serial::CONSOLE.lock().set_color(front as u8, back as u8);
 } 
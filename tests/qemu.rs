@@ -0,0 +1,18 @@
+// Runs the kernel test cases in QEMU through `make test`, so they are part
+// of `cargo test`. The kernel prints the result of each test to the serial
+// console, which QEMU connects to our stdout.
+
+use std::process::{self, Command};
+
+fn main() {
+    let status = Command::new("make")
+        .arg("test")
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("failed to run make");
+
+    if !status.success() {
+        println!("kernel tests failed");
+        process::exit(1);
+    }
+}